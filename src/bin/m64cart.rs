@@ -0,0 +1,165 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use monarch_64_emulator::peripherals::storage::{
+    cartridge_builder::{self, CartridgeManifest},
+    monad_boot_cartridge::MonadBootCartridge,
+    verification::{self, VerificationPolicy},
+};
+
+const USAGE: &str = "usage:
+    m64cart build <manifest.toml> [-o <cartridge.bin>] [--sign <key>] [--symbols <file.tags>]
+    m64cart inspect <cartridge.bin>
+    m64cart validate <cartridge.bin> [--trust <key.pub>]...
+    m64cart patch <cartridge.bin> <patch.ips|patch.bps> [-o <patched.bin>] [--patch-checksum <crc>]
+    m64cart keygen <key>";
+
+#[derive(Default)]
+struct Options {
+    positional: Vec<String>,
+    output: Option<String>,
+    signing_key: Option<String>,
+    symbols_file: Option<String>,
+    trusted_keys: Vec<String>,
+    patch_checksum: Option<u32>,
+}
+
+fn parse_options(mut arguments: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(argument) = arguments.next() {
+        let mut value = || {
+            arguments
+                .next()
+                .ok_or(format!("{} needs a value", argument))
+        };
+        match argument.as_str() {
+            "-o" => options.output = Some(value()?),
+            "--sign" => options.signing_key = Some(value()?),
+            "--symbols" => options.symbols_file = Some(value()?),
+            "--trust" => options.trusted_keys.push(value()?),
+            "--patch-checksum" => {
+                let crc = value()?;
+                let digits = crc.strip_prefix("0x").unwrap_or(&crc);
+                options.patch_checksum = Some(
+                    u32::from_str_radix(digits, 16)
+                        .map_err(|_| format!("{} is not a hex CRC-32", crc))?,
+                )
+            }
+            _ => options.positional.push(argument),
+        }
+    }
+    Ok(options)
+}
+
+/// Builds Monad boot cartridges from a manifest, inspects, validates or patches existing ones, and makes signing keys.
+fn main() -> ExitCode {
+    let result = parse_options(std::env::args().skip(1)).and_then(|options| {
+        match options
+            .positional
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()[..]
+        {
+            ["build", manifest] => build(manifest, &options),
+            ["inspect", cartridge] => inspect(cartridge),
+            ["validate", cartridge] => validate(cartridge, &options),
+            ["patch", cartridge, patch_file] => patch(cartridge, patch_file, &options),
+            ["keygen", key] => keygen(key),
+            _ => Err(USAGE.to_string()),
+        }
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn build(manifest_path: &str, options: &Options) -> Result<(), String> {
+    let mut manifest = CartridgeManifest::load(manifest_path).map_err(|error| error.to_string())?;
+    if let Some(symbols_file) = &options.symbols_file {
+        manifest.symbols_file = Some(PathBuf::from(symbols_file));
+    }
+    let cartridge = manifest.build().map_err(|error| error.to_string())?;
+    let output = options.output.as_ref().map_or_else(
+        || PathBuf::from(manifest_path).with_extension("bin"),
+        PathBuf::from,
+    );
+    let signing_key = options
+        .signing_key
+        .as_ref()
+        .map(|path| {
+            verification::load_signing_key(path).map_err(|error| format!("{}: {}", path, error))
+        })
+        .transpose()?;
+
+    for problem in cartridge_builder::validate(&cartridge) {
+        eprintln!("warning: {}", problem);
+    }
+    cartridge_builder::write_cartridge(&cartridge, &output, signing_key.as_ref())
+        .map_err(|error| format!("{}: {}", output.display(), error))?;
+    println!("Wrote {}", output.display());
+    Ok(())
+}
+
+fn inspect(path: &str) -> Result<(), String> {
+    let cartridge = MonadBootCartridge::from_file(path).map_err(|error| error.to_string())?;
+    print!("{}", cartridge_builder::inspect(&cartridge));
+    Ok(())
+}
+
+fn validate(path: &str, options: &Options) -> Result<(), String> {
+    let mut policy = VerificationPolicy::default();
+    for key_path in &options.trusted_keys {
+        let key = verification::load_verifying_key(key_path)
+            .map_err(|error| format!("{}: {}", key_path, error))?;
+        policy = policy.with_trusted_key(key).with_signature_required();
+    }
+
+    // Loading already checks the header, checksum, segment table and signature
+    let cartridge = MonadBootCartridge::from_file_with_policy(path, &policy)
+        .map_err(|error| error.to_string())?;
+    let problems = cartridge_builder::validate(&cartridge);
+    if problems.is_empty() {
+        println!("{} is valid", path);
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(format!("{} has {} problems", path, problems.len()))
+}
+
+fn patch(cartridge_path: &str, patch_path: &str, options: &Options) -> Result<(), String> {
+    let cartridge = MonadBootCartridge::from_file_patched(
+        cartridge_path,
+        None,
+        Some(patch_path),
+        options.patch_checksum,
+        &VerificationPolicy::default(),
+    )
+    .map_err(|error| error.to_string())?;
+    let output = options.output.as_ref().map_or_else(
+        || PathBuf::from(patch_path).with_extension("bin"),
+        PathBuf::from,
+    );
+
+    // The patched image is written unsigned, since the original signature no longer covers it
+    cartridge_builder::write_cartridge(&cartridge, &output, None)
+        .map_err(|error| format!("{}: {}", output.display(), error))?;
+    println!("Wrote {}", output.display());
+    Ok(())
+}
+
+fn keygen(path: &str) -> Result<(), String> {
+    let key = verification::generate_signing_key().map_err(|error| error.to_string())?;
+    verification::save_key_pair(&key, path).map_err(|error| format!("{}: {}", path, error))?;
+    println!(
+        "Wrote {} and {}",
+        path,
+        PathBuf::from(path).with_extension("pub").display()
+    );
+    Ok(())
+}
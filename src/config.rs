@@ -0,0 +1,827 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    cpus::timing::{CycleCostPreset, CycleCosts},
+    misc::{
+        io_bus::{IoDevice, RemappedDevice},
+        memory_bus::{DEFAULT_RAM_SIZE, MemoryRegion, parse_permissions},
+    },
+    motherboards::Monarch64Motherboard,
+    motherboards::monad::MONAD_CLOCK_HZ,
+    peripherals::{
+        audio::{
+            sound::{DEFAULT_SAMPLE_RATE, SOUND_PORTS, SoundDevice},
+            wav::WavWriter,
+        },
+        debug::semihosting::{SEMIHOSTING_PORT, Semihosting},
+        input::{
+            gamepad::{GAMEPAD_PORTS, Gamepad, GamepadSource, GamepadState, KeyMap},
+            recording::{InputRecorder, InputRecording},
+        },
+        network::{
+            backend::backend_from_spec,
+            nic::{NIC_PORTS, Nic},
+        },
+        storage::{
+            executable::ExecutableFormat,
+            monad_boot_cartridge::{CartridgeLoadError, MonadBootCartridge},
+            verification::{self, KeyError, VerificationAction, VerificationPolicy},
+        },
+        system::{
+            rng::{HardwareRng, RNG_PORT},
+            watchdog::{WATCHDOG_PORTS, Watchdog},
+        },
+    },
+    registry::ModelRegistry,
+    system::Monarch64System,
+};
+
+/// Describes a whole machine, read from a TOML or JSON file.
+///
+/// ```text
+/// motherboard = "monad"
+/// cpu = "monad"
+/// ram_size = 0x10000
+///
+/// [cartridge]
+/// path = "game.bin"
+/// patch = "fix.ips"
+/// patch_checksum = 0x1234ABCD
+/// verify = "warn"
+/// trusted_keys = ["vendor.pub"]
+///
+/// [cycle_costs]
+/// preset = "estimates"
+/// multiply_divide = 12
+///
+/// [[cartridge.swaps]]
+/// at = 100000
+/// path = "disk2.bin"
+///
+/// [[memory]]
+/// kind = "rom"
+/// base = 0x2000_0000
+/// file = "firmware.bin"
+/// permissions = "rx"
+///
+/// [[memory]]
+/// kind = "ram"
+/// base = 0x3000_0000
+/// size = 0x1000
+///
+/// [[devices]]
+/// kind = "rng"
+/// settings = { seed = 42 }
+///
+/// [[devices]]
+/// kind = "gamepad"
+/// port = 0x200
+/// interrupt = 0x21
+///
+/// [devices.settings]
+/// replay = "inputs.txt"
+/// ```
+///
+/// The motherboard and CPU are looked up by name in a `ModelRegistry`. Files are relative to the config. Without a `devices` list the machine gets the hardware RNG and the watchdog,
+/// with one it gets exactly the devices listed. The debug console and cartridge slot are part of the motherboard and
+/// always present. Without `cycle_costs` every instruction takes one cycle. With it, costs left out of the table
+/// take the preset's, which is `flat` unless given.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    pub motherboard: String,
+    /// Leaving the CPU out fits the one the motherboard comes with.
+    pub cpu: Option<String>,
+    /// On the Monad, RAM can't reach into the cartridge ROM at 0x1000_0000.
+    pub ram_size: usize,
+    pub cycle_costs: Option<CycleCostsConfig>,
+    pub cartridge: Option<CartridgeConfig>,
+    pub memory: Vec<MemoryConfig>,
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            motherboard: "monad".to_string(),
+            cpu: None,
+            ram_size: DEFAULT_RAM_SIZE,
+            cycle_costs: None,
+            cartridge: None,
+            memory: Vec::new(),
+            devices: vec![
+                DeviceConfig::new(DeviceKind::Rng { seed: None }),
+                DeviceConfig::new(DeviceKind::Watchdog {}),
+            ],
+        }
+    }
+}
+
+/// How many cycles each class of instruction takes: a preset, with any cost given here in place of its own.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CycleCostsConfig {
+    pub preset: CycleCostPreset,
+    pub alu: Option<u64>,
+    pub multiply_divide: Option<u64>,
+    pub memory: Option<u64>,
+    pub io: Option<u64>,
+    pub branch_taken: Option<u64>,
+    pub branch_not_taken: Option<u64>,
+    pub system: Option<u64>,
+}
+
+impl CycleCostsConfig {
+    pub fn costs(&self) -> CycleCosts {
+        let preset = self.preset.costs();
+        CycleCosts {
+            alu: self.alu.unwrap_or(preset.alu),
+            multiply_divide: self.multiply_divide.unwrap_or(preset.multiply_divide),
+            memory: self.memory.unwrap_or(preset.memory),
+            io: self.io.unwrap_or(preset.io),
+            branch_taken: self.branch_taken.unwrap_or(preset.branch_taken),
+            branch_not_taken: self.branch_not_taken.unwrap_or(preset.branch_not_taken),
+            system: self.system.unwrap_or(preset.system),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CartridgeConfig {
+    pub path: PathBuf,
+    /// `elf`, `hex` or `srec`, for images whose extension doesn't say what they are.
+    #[serde(default)]
+    pub format: Option<ExecutableFormat>,
+    /// An IPS or BPS patch applied to the cartridge as it loads.
+    #[serde(default)]
+    pub patch: Option<PathBuf>,
+    /// The CRC-32 of the image an IPS patch was made for, which IPS patches don't record themselves.
+    #[serde(default)]
+    pub patch_checksum: Option<u32>,
+    #[serde(default)]
+    pub verify: VerificationAction,
+    #[serde(default)]
+    pub trusted_keys: Vec<PathBuf>,
+    #[serde(default)]
+    pub require_signature: bool,
+    #[serde(default)]
+    pub swaps: Vec<SwapConfig>,
+}
+
+/// A cartridge swap once the machine reaches cycle `at`. Without a `path` the cartridge is ejected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SwapConfig {
+    pub at: u64,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+/// Memory beyond main RAM. ROM is filled from `file`, RAM starts out with `file` if there is one and is `size` bytes,
+/// or the size of the file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum MemoryConfig {
+    Rom {
+        base: u64,
+        file: PathBuf,
+        /// Any of `r`, `w` and `x`. ROM can't be written whatever this says.
+        #[serde(default = "rom_permissions")]
+        permissions: String,
+    },
+    Ram {
+        base: u64,
+        #[serde(default)]
+        size: Option<usize>,
+        #[serde(default)]
+        file: Option<PathBuf>,
+    },
+}
+
+fn rom_permissions() -> String {
+    "rx".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "DeviceTable")]
+pub struct DeviceConfig {
+    pub kind: DeviceKind,
+    /// Moves the device so its lowest port is here, keeping the rest of its ports in the same order.
+    pub port: Option<u16>,
+    /// The interrupt vector the device starts with, for devices that raise interrupts. The guest can still change it.
+    pub interrupt: Option<u8>,
+}
+
+/// A device as it is written in a config. The settings for its kind are a table of their own, so a misspelt key is
+/// refused wherever it is rather than silently ignored.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceTable {
+    kind: String,
+    #[serde(default)]
+    settings: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    interrupt: Option<u8>,
+}
+
+impl TryFrom<DeviceTable> for DeviceConfig {
+    type Error = String;
+
+    fn try_from(table: DeviceTable) -> Result<Self, Self::Error> {
+        let mut settings = table.settings;
+        if settings
+            .insert("kind".to_string(), serde_json::Value::String(table.kind))
+            .is_some()
+        {
+            return Err("a device's kind goes next to its settings, not in them".to_string());
+        }
+        Ok(Self {
+            kind: DeviceKind::deserialize(serde_json::Value::Object(settings))
+                .map_err(|error| error.to_string())?,
+            port: table.port,
+            interrupt: table.interrupt,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum DeviceKind {
+    /// Host file access, sandboxed to `root`.
+    Semihosting {
+        root: PathBuf,
+    },
+    /// The hardware RNG. Setting a seed makes it deterministic.
+    Rng {
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+    // A struct variant, unlike a unit one, has its settings checked for unknown keys
+    Watchdog {},
+    /// The NIC, with a backend spec such as `unix:/tmp/a.sock:/tmp/b.sock`.
+    Nic {
+        backend: String,
+    },
+    /// The sound device, recording to a WAV file.
+    Sound {
+        wav: PathBuf,
+    },
+    /// The gamepad, reading the keyboard unless given a recording to replay.
+    Gamepad {
+        #[serde(default)]
+        replay: Option<PathBuf>,
+        #[serde(default)]
+        record: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    Key {
+        path: PathBuf,
+        error: KeyError,
+    },
+    Cartridge(CartridgeLoadError),
+    /// No model of this kind is registered under the name.
+    UnknownModel {
+        kind: &'static str,
+        name: String,
+        known: Vec<&'static str>,
+    },
+    /// A device couldn't be set up.
+    Device {
+        kind: &'static str,
+        error: io::Error,
+    },
+    /// The config parsed but describes a machine that can't be built.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Toml(error) => write!(f, "bad machine config: {}", error),
+            ConfigError::Json(error) => write!(f, "bad machine config: {}", error),
+            ConfigError::Key { path, error } => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Cartridge(error) => write!(f, "{}", error),
+            ConfigError::UnknownModel { kind, name, known } => write!(
+                f,
+                "unknown {} {:?}, expected one of {}",
+                kind,
+                name,
+                known.join(", ")
+            ),
+            ConfigError::Device { kind, error } => {
+                write!(f, "failed to set up the {}: {}", kind, error)
+            }
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<CartridgeLoadError> for ConfigError {
+    fn from(error: CartridgeLoadError) -> Self {
+        ConfigError::Cartridge(error)
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, ConfigError> {
+    fs::read(path).map_err(|error| ConfigError::Io {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+impl MachineConfig {
+    pub fn parse_toml(config: &str) -> Result<Self, ConfigError> {
+        toml::from_str(config).map_err(ConfigError::Toml)
+    }
+
+    pub fn parse_json(config: &str) -> Result<Self, ConfigError> {
+        serde_json::from_str(config).map_err(ConfigError::Json)
+    }
+
+    /// Reads a config, as JSON if the file ends in `.json` and TOML otherwise, resolving the files it names against
+    /// the directory it is in.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let config = fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let mut config = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            Self::parse_json(&config)?
+        } else {
+            Self::parse_toml(&config)?
+        };
+        config.resolve_paths(path.parent().unwrap_or(Path::new("")));
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, directory: &Path) {
+        let resolve = |path: &mut PathBuf| *path = directory.join(&*path);
+        if let Some(cartridge) = &mut self.cartridge {
+            resolve(&mut cartridge.path);
+            cartridge.patch.iter_mut().for_each(resolve);
+            cartridge.trusted_keys.iter_mut().for_each(resolve);
+            for swap in &mut cartridge.swaps {
+                swap.path.iter_mut().for_each(resolve);
+            }
+        }
+        for region in &mut self.memory {
+            match region {
+                MemoryConfig::Rom { file, .. } => resolve(file),
+                MemoryConfig::Ram { file, .. } => file.iter_mut().for_each(resolve),
+            }
+        }
+        for device in &mut self.devices {
+            match &mut device.kind {
+                DeviceKind::Semihosting { root } => resolve(root),
+                DeviceKind::Sound { wav } => resolve(wav),
+                DeviceKind::Gamepad { replay, record } => {
+                    replay.iter_mut().for_each(resolve);
+                    record.iter_mut().for_each(resolve);
+                }
+                DeviceKind::Rng { .. } | DeviceKind::Watchdog {} | DeviceKind::Nic { .. } => {}
+            }
+        }
+    }
+
+    /// Builds the machine out of the built-in models, loading its cartridge and memory and setting up its devices.
+    /// It still has to be `init`ed.
+    pub fn build(&self) -> Result<Monarch64System, ConfigError> {
+        self.build_with(&ModelRegistry::default())
+    }
+
+    /// Builds the machine, looking its motherboard and CPU up in `registry`.
+    pub fn build_with(&self, registry: &ModelRegistry) -> Result<Monarch64System, ConfigError> {
+        let motherboard =
+            registry
+                .motherboard(&self.motherboard)
+                .ok_or_else(|| ConfigError::UnknownModel {
+                    kind: "motherboard",
+                    name: self.motherboard.clone(),
+                    known: registry
+                        .motherboards()
+                        .iter()
+                        .map(|model| model.name)
+                        .collect(),
+                })?;
+        let cpu_name = self.cpu.as_deref().unwrap_or(motherboard.default_cpu);
+        let cpu = registry
+            .cpu(cpu_name)
+            .ok_or_else(|| ConfigError::UnknownModel {
+                kind: "CPU",
+                name: cpu_name.to_string(),
+                known: registry.cpus().iter().map(|model| model.name).collect(),
+            })?;
+
+        let mut cpu = (cpu.create)();
+        if let Some(costs) = self.cycle_costs {
+            cpu.set_cycle_costs(costs.costs());
+        }
+        let motherboard = (motherboard.build)(self, cpu)?;
+        Ok(Monarch64System::new(motherboard).with_ram_size(self.ram_size))
+    }
+}
+
+impl CartridgeConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            format: None,
+            patch: None,
+            patch_checksum: None,
+            verify: VerificationAction::default(),
+            trusted_keys: Vec::new(),
+            require_signature: false,
+            swaps: Vec::new(),
+        }
+    }
+
+    pub fn policy(&self) -> Result<VerificationPolicy, ConfigError> {
+        let mut policy = VerificationPolicy::default().with_action(self.verify);
+        for path in &self.trusted_keys {
+            let key = verification::load_verifying_key(path).map_err(|error| ConfigError::Key {
+                path: path.clone(),
+                error,
+            })?;
+            policy = policy.with_trusted_key(key);
+        }
+        if self.require_signature {
+            policy = policy.with_signature_required();
+        }
+        Ok(policy)
+    }
+
+    /// Loads the cartridge the machine boots from, patched and verified.
+    pub fn load(&self) -> Result<MonadBootCartridge, ConfigError> {
+        Ok(MonadBootCartridge::from_file_patched(
+            &self.path,
+            self.format,
+            self.patch.as_ref(),
+            self.patch_checksum,
+            &self.policy()?,
+        )?)
+    }
+
+    /// Loads the cartridges to swap in, verified the same way as the boot cartridge, with the cycle to swap each
+    /// in at. `None` ejects the cartridge.
+    pub fn load_swaps(&self) -> Result<Vec<(u64, Option<MonadBootCartridge>)>, ConfigError> {
+        let policy = self.policy()?;
+        self.swaps
+            .iter()
+            .map(|swap| {
+                let cartridge = swap
+                    .path
+                    .as_ref()
+                    .map(|path| MonadBootCartridge::from_file_with_policy(path, &policy))
+                    .transpose()?;
+                Ok((swap.at, cartridge))
+            })
+            .collect()
+    }
+}
+
+impl MemoryConfig {
+    pub fn load(&self) -> Result<MemoryRegion, ConfigError> {
+        match self {
+            MemoryConfig::Rom {
+                base,
+                file,
+                permissions,
+            } => Ok(MemoryRegion::Rom {
+                base: *base,
+                data: read(file)?,
+                permissions: parse_permissions(permissions).ok_or_else(|| {
+                    ConfigError::Invalid(format!("unknown memory permissions {:?}", permissions))
+                })?,
+            }),
+            MemoryConfig::Ram { base, size, file } => {
+                let mut data = file.as_deref().map(read).transpose()?.unwrap_or_default();
+                match size {
+                    Some(size) if *size < data.len() => {
+                        return Err(ConfigError::Invalid(format!(
+                            "RAM at {:#X} is {} bytes, too small for its {} byte file",
+                            base,
+                            size,
+                            data.len()
+                        )));
+                    }
+                    Some(size) => data.resize(*size, 0),
+                    None if file.is_none() => {
+                        return Err(ConfigError::Invalid(format!(
+                            "RAM at {:#X} needs a size or a file",
+                            base
+                        )));
+                    }
+                    None => {}
+                }
+                Ok(MemoryRegion::Ram { base: *base, data })
+            }
+        }
+    }
+}
+
+impl DeviceKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceKind::Semihosting { .. } => "semihosting",
+            DeviceKind::Rng { .. } => "hardware RNG",
+            DeviceKind::Watchdog {} => "watchdog",
+            DeviceKind::Nic { .. } => "NIC",
+            DeviceKind::Sound { .. } => "sound device",
+            DeviceKind::Gamepad { .. } => "gamepad",
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn new(kind: DeviceKind) -> Self {
+        Self {
+            kind,
+            port: None,
+            interrupt: None,
+        }
+    }
+
+    /// Sets the device up and plugs it into `motherboard`.
+    pub fn attach(&self, motherboard: &mut dyn Monarch64Motherboard) -> Result<(), ConfigError> {
+        if self.interrupt.is_some()
+            && !matches!(
+                self.kind,
+                DeviceKind::Nic { .. } | DeviceKind::Gamepad { .. }
+            )
+        {
+            return Err(ConfigError::Invalid(format!(
+                "the {} doesn't raise interrupts",
+                self.kind.name()
+            )));
+        }
+
+        let (ports, device): (&[u16], Arc<Mutex<dyn IoDevice + Send>>) = match &self.kind {
+            DeviceKind::Semihosting { root } => {
+                let semihosting = Semihosting::new(root).map_err(|error| ConfigError::Io {
+                    path: root.clone(),
+                    error,
+                })?;
+                (&[SEMIHOSTING_PORT], Arc::new(Mutex::new(semihosting)))
+            }
+            DeviceKind::Rng { seed } => {
+                // Setting a seed makes the RNG deterministic so a run can be replayed exactly
+                let rng = match seed {
+                    Some(seed) => {
+                        log::info!("Hardware RNG seeded with {}", seed);
+                        HardwareRng::with_seed(*seed)
+                    }
+                    None => HardwareRng::new(),
+                };
+                (&[RNG_PORT], Arc::new(Mutex::new(rng)))
+            }
+            DeviceKind::Watchdog {} => (&WATCHDOG_PORTS, Arc::new(Mutex::new(Watchdog::new()))),
+            DeviceKind::Nic { backend } => {
+                let backend = backend_from_spec(backend).map_err(|error| ConfigError::Device {
+                    kind: self.kind.name(),
+                    error,
+                })?;
+                let mut nic = Nic::new(backend);
+                if let Some(vector) = self.interrupt {
+                    nic = nic.with_interrupt_vector(vector);
+                }
+                (&NIC_PORTS, Arc::new(Mutex::new(nic)))
+            }
+            DeviceKind::Sound { wav } => {
+                let output = WavWriter::create(wav, DEFAULT_SAMPLE_RATE).map_err(|error| {
+                    ConfigError::Io {
+                        path: wav.clone(),
+                        error,
+                    }
+                })?;
+                let sound = SoundDevice::new(output, MONAD_CLOCK_HZ, DEFAULT_SAMPLE_RATE).map_err(
+                    |error| ConfigError::Device {
+                        kind: self.kind.name(),
+                        error,
+                    },
+                )?;
+                (&SOUND_PORTS, Arc::new(Mutex::new(sound)))
+            }
+            DeviceKind::Gamepad { replay, record } => {
+                let source = match replay {
+                    Some(path) => {
+                        let recording =
+                            InputRecording::load(path).map_err(|error| ConfigError::Io {
+                                path: path.clone(),
+                                error,
+                            })?;
+                        GamepadSource::Replay(recording)
+                    }
+                    None => {
+                        let state = Arc::new(Mutex::new(GamepadState::default()));
+                        KeyMap::default().spawn_stdin_feeder(state.clone());
+                        GamepadSource::Live(state)
+                    }
+                };
+                let mut gamepad = Gamepad::new(source);
+                if let Some(path) = record {
+                    let recorder =
+                        InputRecorder::create(path).map_err(|error| ConfigError::Io {
+                            path: path.clone(),
+                            error,
+                        })?;
+                    gamepad = gamepad.with_recorder(recorder);
+                }
+                if let Some(vector) = self.interrupt {
+                    gamepad = gamepad.with_interrupt_vector(vector);
+                }
+                (&GAMEPAD_PORTS, Arc::new(Mutex::new(gamepad)))
+            }
+        };
+
+        match self.port {
+            Some(base) => {
+                let offset = base.wrapping_sub(*ports.iter().min().unwrap());
+                let ports: Vec<u16> = ports.iter().map(|port| port.wrapping_add(offset)).collect();
+                let device = RemappedDevice::new(device, offset);
+                motherboard.attach_device(&ports, Arc::new(Mutex::new(device)));
+            }
+            None => motherboard.attach_device(ports, device),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        motherboard = "monad"
+        ram_size = 0x10000
+
+        [cartridge]
+        path = "game.hex"
+        format = "hex"
+        patch = "fix.ips"
+        patch_checksum = 0x1234ABCD
+        verify = "warn"
+
+        [[cartridge.swaps]]
+        at = 100000
+        path = "disk2.bin"
+
+        [[cartridge.swaps]]
+        at = 200000
+
+        [cycle_costs]
+        preset = "estimates"
+        multiply_divide = 12
+
+        [[memory]]
+        kind = "ram"
+        base = 0x3000_0000
+        size = 0x1000
+
+        [[devices]]
+        kind = "rng"
+        settings = { seed = 42 }
+
+        [[devices]]
+        kind = "gamepad"
+        port = 0x200
+        interrupt = 0x21
+
+        [devices.settings]
+        replay = "inputs.txt"
+    "#;
+
+    const JSON: &str = r#"{
+        "motherboard": "monad",
+        "ram_size": 65536,
+        "cartridge": {
+            "path": "game.hex",
+            "format": "hex",
+            "patch": "fix.ips",
+            "patch_checksum": 305441741,
+            "verify": "warn",
+            "swaps": [{ "at": 100000, "path": "disk2.bin" }, { "at": 200000 }]
+        },
+        "cycle_costs": { "preset": "estimates", "multiply_divide": 12 },
+        "memory": [{ "kind": "ram", "base": 805306368, "size": 4096 }],
+        "devices": [
+            { "kind": "rng", "settings": { "seed": 42 } },
+            { "kind": "gamepad", "settings": { "replay": "inputs.txt" }, "port": 512, "interrupt": 33 }
+        ]
+    }"#;
+
+    fn check(config: MachineConfig) {
+        assert_eq!(config.motherboard, "monad");
+        assert_eq!(config.cpu, None);
+        assert_eq!(config.ram_size, 0x10000);
+        let costs = config.cycle_costs.unwrap().costs();
+        assert_eq!(costs.multiply_divide, 12);
+        assert_eq!(costs.memory, CycleCosts::estimates().memory);
+
+        let cartridge = config.cartridge.unwrap();
+        assert_eq!(cartridge.path, Path::new("game.hex"));
+        assert_eq!(cartridge.format, Some(ExecutableFormat::IntelHex));
+        assert_eq!(cartridge.patch.as_deref(), Some(Path::new("fix.ips")));
+        assert_eq!(cartridge.patch_checksum, Some(0x1234ABCD));
+        assert_eq!(cartridge.verify, VerificationAction::Warn);
+        let swaps: Vec<_> = cartridge
+            .swaps
+            .iter()
+            .map(|swap| (swap.at, swap.path.clone()))
+            .collect();
+        assert_eq!(
+            swaps,
+            [(100000, Some(PathBuf::from("disk2.bin"))), (200000, None)]
+        );
+
+        assert!(matches!(
+            config.memory[..],
+            [MemoryConfig::Ram {
+                base: 0x3000_0000,
+                size: Some(0x1000),
+                file: None
+            }]
+        ));
+        assert!(matches!(
+            config.devices[0].kind,
+            DeviceKind::Rng { seed: Some(42) }
+        ));
+        let gamepad = &config.devices[1];
+        assert!(matches!(
+            &gamepad.kind,
+            DeviceKind::Gamepad { replay: Some(path), record: None } if path == Path::new("inputs.txt")
+        ));
+        assert_eq!((gamepad.port, gamepad.interrupt), (Some(0x200), Some(0x21)));
+    }
+
+    #[test]
+    fn reads_toml_and_json_alike() {
+        check(MachineConfig::parse_toml(TOML).unwrap());
+        check(MachineConfig::parse_json(JSON).unwrap());
+
+        let config = MachineConfig::parse_toml("").unwrap();
+        assert_eq!(config.motherboard, "monad");
+        assert_eq!(config.devices.len(), 2);
+    }
+
+    #[test]
+    fn refuses_malformed_configs() {
+        for config in [
+            "ram_sise = 0x1000",
+            "ram_size = \"big\"",
+            "[cartridge]\npath = \"game.bin\"\npatch_sum = 1",
+            "[cartridge]\npath = \"game.bin\"\nformat = \"coff\"",
+            "[cartridge]\nverify = \"warn\"",
+            "[cycle_costs]\nfloat = 2",
+            "[cycle_costs]\npreset = \"fast\"",
+            "[[memory]]\nkind = \"flash\"\nbase = 0",
+            "[[memory]]\nkind = \"rom\"\nbase = 0\nfile = \"a.bin\"\nsize = 4",
+            "[[devices]]\nkind = \"modem\"",
+            "[[devices]]\nkind = \"rng\"\nseed = 42",
+            "[[devices]]\nkind = \"rng\"\nsettings = { sead = 42 }",
+            "[[devices]]\nkind = \"rng\"\nsettings = { kind = \"watchdog\" }",
+            "[[devices]]\nkind = \"watchdog\"\nprot = 0x200",
+            "[[devices]]\nkind = \"watchdog\"\nsettings = { timeout = 10 }",
+        ] {
+            assert!(
+                matches!(MachineConfig::parse_toml(config), Err(ConfigError::Toml(_))),
+                "{:?}",
+                config
+            );
+        }
+        assert!(matches!(
+            MachineConfig::parse_json("{ \"ram_size\": 4096, }"),
+            Err(ConfigError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_ram_over_the_cartridge_window() {
+        let config = MachineConfig::parse_toml("ram_size = 0x1000_0001").unwrap();
+        assert!(matches!(config.build(), Err(ConfigError::Invalid(_))));
+    }
+}
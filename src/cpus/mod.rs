@@ -1,10 +1,58 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use crate::misc::{io_bus::IoBus, memory_bus::MemoryBus48};
+use crate::{
+    cpus::timing::{CycleCosts, CycleCounters},
+    misc::{io_bus::IoBus, memory_bus::MemoryBus48, symbols::SymbolTable},
+};
 
 pub mod monad;
+pub mod monad_disassembler;
+pub mod timing;
+
+/// What the CPU logs as it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceMode {
+    Off,
+    /// Every instruction, disassembled.
+    #[default]
+    Instructions,
+    /// Only when execution moves into a different symbol, which follows calls and returns without the noise.
+    Symbols,
+}
+
+impl std::str::FromStr for TraceMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "off" => Ok(TraceMode::Off),
+            "instructions" => Ok(TraceMode::Instructions),
+            "symbols" => Ok(TraceMode::Symbols),
+            _ => Err(format!(
+                "unknown trace mode {:?}, expected off, instructions or symbols",
+                mode
+            )),
+        }
+    }
+}
 
 pub trait Monarch64CPU {
-    fn execute_cycle(&mut self, memory_bus: &Mutex<MemoryBus48>, io_bus: &Mutex<IoBus>);
+    /// Runs one instruction, returning how many cycles it took.
+    fn execute_cycle(&mut self, memory_bus: &Mutex<MemoryBus48>, io_bus: &Mutex<IoBus>) -> u64;
     fn run_cpu(&mut self, memory_bus: &Mutex<MemoryBus48>, io_bus: &Mutex<IoBus>);
-}
+    fn is_running(&self) -> bool;
+    fn set_running(&mut self, running: bool);
+    /// Queues an external interrupt. It is taken before the next instruction once the CPU has interrupts enabled.
+    fn raise_interrupt(&mut self, vector: u8);
+    /// Sets where execution starts, and where the stack is, after a reset.
+    fn set_reset_vector(&mut self, entry_point: u64, stack_pointer: u64);
+    /// Puts the CPU back into its power-on state. This is what the `rst` instruction does.
+    fn reset(&mut self);
+    /// Symbols used to show addresses as `label+offset` in traces, or `None` to go back to plain addresses.
+    fn set_symbols(&mut self, symbols: Option<Arc<SymbolTable>>);
+    fn set_trace_mode(&mut self, mode: TraceMode);
+    /// How many cycles each class of instruction takes from now on.
+    fn set_cycle_costs(&mut self, costs: CycleCosts);
+    /// What the CPU has run so far, broken down by instruction class.
+    fn cycle_counters(&self) -> &CycleCounters;
+}
\ No newline at end of file
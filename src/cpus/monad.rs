@@ -1,6 +1,6 @@
-use std::{ops::Neg, sync::Mutex};
+use std::{collections::VecDeque, ops::Neg, sync::{Arc, Mutex}};
 
-use crate::{cpus::Monarch64CPU, misc::{io_bus::IoBus, memory_bus::MemoryBus48}};
+use crate::{cpus::{Monarch64CPU, TraceMode, monad_disassembler::disassemble, timing::{CycleCosts, CycleCounters, InstructionClass}}, misc::{io_bus::IoBus, memory_bus::{MEMORY_EXECUTE, MemoryBus48}, symbols::SymbolTable}};
 
 pub struct MonadCPU {
     pub r0: u64,
@@ -35,12 +35,49 @@ pub struct MonadCPU {
     imm6: u64,
     imm7: u64,
     running: bool,
+    pending_interrupts: VecDeque<u8>,
+    reset_rip: u64,
+    reset_rsp: u64,
+    symbols: Option<Arc<SymbolTable>>,
+    trace_mode: TraceMode,
+    /// The symbol the last traced instruction was in, for `TraceMode::Symbols`.
+    traced_symbol: Option<String>,
+    /// Set by wfi, the CPU does nothing until an interrupt is delivered.
+    waiting: bool,
+    /// Set by the instruction being run when it jumps, even if it jumps to the next instruction.
+    branch_taken: bool,
+    cycle_costs: CycleCosts,
+    /// Cycles since the last reset, which the guest reads through `rcyc`.
+    cycle_count: u64,
+    counters: CycleCounters,
 }
 
+/// Bit of cr0 that allows external interrupts to be taken. It is cleared when an interrupt is delivered.
+const CR0_INTERRUPT_ENABLE: u64 = 0b1;
+
 impl Monarch64CPU for MonadCPU {
-    fn execute_cycle(&mut self, memory_bus: &Mutex<MemoryBus48>, io_bus: &Mutex<IoBus>) {
-        let operation: u64;
-        operation = u64::from_le_bytes(
+    fn execute_cycle(&mut self, memory_bus: &Mutex<MemoryBus48>, io_bus: &Mutex<IoBus>) -> u64 {
+        if self.cr0 & CR0_INTERRUPT_ENABLE != 0
+            && let Some(vector) = self.pending_interrupts.pop_front()
+        {
+            self.waiting = false;
+            self.deliver_interrupt(vector, memory_bus);
+        }
+
+        if self.waiting {
+            // Asleep in wfi, time passes but nothing runs until an interrupt comes in
+            self.cycle_count = self.cycle_count.wrapping_add(1);
+            self.counters.idle_cycles += 1;
+            return 1;
+        }
+
+        if !memory_bus.lock().unwrap().has_permissions(self.rip, 8, MEMORY_EXECUTE) {
+            log::error!("Attempted to execute non-executable memory at {}. Halting.", self.describe_address(self.rip));
+            self.running = false;
+            return 0;
+        }
+
+        let operation = u64::from_le_bytes(
             memory_bus
                 .lock()
                 .unwrap()
@@ -48,8 +85,9 @@ impl Monarch64CPU for MonadCPU {
                 .try_into()
                 .unwrap(),
         );
+        self.trace(operation);
         self.rip += 8;
-        log::info!("Executing Operation 0x{:X} at address 0x{:X}", operation, self.rip);
+        self.branch_taken = false;
         let opcode = (operation & 0xFFFF) as u16;
 
         match opcode {
@@ -462,7 +500,7 @@ impl Monarch64CPU for MonadCPU {
                 self.jmpp(operation);
             },
             0x030C => {
-                self.int(operation);
+                self.int(operation, memory_bus);
             },
             0x030D => {
                 self.wfi(operation);
@@ -470,6 +508,9 @@ impl Monarch64CPU for MonadCPU {
             0x030E => {
                 self.rst(operation);
             },
+            0x030F => {
+                self.iret(operation, memory_bus);
+            },
             // Begin I/O Instructions
             0x0400 => {
                 self.inb(operation, io_bus);
@@ -506,6 +547,15 @@ impl Monarch64CPU for MonadCPU {
                 panic!("Unknown opcode");
             }
         }
+
+        let class = instruction_class(opcode);
+        let cycles = self.cycle_costs.cost(class, self.branch_taken);
+        // rst starts the count over, so the cycles it took are not on it
+        if opcode != 0x030E {
+            self.cycle_count = self.cycle_count.wrapping_add(cycles);
+        }
+        self.counters.record(class, cycles, self.branch_taken);
+        cycles
     }
 
     fn run_cpu(&mut self, memory_bus: &Mutex<MemoryBus48>, io_bus: &Mutex<IoBus>) {
@@ -514,9 +564,137 @@ impl Monarch64CPU for MonadCPU {
             self.execute_cycle(memory_bus, io_bus);
         }
     }
+
+    fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn set_running(&mut self, running: bool) {
+        self.running = running;
+    }
+
+    fn raise_interrupt(&mut self, vector: u8) {
+        // Interrupts are edge triggered, raising one that is already waiting does nothing
+        if !self.pending_interrupts.contains(&vector) {
+            self.pending_interrupts.push_back(vector);
+        }
+    }
+
+    fn set_reset_vector(&mut self, entry_point: u64, stack_pointer: u64) {
+        self.reset_rip = entry_point;
+        self.reset_rsp = stack_pointer;
+    }
+
+    fn set_symbols(&mut self, symbols: Option<Arc<SymbolTable>>) {
+        self.symbols = symbols;
+    }
+
+    fn set_trace_mode(&mut self, mode: TraceMode) {
+        self.trace_mode = mode;
+        self.traced_symbol = None;
+    }
+
+    fn set_cycle_costs(&mut self, costs: CycleCosts) {
+        self.cycle_costs = costs;
+    }
+
+    fn cycle_counters(&self) -> &CycleCounters {
+        &self.counters
+    }
+
+    fn reset(&mut self) {
+        self.pending_interrupts.clear();
+        self.r0 = 0;
+        self.r1 = 0;
+        self.r2 = 0;
+        self.r3 = 0;
+        self.r4 = 0;
+        self.r5 = 0;
+        self.r6 = 0;
+        self.r7 = 0;
+        self.r8 = 0;
+        self.r9 = 0;
+        self.r10 = 0;
+        self.r11 = 0;
+        self.r12 = 0;
+        self.r13 = 0;
+        self.r14 = 0;
+        self.r15 = 0;
+        self.rflags = 0;
+        self.rip = self.reset_rip;
+        self.rsp = self.reset_rsp;
+        self.rpt = 0;
+        self.rit = 0;
+        self.cr0 = 0;
+        self.cr1 = 0;
+        self.imm0 = 0;
+        self.imm1 = 0;
+        self.imm2 = 0;
+        self.imm3 = 0;
+        self.imm4 = 0;
+        self.imm5 = 0;
+        self.imm6 = 0;
+        self.imm7 = 0;
+        self.cycle_count = 0;
+        self.waiting = false;
+        self.running = true;
+    }
+}
+
+impl Default for MonadCPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MonadCPU {
+    /// Formats an address for logs, with the symbol it falls in if we have symbols for it.
+    fn describe_address(&self, address: u64) -> String {
+        match self.symbols.as_ref().and_then(|symbols| symbols.symbolize(address)) {
+            Some(symbol) => format!("0x{:X} <{}>", address, symbol),
+            None => format!("0x{:X}", address),
+        }
+    }
+
+    fn trace(&mut self, operation: u64) {
+        match self.trace_mode {
+            TraceMode::Off => {}
+            TraceMode::Instructions => {
+                log::info!("Executing Operation 0x{:X} at address {}: {}", operation, self.describe_address(self.rip), disassemble(operation));
+            }
+            TraceMode::Symbols => {
+                let symbol = self.symbols.as_ref().and_then(|symbols| symbols.lookup(self.rip)).map(|(symbol, _)| symbol.name.clone());
+                if symbol != self.traced_symbol {
+                    log::info!("Entered {} at {}", symbol.as_deref().unwrap_or("unknown code"), self.describe_address(self.rip));
+                    self.traced_symbol = symbol;
+                }
+            }
+        }
+    }
+
+    fn branch_to(&mut self, target: u64) {
+        self.rip = target;
+        self.branch_taken = true;
+    }
+
+    /// Pushes rip and then cr0 onto the stack, and jumps to the handler in the interrupt table pointed to by rit.
+    fn deliver_interrupt(&mut self, vector: u8, memory_bus: &Mutex<MemoryBus48>) {
+        let mut memory_bus = memory_bus.lock().unwrap();
+        let handler_bytes = memory_bus.read_bytes(self.rit.wrapping_add(vector as u64 * 8), 8);
+        let Ok(handler_bytes) = handler_bytes.try_into() else {
+            log::error!("Interrupt table entry for vector {:#X} is out of bounds. Dropping interrupt.", vector);
+            return;
+        };
+
+        self.rsp = self.rsp.wrapping_sub(8);
+        memory_bus.write_bytes(self.rsp, &self.rip.to_le_bytes());
+        self.rsp = self.rsp.wrapping_sub(8);
+        memory_bus.write_bytes(self.rsp, &self.cr0.to_le_bytes());
+        self.branch_to(u64::from_le_bytes(handler_bytes));
+        // Handlers run with interrupts masked until they set the bit again or return with iret
+        self.cr0 &= !CR0_INTERRUPT_ENABLE;
+    }
+
     fn smemb(&mut self, operation: u64, memory_bus: &Mutex<MemoryBus48>) {
         let source_reg = ((operation >> 16) & 0xFFFF) as u16;
         let dest_reg = ((operation >> 32) & 0xFFFF) as u16;
@@ -579,7 +757,7 @@ impl MonadCPU {
 
         self.set_register_value_from_code(
             dest_reg,
-            (dest_value & 0xFFFFFFFFFFFFFF00) | ((u8::from_le_bytes(memory_bus.lock().unwrap().read_bytes(source_address, 1).try_into().unwrap()))) as u64
+            (dest_value & 0xFFFFFFFFFFFFFF00) | (u8::from_le_bytes(memory_bus.lock().unwrap().read_bytes(source_address, 1).try_into().unwrap())) as u64
         );
     }
 
@@ -593,7 +771,7 @@ impl MonadCPU {
 
         self.set_register_value_from_code(
             dest_reg,
-            (dest_value & 0xFFFFFFFFFFFF0000) | ((u16::from_le_bytes(memory_bus.lock().unwrap().read_bytes(source_address, 2).try_into().unwrap()))) as u64
+            (dest_value & 0xFFFFFFFFFFFF0000) | (u16::from_le_bytes(memory_bus.lock().unwrap().read_bytes(source_address, 2).try_into().unwrap())) as u64
         );
     }
 
@@ -607,7 +785,7 @@ impl MonadCPU {
 
         self.set_register_value_from_code(
             dest_reg,
-            (dest_value & 0xFFFFFFFF00000000) | ((u32::from_le_bytes(memory_bus.lock().unwrap().read_bytes(source_address, 4).try_into().unwrap()))) as u64
+            (dest_value & 0xFFFFFFFF00000000) | (u32::from_le_bytes(memory_bus.lock().unwrap().read_bytes(source_address, 4).try_into().unwrap())) as u64
         );
     }
 
@@ -625,7 +803,7 @@ impl MonadCPU {
 
     fn lli(&mut self, operation: u64) {
         let imm_reg = ((operation & 0xFFFF0000) >> 16) as u16;
-        if (imm_reg < 0xF000) | (imm_reg > 0xF007) {
+        if !(0xF000..=0xF007).contains(&imm_reg) {
             log::error!("Tried to load immediate into a non imm register.");
             panic!("Invalid immediate register");
         }
@@ -638,7 +816,7 @@ impl MonadCPU {
 
     fn lui(&mut self, operation: u64) {
         let imm_reg = ((operation & 0xFFFF0000) >> 16) as u16;
-        if (imm_reg < 0xF000) || (imm_reg > 0xF007) {
+        if !(0xF000..=0xF007).contains(&imm_reg) {
             log::error!("Tried to load immediate into a non imm register.");
             panic!("Invalid immediate register");
         }
@@ -2232,7 +2410,7 @@ impl MonadCPU {
         }
         // Sign flag is never set for unsigned addition.
         self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b100000;
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn incbs(&mut self, operation: u64) {
@@ -2418,7 +2596,7 @@ impl MonadCPU {
         }
         // Sign flag is never set for unsigned addition.
         self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b100000;
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn decbs(&mut self, operation: u64) {
@@ -2917,7 +3095,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b10000;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn orb(&mut self, operation: u64) {
@@ -3046,7 +3224,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b10000;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn xorb(&mut self, operation: u64) {
@@ -3175,7 +3353,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b10000;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn notb(&mut self, operation: u64) {
@@ -3236,7 +3414,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b1;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn norb(&mut self, operation: u64) {
@@ -3365,7 +3543,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b10000;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn nandb(&mut self, operation: u64) {
@@ -3494,7 +3672,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b10000;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
     
     fn shlb(&mut self, operation: u64) {
@@ -3554,7 +3732,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b1;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn shrb(&mut self, operation: u64) {
@@ -3614,7 +3792,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b1;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn rolb(&mut self, operation: u64) {
@@ -3674,7 +3852,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b1;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn rorb(&mut self, operation: u64) {
@@ -3734,7 +3912,7 @@ impl MonadCPU {
         } else {
             self.rflags &= 0xFFFFFFFFFFFFFFFF ^ 0b1;
         }
-        self.set_register_value_from_code(dest_reg, result as u64);
+        self.set_register_value_from_code(dest_reg, result);
     }
 
     fn bitt(&mut self, operation: u64) {
@@ -3773,14 +3951,14 @@ impl MonadCPU {
     fn jmp(&mut self, operation: u64) {
         let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
         let target_value = self.get_register_value_from_code(target_reg);
-        self.rip = target_value;
+        self.branch_to(target_value);
     }
 
     fn jmpeq(&mut self, operation: u64) {
         if (self.rflags & 0b10) != 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3788,7 +3966,7 @@ impl MonadCPU {
         if (self.rflags & 0b1) != 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3796,7 +3974,7 @@ impl MonadCPU {
         if (self.rflags & 0b10) == 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3804,7 +3982,7 @@ impl MonadCPU {
         if (self.rflags & 0b1) != 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3812,7 +3990,7 @@ impl MonadCPU {
         if (self.rflags & 0b1000) != 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3820,7 +3998,7 @@ impl MonadCPU {
         if ((self.rflags & 0b1000) != 0) | ((self.rflags & 0b10) != 0) {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3828,7 +4006,7 @@ impl MonadCPU {
         if (self.rflags & 0b10000) != 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3836,7 +4014,7 @@ impl MonadCPU {
         if ((self.rflags & 0b10000) != 0) | ((self.rflags & 0b10) != 0) {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3844,7 +4022,7 @@ impl MonadCPU {
         if (self.rflags & 0b100000) != 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3852,7 +4030,7 @@ impl MonadCPU {
         if (self.rflags & 0b1000000) != 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
@@ -3860,51 +4038,43 @@ impl MonadCPU {
         if (self.rflags & 0b1000000) == 0 {
             let target_reg = ((operation & 0xFFFF0000) >> 16) as u16;
             let target_value = self.get_register_value_from_code(target_reg);
-            self.rip = target_value;
+            self.branch_to(target_value);
         }
     }
 
-    fn int(&mut self, operation: u64) {
-        todo!("Interrupts are not implemented yet");
+    fn int(&mut self, operation: u64, memory_bus: &Mutex<MemoryBus48>) {
+        let vector_reg = ((operation & 0xFFFF0000) >> 16) as u16;
+        let vector = (self.get_register_value_from_code(vector_reg) & 0xFF) as u8;
+
+        // Software interrupts are taken even with interrupts masked, it's the guest asking for them
+        self.deliver_interrupt(vector, memory_bus);
     }
 
-    fn wfi(&mut self, operation: u64) {
-        self.running = false;
+    fn iret(&mut self, _operation: u64, memory_bus: &Mutex<MemoryBus48>) {
+        let memory_bus = memory_bus.lock().unwrap();
+        let frame = memory_bus.read_bytes(self.rsp, 16);
+        let Ok(frame) = <[u8; 16]>::try_from(frame) else {
+            log::error!("Interrupt frame at {:#X} is out of bounds. Halting.", self.rsp);
+            self.running = false;
+            return;
+        };
+
+        self.cr0 = u64::from_le_bytes(frame[0..8].try_into().unwrap());
+        self.branch_to(u64::from_le_bytes(frame[8..16].try_into().unwrap()));
+        self.rsp = self.rsp.wrapping_add(16);
     }
 
-    fn rst(&mut self, operation: u64) {
-        self.r0 = 0;
-        self.r1 = 0;
-        self.r2 = 0;
-        self.r3 = 0;
-        self.r4 = 0;
-        self.r5 = 0;
-        self.r6 = 0;
-        self.r7 = 0;
-        self.r8 = 0;
-        self.r9 = 0;
-        self.r10 = 0;
-        self.r11 = 0;
-        self.r12 = 0;
-        self.r13 = 0;
-        self.r14 = 0;
-        self.r15 = 0;
-        self.rflags = 0;
-        self.rip = 0;
-        self.rsp = 0;
-        self.rpt = 0;
-        self.rit = 0;
-        self.cr0 = 0;
-        self.cr1 = 0;
-        self.imm0 = 0;
-        self.imm1 = 0;
-        self.imm2 = 0;
-        self.imm3 = 0;
-        self.imm4 = 0;
-        self.imm5 = 0;
-        self.imm6 = 0;
-        self.imm7 = 0;
-        self.running = true;
+    fn wfi(&mut self, _operation: u64) {
+        if self.cr0 & CR0_INTERRUPT_ENABLE != 0 {
+            self.waiting = true;
+        } else {
+            // With interrupts masked nothing could ever wake us, so this is where the program ends
+            self.running = false;
+        }
+    }
+
+    fn rst(&mut self, _operation: u64) {
+        self.reset();
     }
 
     fn inb(&mut self, operation: u64, io_bus: &Mutex<IoBus>) {
@@ -3987,7 +4157,7 @@ impl MonadCPU {
         io_bus.lock().unwrap().write_u64(port_index, input_value);
     }
 
-    fn cpuid(&mut self, operation: u64) {
+    fn cpuid(&mut self, _operation: u64) {
         todo!("CPUID instruction is not implemented yet");
     }
 
@@ -4016,6 +4186,7 @@ impl MonadCPU {
             0x0014 => self.rit,
             0x0015 => self.cr0,
             0x0016 => self.cr1,
+            0x0017 => self.cycle_count,
             0xF000 => self.imm0,
             0xF001 => self.imm1,
             0xF002 => self.imm2,
@@ -4056,6 +4227,11 @@ impl MonadCPU {
             0x0014 => &mut self.rit,
             0x0015 => &mut self.cr0,
             0x0016 => &mut self.cr1,
+            0x0017 => {
+                // The cycle counter only counts up, writes to it are dropped like they would be on the hardware
+                log::warn!("Ignoring write of {:#X} to the read-only cycle counter rcyc", value);
+                return;
+            },
             0xF000 => &mut self.imm0,
             0xF001 => &mut self.imm1,
             0xF002 => &mut self.imm2,
@@ -4107,6 +4283,133 @@ impl MonadCPU {
             imm6: 0,
             imm7: 0,
             running: false,
+            pending_interrupts: VecDeque::new(),
+            reset_rip: 0,
+            reset_rsp: 0,
+            symbols: None,
+            trace_mode: TraceMode::default(),
+            traced_symbol: None,
+            waiting: false,
+            branch_taken: false,
+            cycle_costs: CycleCosts::default(),
+            cycle_count: 0,
+            counters: CycleCounters::default(),
         }
     }
+
+    pub fn with_cycle_costs(mut self, costs: CycleCosts) -> Self {
+        self.cycle_costs = costs;
+        self
+    }
+}
+
+/// Which cost class an opcode falls in, following the opcode groups of the instruction set.
+fn instruction_class(opcode: u16) -> InstructionClass {
+    match opcode {
+        0x0001..=0x0008 => InstructionClass::Memory,
+        0x0009..=0x0014 => InstructionClass::Alu,
+        0x0110..=0x011F => InstructionClass::MultiplyDivide,
+        0x0100..=0x02FF => InstructionClass::Alu,
+        0x0300..=0x030C | 0x030F => InstructionClass::Branch,
+        0x0400..=0x0407 => InstructionClass::Io,
+        _ => InstructionClass::System,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_operations(memory_bus: &Mutex<MemoryBus48>, address: u64, operations: &[u64]) {
+        let bytes: Vec<u8> = operations.iter().flat_map(|operation| operation.to_le_bytes()).collect();
+        memory_bus.lock().unwrap().write_bytes(address, &bytes);
+    }
+
+    #[test]
+    fn interrupts_wake_wfi_and_handlers_return_with_iret() {
+        let memory_bus = Mutex::new(MemoryBus48::new());
+        let io_bus = Mutex::new(IoBus::new());
+        // wfi, int r0, wfi, with a handler for vector 3 that returns straight away
+        write_operations(&memory_bus, 0x0, &[0x030D, 0x030C, 0x030D]);
+        write_operations(&memory_bus, 0x800 + 3 * 8, &[0x900]);
+        write_operations(&memory_bus, 0x900, &[0x030F]);
+
+        let mut cpu = MonadCPU::new();
+        cpu.rit = 0x800;
+        cpu.rsp = 0xF00;
+        cpu.cr0 = CR0_INTERRUPT_ENABLE;
+        cpu.r0 = 3;
+        cpu.set_running(true);
+
+        cpu.execute_cycle(&memory_bus, &io_bus);
+        cpu.execute_cycle(&memory_bus, &io_bus);
+        assert!(cpu.is_running());
+        assert_eq!(cpu.rip, 0x8);
+        assert_eq!(cpu.cycle_counters().idle_cycles, 1);
+
+        cpu.raise_interrupt(3);
+        cpu.execute_cycle(&memory_bus, &io_bus);
+        assert_eq!((cpu.rip, cpu.rsp, cpu.cr0), (0x8, 0xF00, CR0_INTERRUPT_ENABLE));
+
+        // Software interrupts are taken even with interrupts masked
+        cpu.cr0 = 0;
+        cpu.execute_cycle(&memory_bus, &io_bus);
+        assert_eq!((cpu.rip, cpu.rsp), (0x900, 0xF00 - 16));
+        cpu.execute_cycle(&memory_bus, &io_bus);
+        assert_eq!((cpu.rip, cpu.rsp, cpu.cr0), (0x10, 0xF00, 0));
+
+        // Nothing could wake a wfi with interrupts masked, so it halts
+        cpu.execute_cycle(&memory_bus, &io_bus);
+        assert!(!cpu.is_running());
+    }
+
+    #[test]
+    fn branches_cost_what_they_decided_and_rst_restarts_the_count() {
+        let memory_bus = Mutex::new(MemoryBus48::new());
+        let io_bus = Mutex::new(IoBus::new());
+        // jmp r0 to the very next instruction, jmpz r1 with the zero flag clear, rst
+        write_operations(&memory_bus, 0x0, &[0x0300, 0x0302 | 1 << 16, 0x030E]);
+
+        let mut cpu = MonadCPU::new().with_cycle_costs(CycleCosts {
+            branch_taken: 3,
+            system: 5,
+            ..CycleCosts::flat()
+        });
+        cpu.r0 = 0x8;
+        cpu.r1 = 0x100;
+        cpu.set_running(true);
+
+        cpu.execute_cycle(&memory_bus, &io_bus);
+        assert_eq!((cpu.rip, cpu.cycle_count), (0x8, 3));
+        cpu.execute_cycle(&memory_bus, &io_bus);
+        assert_eq!((cpu.rip, cpu.cycle_count), (0x10, 4));
+        assert_eq!(cpu.execute_cycle(&memory_bus, &io_bus), 5);
+        assert_eq!(cpu.cycle_count, 0);
+
+        let counters = cpu.cycle_counters();
+        assert_eq!((counters.branches_taken, counters.branches_not_taken), (1, 1));
+        assert_eq!(counters.cycles, 9);
+    }
+    #[test]
+    fn the_estimates_preset_retimes_a_loop() {
+        let memory_bus = Mutex::new(MemoryBus48::new());
+        let io_bus = Mutex::new(IoBus::new());
+        // Count r0 down to zero: decq r0, jmpz r2 to the wfi, jmp r1 back to the start
+        write_operations(&memory_bus, 0x0, &[0x012B, 0x0302 | 2 << 16, 0x0300 | 1 << 16, 0x030D]);
+
+        let run = |config: &str| {
+            let costs = crate::MachineConfig::parse_toml(config).unwrap().cycle_costs.unwrap_or_default();
+            let mut cpu = MonadCPU::new().with_cycle_costs(costs.costs());
+            cpu.r0 = 3;
+            cpu.r2 = 0x18;
+            cpu.set_running(true);
+            while cpu.is_running() {
+                cpu.execute_cycle(&memory_bus, &io_bus);
+            }
+            cpu.cycle_counters().cycles
+        };
+        assert_eq!(run(""), 9);
+        assert_eq!(run("[cycle_costs]\npreset = \"estimates\""), 15);
+        assert_eq!(run("[cycle_costs]\npreset = \"estimates\"\nbranch_taken = 5"), 21);
+    }
 }
@@ -0,0 +1,259 @@
+use crate::misc::symbols::SymbolTable;
+
+/// Which of the operand fields an instruction uses. Register fields are 16 bits each, at bits 16, 32 and 48, and an
+/// immediate is the top 32 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operands {
+    None,
+    One,
+    Two,
+    Three,
+    Immediate,
+}
+
+const INSTRUCTIONS: &[(u16, &str, Operands)] = &[
+    (0x0000, "nop", Operands::None),
+    (0x0001, "smemb", Operands::Two),
+    (0x0002, "smemw", Operands::Two),
+    (0x0003, "smemd", Operands::Two),
+    (0x0004, "smemq", Operands::Two),
+    (0x0005, "lmemb", Operands::Two),
+    (0x0006, "lmemw", Operands::Two),
+    (0x0007, "lmemd", Operands::Two),
+    (0x0008, "lmemq", Operands::Two),
+    (0x0009, "lli", Operands::Immediate),
+    (0x000A, "lui", Operands::Immediate),
+    (0x000B, "cbw", Operands::Two),
+    (0x000C, "cbws", Operands::Two),
+    (0x000D, "cwd", Operands::Two),
+    (0x000E, "cwds", Operands::Two),
+    (0x000F, "cdq", Operands::Two),
+    (0x0010, "cdqs", Operands::Two),
+    (0x0011, "movb", Operands::Two),
+    (0x0012, "movw", Operands::Two),
+    (0x0013, "movd", Operands::Two),
+    (0x0014, "movq", Operands::Two),
+    (0x0100, "addb", Operands::Three),
+    (0x0101, "addw", Operands::Three),
+    (0x0102, "addd", Operands::Three),
+    (0x0103, "addq", Operands::Three),
+    (0x0104, "addbs", Operands::Three),
+    (0x0105, "addws", Operands::Three),
+    (0x0106, "addds", Operands::Three),
+    (0x0107, "addqs", Operands::Three),
+    (0x0108, "subb", Operands::Three),
+    (0x0109, "subw", Operands::Three),
+    (0x010A, "subd", Operands::Three),
+    (0x010B, "subq", Operands::Three),
+    (0x010C, "subbs", Operands::Three),
+    (0x010D, "subws", Operands::Three),
+    (0x010E, "subds", Operands::Three),
+    (0x010F, "subqs", Operands::Three),
+    (0x0110, "mulb", Operands::Three),
+    (0x0111, "mulw", Operands::Three),
+    (0x0112, "muld", Operands::Three),
+    (0x0113, "mulq", Operands::Three),
+    (0x0114, "mulbs", Operands::Three),
+    (0x0115, "mulws", Operands::Three),
+    (0x0116, "mulds", Operands::Three),
+    (0x0117, "mulqs", Operands::Three),
+    (0x0118, "divb", Operands::Three),
+    (0x0119, "divw", Operands::Three),
+    (0x011A, "divd", Operands::Three),
+    (0x011B, "divq", Operands::Three),
+    (0x011C, "divbs", Operands::Three),
+    (0x011D, "divws", Operands::Three),
+    (0x011E, "divds", Operands::Three),
+    (0x011F, "divqs", Operands::Three),
+    (0x0120, "incb", Operands::One),
+    (0x0121, "incw", Operands::One),
+    (0x0122, "incd", Operands::One),
+    (0x0123, "incq", Operands::One),
+    (0x0124, "incbs", Operands::One),
+    (0x0125, "incws", Operands::One),
+    (0x0126, "incds", Operands::One),
+    (0x0127, "incqs", Operands::One),
+    (0x0128, "decb", Operands::One),
+    (0x0129, "decw", Operands::One),
+    (0x012A, "decd", Operands::One),
+    (0x012B, "decq", Operands::One),
+    (0x012C, "decbs", Operands::One),
+    (0x012D, "decws", Operands::One),
+    (0x012E, "decds", Operands::One),
+    (0x012F, "decqs", Operands::One),
+    (0x0130, "negb", Operands::One),
+    (0x0131, "negw", Operands::One),
+    (0x0132, "negd", Operands::One),
+    (0x0133, "negq", Operands::One),
+    (0x0134, "cmpb", Operands::Two),
+    (0x0135, "cmpw", Operands::Two),
+    (0x0136, "cmpd", Operands::Two),
+    (0x0137, "cmpq", Operands::Two),
+    (0x0138, "cmpbs", Operands::Two),
+    (0x0139, "cmpws", Operands::Two),
+    (0x013A, "cmpds", Operands::Two),
+    (0x013B, "cmpqs", Operands::Two),
+    (0x013C, "andb", Operands::Three),
+    (0x013D, "andw", Operands::Three),
+    (0x013E, "andd", Operands::Three),
+    (0x013F, "andq", Operands::Three),
+    (0x0140, "orb", Operands::Three),
+    (0x0141, "orw", Operands::Three),
+    (0x0142, "ord", Operands::Three),
+    (0x0143, "orq", Operands::Three),
+    (0x0144, "xorb", Operands::Three),
+    (0x0145, "xorw", Operands::Three),
+    (0x0146, "xord", Operands::Three),
+    (0x0147, "xorq", Operands::Three),
+    (0x0148, "notb", Operands::Two),
+    (0x0149, "notw", Operands::Two),
+    (0x014A, "notd", Operands::Two),
+    (0x014B, "notq", Operands::One),
+    (0x014C, "norb", Operands::Three),
+    (0x014D, "norw", Operands::Three),
+    (0x014E, "nord", Operands::Three),
+    (0x014F, "norq", Operands::Three),
+    (0x0150, "nandb", Operands::Three),
+    (0x0151, "nandw", Operands::Three),
+    (0x0152, "nandd", Operands::Three),
+    (0x0153, "nandq", Operands::Three),
+    (0x0154, "shlb", Operands::Two),
+    (0x0155, "shlw", Operands::Two),
+    (0x0156, "shld", Operands::Two),
+    (0x0157, "shlq", Operands::Two),
+    (0x0158, "shrb", Operands::Two),
+    (0x0159, "shrw", Operands::Two),
+    (0x015A, "shrd", Operands::Two),
+    (0x015B, "shrq", Operands::Two),
+    (0x015C, "rolb", Operands::Two),
+    (0x015D, "rolw", Operands::Two),
+    (0x015E, "rold", Operands::Two),
+    (0x015F, "rolq", Operands::Two),
+    (0x0160, "rorb", Operands::Two),
+    (0x0161, "rorw", Operands::Two),
+    (0x0162, "rord", Operands::Two),
+    (0x0163, "rorq", Operands::Two),
+    (0x0200, "bitt", Operands::Two),
+    (0x0201, "bits", Operands::Two),
+    (0x0202, "bitc", Operands::Two),
+    (0x0300, "jmp", Operands::One),
+    (0x0301, "jmpeq", Operands::One),
+    (0x0302, "jmpz", Operands::One),
+    (0x0303, "jmpneq", Operands::One),
+    (0x0304, "jmpnz", Operands::One),
+    (0x0305, "jmpgt", Operands::One),
+    (0x0306, "jmpge", Operands::One),
+    (0x0307, "jmplt", Operands::One),
+    (0x0308, "jmple", Operands::One),
+    (0x0309, "jmpo", Operands::One),
+    (0x030A, "jmpn", Operands::One),
+    (0x030B, "jmpp", Operands::One),
+    (0x030C, "int", Operands::One),
+    (0x030D, "wfi", Operands::None),
+    (0x030E, "rst", Operands::None),
+    (0x030F, "iret", Operands::None),
+    (0x0400, "inb", Operands::Two),
+    (0x0401, "inw", Operands::Two),
+    (0x0402, "ind", Operands::Two),
+    (0x0403, "inq", Operands::Two),
+    (0x0404, "outb", Operands::Two),
+    (0x0405, "outw", Operands::Two),
+    (0x0406, "outd", Operands::Two),
+    (0x0407, "outq", Operands::Two),
+    (0x0FFF, "cpuid", Operands::None),
+];
+
+/// The assembler name of a register code, e.g. `r3` or `imm0`.
+pub fn register_name(code: u16) -> String {
+    match code {
+        0x0000..=0x000F => format!("r{}", code),
+        0x0010 => "rflags".to_string(),
+        0x0011 => "rip".to_string(),
+        0x0012 => "rsp".to_string(),
+        0x0013 => "rpt".to_string(),
+        0x0014 => "rit".to_string(),
+        0x0015 => "cr0".to_string(),
+        0x0016 => "cr1".to_string(),
+        0x0017 => "rcyc".to_string(),
+        0xF000..=0xF007 => format!("imm{}", code - 0xF000),
+        _ => format!("?{:#X}", code),
+    }
+}
+
+/// Decodes one operation into assembler syntax, e.g. `addq r1, r2, r3`. Unknown opcodes come out as `.quad`.
+pub fn disassemble(operation: u64) -> String {
+    let opcode = operation as u16;
+    let Ok(index) = INSTRUCTIONS.binary_search_by_key(&opcode, |&(opcode, _, _)| opcode) else {
+        return format!(".quad {:#018X}", operation);
+    };
+    let (_, mnemonic, operands) = INSTRUCTIONS[index];
+    let register = |shift: u32| register_name((operation >> shift) as u16);
+    match operands {
+        Operands::None => mnemonic.to_string(),
+        Operands::One => format!("{} {}", mnemonic, register(16)),
+        Operands::Two => format!("{} {}, {}", mnemonic, register(16), register(32)),
+        Operands::Three => format!(
+            "{} {}, {}, {}",
+            mnemonic,
+            register(16),
+            register(32),
+            register(48)
+        ),
+        Operands::Immediate => format!("{} {}, {:#X}", mnemonic, register(16), operation >> 32),
+    }
+}
+
+/// Disassembles `code`, loaded at `address`, one instruction per line. With symbols, every symbol gets a label line
+/// and each address is shown as `label+offset` as well.
+pub fn listing(code: &[u8], address: u64, symbols: Option<&SymbolTable>) -> String {
+    let mut listing = String::new();
+    for (index, operation) in code.chunks(8).enumerate() {
+        let instruction_address = address + index as u64 * 8;
+        let mut bytes = [0; 8];
+        bytes[..operation.len()].copy_from_slice(operation);
+        let operation = u64::from_le_bytes(bytes);
+
+        let location = symbols.and_then(|symbols| symbols.lookup(instruction_address));
+        if let Some((symbol, 0)) = location {
+            listing.push_str(&format!("\n{}:\n", symbol.name));
+        }
+        let label = symbols
+            .and_then(|symbols| symbols.symbolize(instruction_address))
+            .map_or_else(String::new, |label| format!("<{}>", label));
+        listing.push_str(&format!(
+            "{:#014X} {:<24} {:016X}  {}\n",
+            instruction_address,
+            label,
+            operation,
+            disassemble(operation)
+        ));
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// The opcodes the CPU dispatches on, read from the match in `MonadCPU::execute_cycle`.
+    fn dispatched_opcodes() -> BTreeSet<u16> {
+        let source = include_str!("monad.rs");
+        let start = source.find("        match opcode {").unwrap();
+        let end = start + source[start..].find("            _ => {").unwrap();
+        source[start..end]
+            .lines()
+            .filter_map(|line| line.trim().strip_suffix(" => {")?.strip_prefix("0x"))
+            .map(|opcode| u16::from_str_radix(opcode, 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn every_instruction_the_cpu_runs_has_a_mnemonic() {
+        let table: BTreeSet<u16> = INSTRUCTIONS.iter().map(|&(opcode, _, _)| opcode).collect();
+        assert_eq!(table, dispatched_opcodes());
+        // The lookup is a binary search, so the table has to stay sorted
+        assert!(INSTRUCTIONS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+/// The kind of work an instruction does, which decides how many cycles it costs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionClass {
+    /// Arithmetic, logic, shifts, bit tests and register moves.
+    Alu,
+    MultiplyDivide,
+    /// Loads and stores.
+    Memory,
+    /// Port reads and writes.
+    Io,
+    /// Jumps, conditional or not, software interrupts and returns from interrupts.
+    Branch,
+    /// Everything else, such as `nop`, `wfi` and `rst`.
+    System,
+}
+
+impl InstructionClass {
+    pub const ALL: [InstructionClass; 6] = [
+        InstructionClass::Alu,
+        InstructionClass::MultiplyDivide,
+        InstructionClass::Memory,
+        InstructionClass::Io,
+        InstructionClass::Branch,
+        InstructionClass::System,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InstructionClass::Alu => "ALU",
+            InstructionClass::MultiplyDivide => "multiply/divide",
+            InstructionClass::Memory => "memory",
+            InstructionClass::Io => "I/O",
+            InstructionClass::Branch => "branch",
+            InstructionClass::System => "system",
+        }
+    }
+}
+
+/// How many cycles each class of instruction takes.
+///
+/// By default every instruction takes one cycle. The watchdog, cartridge swaps and input recordings are all timed in
+/// cycles, so other costs have to be asked for per machine, through a `CycleCostPreset` and its config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleCosts {
+    pub alu: u64,
+    pub multiply_divide: u64,
+    pub memory: u64,
+    pub io: u64,
+    /// A branch that jumps, which has to refetch from its target.
+    pub branch_taken: u64,
+    /// A conditional branch that falls through to the next instruction.
+    pub branch_not_taken: u64,
+    pub system: u64,
+}
+
+impl Default for CycleCosts {
+    fn default() -> Self {
+        Self::flat()
+    }
+}
+
+impl CycleCosts {
+    /// Estimates for the Monarch64 hardware, to be tuned as it firms up.
+    pub fn estimates() -> Self {
+        Self {
+            alu: 1,
+            multiply_divide: 8,
+            memory: 3,
+            io: 4,
+            branch_taken: 3,
+            branch_not_taken: 1,
+            system: 1,
+        }
+    }
+
+    /// Every instruction takes a single cycle, so cycles count instructions.
+    pub fn flat() -> Self {
+        Self {
+            alu: 1,
+            multiply_divide: 1,
+            memory: 1,
+            io: 1,
+            branch_taken: 1,
+            branch_not_taken: 1,
+            system: 1,
+        }
+    }
+
+    /// The cycles an instruction of `class` takes. `taken` only matters for branches.
+    pub fn cost(&self, class: InstructionClass, taken: bool) -> u64 {
+        match class {
+            InstructionClass::Alu => self.alu,
+            InstructionClass::MultiplyDivide => self.multiply_divide,
+            InstructionClass::Memory => self.memory,
+            InstructionClass::Io => self.io,
+            InstructionClass::Branch if taken => self.branch_taken,
+            InstructionClass::Branch => self.branch_not_taken,
+            InstructionClass::System => self.system,
+        }
+    }
+}
+
+/// A named set of cycle costs a machine config can start from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CycleCostPreset {
+    /// `CycleCosts::flat`.
+    #[default]
+    Flat,
+    /// `CycleCosts::estimates`.
+    Estimates,
+}
+
+impl CycleCostPreset {
+    pub fn costs(self) -> CycleCosts {
+        match self {
+            CycleCostPreset::Flat => CycleCosts::flat(),
+            CycleCostPreset::Estimates => CycleCosts::estimates(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassCounters {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+/// What the CPU has run since it was created, for the host to estimate how guest code will perform.
+///
+/// Unlike the guest's cycle counter register these survive resets, so a whole session can be measured.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CycleCounters {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    /// Cycles spent asleep in `wfi`, which count towards `cycles` but aren't any instruction's.
+    pub idle_cycles: u64,
+    classes: [ClassCounters; InstructionClass::ALL.len()],
+}
+
+impl CycleCounters {
+    pub fn record(&mut self, class: InstructionClass, cycles: u64, taken: bool) {
+        self.instructions += 1;
+        self.cycles += cycles;
+        if class == InstructionClass::Branch {
+            if taken {
+                self.branches_taken += 1;
+            } else {
+                self.branches_not_taken += 1;
+            }
+        }
+        let counters = &mut self.classes[class as usize];
+        counters.instructions += 1;
+        counters.cycles += cycles;
+    }
+
+    pub fn class(&self, class: InstructionClass) -> ClassCounters {
+        self.classes[class as usize]
+    }
+}
+
+impl fmt::Display for CycleCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} instructions in {} cycles",
+            self.instructions, self.cycles
+        )?;
+        for class in InstructionClass::ALL {
+            let counters = self.class(class);
+            writeln!(
+                f,
+                "    {:<16} {:>12} instructions {:>12} cycles",
+                class.name(),
+                counters.instructions,
+                counters.cycles
+            )?;
+        }
+        writeln!(
+            f,
+            "    branches taken   {:>12}, not taken {}",
+            self.branches_taken, self.branches_not_taken
+        )?;
+        writeln!(f, "    idle             {:>12} cycles", self.idle_cycles)
+    }
+}
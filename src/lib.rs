@@ -0,0 +1,29 @@
+//! The Monarch64 emulator as a library, for tools that want to run a machine of their own.
+//!
+//! Everything is reachable through its module, and the types most embedders need are also re-exported here.
+
+pub mod config;
+pub mod cpus;
+pub mod misc;
+pub mod motherboards;
+pub mod peripherals;
+pub mod registry;
+pub mod system;
+
+pub use config::MachineConfig;
+pub use cpus::{
+    Monarch64CPU,
+    monad::MonadCPU,
+    timing::{CycleCostPreset, CycleCosts, CycleCounters},
+};
+pub use misc::{
+    io_bus::{IoBus, IoDevice, MachineSignal},
+    memory_bus::MemoryBus48,
+    symbols::SymbolTable,
+};
+pub use motherboards::{Monarch64Motherboard, monad::MonadMotherboard};
+pub use peripherals::storage::monad_boot_cartridge::{
+    CartridgeError, CartridgeLoadError, CartridgeSegment, MonadBootCartridge,
+};
+pub use registry::ModelRegistry;
+pub use system::Monarch64System;
\ No newline at end of file
@@ -1,17 +1,211 @@
-use crate::{
-    motherboards::monad::MonadMotherboard,
-    peripherals::storage::monad_boot_cartridge::MonadBootCartridge, system::Monarch64System,
+use std::{path::PathBuf, process::ExitCode};
+
+use monarch_64_emulator::{
+    MachineConfig, ModelRegistry, MonadBootCartridge,
+    config::{CartridgeConfig, DeviceConfig, DeviceKind, SwapConfig},
+    cpus::{TraceMode, monad_disassembler},
+    peripherals::storage::{
+        cartridge_builder, executable::ExecutableFormat, monad_boot_cartridge::SEGMENT_EXECUTE,
+        verification::VerificationAction,
+    },
 };
 
-pub mod cpus;
-pub mod misc;
-pub mod motherboards;
-pub mod peripherals;
-pub mod system;
+const USAGE: &str = "usage: monarch-64-emulator [command] [cartridge] [options]
+
+commands:
+    run            run the cartridge, the default
+    trace          run the cartridge, logging every instruction
+    disasm         disassemble the cartridge's executable segments
+    inspect-cart   describe the cartridge and check it for mistakes
+    models         list the motherboard and CPU models machines can be built from
+    help           show this message
+
+The cartridge defaults to the one in the machine config, or test_boot_cartridge.bin.
+Options are applied on top of the machine config.
+
+options:
+    --config <file>             build the machine from a TOML or JSON config
+    --motherboard <model>       build the machine around this motherboard
+    --cpu <model>               fit the motherboard with this CPU
+    --format <elf|hex|srec>     read the cartridge as this format instead of going by its extension
+    --patch <file>              apply an IPS or BPS patch to the cartridge
+    --patch-checksum <crc>      CRC-32 of the cartridge image an IPS patch is for
+    --ram <size>                RAM size, e.g. 4096, 0x1000 or 64K
+    --log-level <level>         off, error, warn, info, debug or trace
+    --log <destination>         stdout, stderr or a file, can be repeated
+    --max-instructions <count>  stop after running this many instructions
+    --trace <mode>              off, instructions or symbols
+    --stats                     print the instructions and cycles the run took
+    --verify <action>           refuse, warn or boot cartridges failing verification
+    --trust <key.pub>           trust cartridges signed with this key, which makes
+                                a signature required, can be repeated
+    --require-signature         treat unsigned cartridges as failing verification
+    --rng-seed <seed>           make the hardware RNG deterministic
+    --semihosting <directory>   give the guest access to this directory
+    --nic <spec>                attach the NIC, e.g. unix:/tmp/a.sock:/tmp/b.sock
+    --audio-wav <file>          attach the sound device, recording to a WAV file
+    --gamepad <source>          attach the gamepad, reading keyboard or replay:<file>
+    --record-input <file>       record the gamepad's input for replaying later
+    --swap <cycle>:<cartridge>  swap cartridges once the machine reaches a cycle, or
+                                eject with <cycle>:eject, can be repeated
+    --start <address>           disasm: first address to disassemble
+    --count <count>             disasm: how many instructions to disassemble
+
+There is no serial port or disk image device yet, so neither can be attached.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Run,
+    Trace,
+    Disassemble,
+    InspectCartridge,
+    Models,
+    Help,
+}
+
+#[derive(Default)]
+struct Options {
+    cartridge: Option<String>,
+    config: Option<String>,
+    motherboard: Option<String>,
+    cpu: Option<String>,
+    format: Option<ExecutableFormat>,
+    patch: Option<String>,
+    patch_checksum: Option<u32>,
+    ram_size: Option<usize>,
+    log_level: Option<log::LevelFilter>,
+    log_destinations: Vec<String>,
+    max_instructions: Option<u64>,
+    trace_mode: Option<TraceMode>,
+    stats: bool,
+    verify: Option<VerificationAction>,
+    trusted_keys: Vec<String>,
+    require_signature: bool,
+    rng_seed: Option<u64>,
+    semihosting: Option<String>,
+    nic: Option<String>,
+    audio_wav: Option<String>,
+    gamepad: Option<String>,
+    record_input: Option<String>,
+    swaps: Vec<String>,
+    start: Option<u64>,
+    count: Option<usize>,
+}
+
+/// Reads a number in decimal or, with `0x`, hex. Underscores can be used to group digits.
+fn parse_number(text: &str) -> Result<u64, String> {
+    let digits = text.replace('_', "");
+    match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| format!("{:?} is not a number", text))
+}
+
+/// Reads a number of bytes, which can end in `K`, `M` or `G`.
+fn parse_size(text: &str) -> Result<usize, String> {
+    let (number, unit) = match text.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&text[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&text[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&text[..index], 1 << 30),
+        _ => (text, 1),
+    };
+    parse_number(number)?
+        .checked_mul(unit)
+        .map(|size| size as usize)
+        .ok_or(format!("{:?} is too big", text))
+}
+
+fn parse_arguments(
+    mut arguments: impl Iterator<Item = String>,
+) -> Result<(Command, Options), String> {
+    let mut command = None;
+    let mut options = Options::default();
+
+    while let Some(argument) = arguments.next() {
+        let mut value = || {
+            arguments
+                .next()
+                .ok_or(format!("{} needs a value", argument))
+        };
+        match argument.as_str() {
+            "--config" => options.config = Some(value()?),
+            "--motherboard" => options.motherboard = Some(value()?),
+            "--cpu" => options.cpu = Some(value()?),
+            "--format" => options.format = Some(value()?.parse()?),
+            "--patch" => options.patch = Some(value()?),
+            "--patch-checksum" => {
+                options.patch_checksum = Some(
+                    u32::try_from(parse_number(&value()?)?)
+                        .map_err(|_| "--patch-checksum is a 32 bit CRC".to_string())?,
+                )
+            }
+            "--ram" => options.ram_size = Some(parse_size(&value()?)?),
+            "--log-level" => {
+                let level = value()?;
+                options.log_level = Some(
+                    level
+                        .parse()
+                        .map_err(|_| format!("unknown log level {:?}", level))?,
+                );
+            }
+            "--log" => options.log_destinations.push(value()?),
+            "--max-instructions" => options.max_instructions = Some(parse_number(&value()?)?),
+            "--trace" => options.trace_mode = Some(value()?.parse()?),
+            "--stats" => options.stats = true,
+            "--verify" => options.verify = Some(value()?.parse()?),
+            "--trust" => options.trusted_keys.push(value()?),
+            "--require-signature" => options.require_signature = true,
+            "--rng-seed" => options.rng_seed = Some(parse_number(&value()?)?),
+            "--semihosting" => options.semihosting = Some(value()?),
+            "--nic" => options.nic = Some(value()?),
+            "--audio-wav" => options.audio_wav = Some(value()?),
+            "--gamepad" => options.gamepad = Some(value()?),
+            "--record-input" => options.record_input = Some(value()?),
+            "--swap" => options.swaps.push(value()?),
+            "--start" => options.start = Some(parse_number(&value()?)?),
+            "--count" => options.count = Some(parse_number(&value()?)? as usize),
+            "-h" | "--help" => command = Some(Command::Help),
+            _ if argument.starts_with("--") => return Err(format!("unknown option {}", argument)),
+            _ if command.is_none() && options.cartridge.is_none() => {
+                command = Some(match argument.as_str() {
+                    "run" => Command::Run,
+                    "trace" => Command::Trace,
+                    "disasm" => Command::Disassemble,
+                    "inspect-cart" => Command::InspectCartridge,
+                    "models" => Command::Models,
+                    "help" => Command::Help,
+                    // Older scripts pass the cartridge straight away
+                    _ => {
+                        options.cartridge = Some(argument);
+                        Command::Run
+                    }
+                });
+            }
+            _ if options.cartridge.is_none() => options.cartridge = Some(argument),
+            _ => return Err(format!("unexpected argument {}", argument)),
+        }
+    }
+
+    Ok((command.unwrap_or(Command::Run), options))
+}
 
-pub fn main() {
-    // First, we must initialize fern
-    fern::Dispatch::new()
+/// Running the machine logs at debug level to stdout and `output.log` unless told otherwise. The other commands print
+/// their results to stdout, so they only log warnings, to stderr.
+fn init_logging(command: Command, options: &Options) -> Result<(), String> {
+    let running = matches!(command, Command::Run | Command::Trace);
+    let default_level = if running {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Warn
+    };
+    let default_destinations: &[&str] = if running {
+        &["stdout", "output.log"]
+    } else {
+        &["stderr"]
+    };
+
+    let mut dispatch = fern::Dispatch::new()
         // Perform allocation-free log formatting
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -22,24 +216,298 @@ pub fn main() {
                 message
             ))
         })
-        // Add blanket level filter -
-        .level(log::LevelFilter::Debug)
-        // - and per-module overrides
-        .level_for("hyper", log::LevelFilter::Info)
-        // Output to stdout, files, and other Dispatch configurations
-        .chain(std::io::stdout())
-        .chain(fern::log_file("output.log").expect("failed to open log file"))
-        // Apply globally
+        .level(options.log_level.unwrap_or(default_level))
+        .level_for("hyper", log::LevelFilter::Info);
+
+    let destinations = if options.log_destinations.is_empty() {
+        default_destinations
+            .iter()
+            .map(|&destination| destination.to_string())
+            .collect()
+    } else {
+        options.log_destinations.clone()
+    };
+    for destination in destinations {
+        dispatch = match destination.as_str() {
+            "stdout" => dispatch.chain(std::io::stdout()),
+            "stderr" => dispatch.chain(std::io::stderr()),
+            path => dispatch
+                .chain(fern::log_file(path).map_err(|error| format!("{}: {}", path, error))?),
+        };
+    }
+    dispatch
         .apply()
-        .expect("failed to initialize logging");
+        .map_err(|error| format!("failed to initialize logging: {}", error))
+}
+
+/// Sets up a device given on the command line in place of the config's device of the same kind, which keeps its port
+/// and interrupt, or adds it if the config has none.
+fn replace_device(config: &mut MachineConfig, kind: DeviceKind) {
+    let existing = config
+        .devices
+        .iter_mut()
+        .find(|device| std::mem::discriminant(&device.kind) == std::mem::discriminant(&kind));
+    match existing {
+        Some(device) => device.kind = kind,
+        None => config.devices.push(DeviceConfig::new(kind)),
+    }
+}
 
-    let mut system = Monarch64System::new(Box::new(
-        MonadMotherboard::new(Box::new(cpus::monad::MonadCPU::new())).with_boot_cartridge(
-            MonadBootCartridge::new(include_bytes!("../test_boot_cartridge.bin")),
+/// Reads the machine config, if there is one, and applies the command line on top of it.
+fn machine_config(options: &Options) -> Result<MachineConfig, String> {
+    let mut config = match &options.config {
+        Some(path) => MachineConfig::load(path).map_err(|error| error.to_string())?,
+        // Without a config, a motherboard picked by name brings its own
+        None => match &options.motherboard {
+            Some(name) => {
+                let model = ModelRegistry::default()
+                    .motherboard(name)
+                    .map(|model| model.default_config)
+                    .ok_or(format!(
+                        "unknown motherboard {:?}, see the models command",
+                        name
+                    ))?;
+                model()
+            }
+            None => MachineConfig::default(),
+        },
+    };
+    if let Some(name) = &options.motherboard {
+        config.motherboard = name.clone();
+    }
+    if let Some(name) = &options.cpu {
+        config.cpu = Some(name.clone());
+    }
+
+    if options.cartridge.is_some() || config.cartridge.is_none() {
+        let path = options
+            .cartridge
+            .as_deref()
+            .unwrap_or("test_boot_cartridge.bin");
+        config
+            .cartridge
+            .get_or_insert_with(|| CartridgeConfig::new(path))
+            .path = PathBuf::from(path);
+    }
+    let cartridge = config.cartridge.as_mut().unwrap();
+    if let Some(format) = options.format {
+        cartridge.format = Some(format);
+    }
+    if let Some(patch) = &options.patch {
+        cartridge.patch = Some(PathBuf::from(patch));
+    }
+    if let Some(checksum) = options.patch_checksum {
+        cartridge.patch_checksum = Some(checksum);
+    }
+    if let Some(action) = options.verify {
+        cartridge.verify = action;
+    }
+    cartridge
+        .trusted_keys
+        .extend(options.trusted_keys.iter().map(PathBuf::from));
+    cartridge.require_signature |= options.require_signature;
+    for swap in &options.swaps {
+        let (cycle, target) = swap
+            .split_once(':')
+            .ok_or(format!("invalid cartridge swap {:?}", swap))?;
+        cartridge.swaps.push(SwapConfig {
+            at: parse_number(cycle)?,
+            path: (target != "eject").then(|| PathBuf::from(target)),
+        });
+    }
+
+    if let Some(size) = options.ram_size {
+        config.ram_size = size;
+    }
+
+    if let Some(seed) = options.rng_seed {
+        let rng_seed = config
+            .devices
+            .iter_mut()
+            .find_map(|device| match &mut device.kind {
+                DeviceKind::Rng { seed } => Some(seed),
+                _ => None,
+            });
+        match rng_seed {
+            Some(rng_seed) => *rng_seed = Some(seed),
+            None => config
+                .devices
+                .push(DeviceConfig::new(DeviceKind::Rng { seed: Some(seed) })),
+        }
+    }
+
+    // Guest programs only get host file access if there is a sandbox directory to give them
+    let has_semihosting = config
+        .devices
+        .iter()
+        .any(|device| matches!(device.kind, DeviceKind::Semihosting { .. }));
+    match &options.semihosting {
+        Some(root) => replace_device(&mut config, DeviceKind::Semihosting { root: root.into() }),
+        None if !has_semihosting && PathBuf::from("semihosting").is_dir() => replace_device(
+            &mut config,
+            DeviceKind::Semihosting {
+                root: "semihosting".into(),
+            },
         ),
-    ));
+        None if !has_semihosting => log::debug!("Semihosting disabled: no semihosting directory"),
+        None => {}
+    }
+
+    if let Some(spec) = &options.nic {
+        replace_device(
+            &mut config,
+            DeviceKind::Nic {
+                backend: spec.clone(),
+            },
+        );
+    }
+    if let Some(path) = &options.audio_wav {
+        replace_device(&mut config, DeviceKind::Sound { wav: path.into() });
+    }
+    if let Some(spec) = &options.gamepad {
+        let replay = if spec == "keyboard" {
+            None
+        } else if let Some(path) = spec.strip_prefix("replay:") {
+            Some(PathBuf::from(path))
+        } else {
+            return Err(format!("unknown gamepad source {:?}", spec));
+        };
+        replace_device(
+            &mut config,
+            DeviceKind::Gamepad {
+                replay,
+                record: options.record_input.as_ref().map(PathBuf::from),
+            },
+        );
+    }
+
+    Ok(config)
+}
+
+fn load_cartridge(options: &Options) -> Result<MonadBootCartridge, String> {
+    let config = machine_config(options)?;
+    config
+        .cartridge
+        .unwrap()
+        .load()
+        .map_err(|error| error.to_string())
+}
+
+fn run(command: Command, options: &Options) -> Result<(), String> {
+    let mut system = machine_config(options)?
+        .build()
+        .map_err(|error| error.to_string())?;
+
+    let default_trace_mode = if command == Command::Trace {
+        TraceMode::Instructions
+    } else {
+        TraceMode::Off
+    };
+    system
+        .motherboard
+        .get_cpu_mut()
+        .set_trace_mode(options.trace_mode.unwrap_or(default_trace_mode));
+    system
+        .motherboard
+        .set_instruction_limit(options.max_instructions);
+
+    system.init();
+    system.run();
+    if options.stats {
+        print!("{}", system.motherboard.get_cpu().cycle_counters());
+    }
+    Ok(())
+}
+
+fn disassemble(options: &Options) -> Result<(), String> {
+    let cartridge = load_cartridge(options)?;
+    let segments = cartridge.get_segments();
+    // Revision 0 and 1 cartridges are one segment with every permission, so all of it gets disassembled
+    let executable = segments
+        .iter()
+        .filter(|segment| segment.flags & SEGMENT_EXECUTE != 0);
+
+    let mut remaining = options.count.unwrap_or(usize::MAX);
+    for segment in executable {
+        let end = segment.load_address + segment.data.len() as u64;
+        let start = options
+            .start
+            .unwrap_or(segment.load_address)
+            .max(segment.load_address);
+        if start >= end || remaining == 0 {
+            continue;
+        }
+        // Instructions are 8 bytes, counted from the start of the segment
+        let offset = ((start - segment.load_address) & !7) as usize;
+        let length = (segment.data.len() - offset).min(remaining.saturating_mul(8));
+        remaining -= length.div_ceil(8);
+
+        print!(
+            "{}",
+            monad_disassembler::listing(
+                &segment.data[offset..offset + length],
+                segment.load_address + offset as u64,
+                cartridge.get_symbols().map(|symbols| &**symbols),
+            )
+        );
+    }
+    Ok(())
+}
+
+fn list_models() -> Result<(), String> {
+    let registry = ModelRegistry::default();
+    println!("motherboards:");
+    for model in registry.motherboards() {
+        println!(
+            "    {:<12} {} (CPU: {})",
+            model.name, model.description, model.default_cpu
+        );
+    }
+    println!("cpus:");
+    for model in registry.cpus() {
+        println!("    {:<12} {}", model.name, model.description);
+    }
+    Ok(())
+}
+
+fn inspect_cartridge(options: &Options) -> Result<(), String> {
+    let cartridge = load_cartridge(options)?;
+    print!("{}", cartridge_builder::inspect(&cartridge));
+    for problem in cartridge_builder::validate(&cartridge) {
+        println!("Problem:       {}", problem);
+    }
+    Ok(())
+}
 
-    system.motherboard.init(&system.memory_bus);
+pub fn main() -> ExitCode {
+    let (command, options) = match parse_arguments(std::env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    if command == Command::Help {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    if let Err(error) = init_logging(command, &options) {
+        eprintln!("{}", error);
+        return ExitCode::FAILURE;
+    }
 
-    system.motherboard.run_cpu(&system.memory_bus);
+    let result = match command {
+        Command::Run | Command::Trace => run(command, &options),
+        Command::Disassemble => disassemble(&options),
+        Command::InspectCartridge => inspect_cartridge(&options),
+        Command::Models => list_models(),
+        Command::Help => Ok(()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            log::error!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -1,4 +1,76 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::misc::{
+    memory_bus::MemoryBus48,
+    scheduler::{Clock, DeviceId, Scheduler},
+};
+
+/// Requests a device can make of the machine it is plugged into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineSignal {
+    /// Reset the CPU the same way the `rst` instruction does.
+    Reset,
+    /// Clear memory, reload the boot cartridge and reset the CPU.
+    PowerCycle,
+    /// Raise the external interrupt with this vector.
+    Interrupt(u8),
+    /// Carry out the cartridge swap queued in the cartridge slot.
+    CartridgeSwap,
+}
+
+/// A device that responds to port reads and writes on the `IoBus`.
+///
+/// Every access has a no-op default so devices only implement the widths they care about.
+pub trait IoDevice {
+    fn read_u8(&mut self, _port: u16) -> u8 {
+        0
+    }
+
+    fn read_u16(&mut self, _port: u16) -> u16 {
+        0
+    }
+
+    fn read_u32(&mut self, _port: u16) -> u32 {
+        0
+    }
+
+    fn read_u64(&mut self, _port: u16) -> u64 {
+        0
+    }
+
+    fn write_u8(&mut self, _port: u16, _value: u8) {}
+
+    fn write_u16(&mut self, _port: u16, _value: u16) {}
+
+    fn write_u32(&mut self, _port: u16, _value: u32) {}
+
+    fn write_u64(&mut self, _port: u16, _value: u64) {}
+
+    /// Called by the motherboard after every instruction, for devices that have to finish off a port access once the
+    /// instruction retires or watch for something the host can change at any time. Only devices whose `wants_tick`
+    /// says so are ticked. Devices that keep time should schedule events on their clock instead.
+    fn tick(&mut self, _memory_bus: &Mutex<MemoryBus48>, _signals: &mut Vec<MachineSignal>) {}
+
+    /// Whether the device needs `tick` after every instruction. The bus asks once, when the device is attached.
+    fn wants_tick(&self) -> bool {
+        false
+    }
+
+    /// Hands the device its clock when it is attached to a bus.
+    fn set_clock(&mut self, _clock: Clock) {}
+
+    /// Called when an event the device scheduled on its clock comes due.
+    fn handle_event(
+        &mut self,
+        _event: u64,
+        _memory_bus: &Mutex<MemoryBus48>,
+        _signals: &mut Vec<MachineSignal>,
+    ) {
+    }
+}
 
 pub struct IoHandler {
     pub read_u8: fn(port: u16) -> u8,
@@ -11,19 +83,257 @@ pub struct IoHandler {
     pub write_u64: fn(port: u16, value: u64),
 }
 
+impl IoDevice for IoHandler {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        (self.read_u8)(port)
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        (self.read_u16)(port)
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        (self.read_u32)(port)
+    }
+
+    fn read_u64(&mut self, port: u16) -> u64 {
+        (self.read_u64)(port)
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        (self.write_u8)(port, value);
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        (self.write_u16)(port, value);
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        (self.write_u32)(port, value);
+    }
+
+    fn write_u64(&mut self, port: u16, value: u64) {
+        (self.write_u64)(port, value);
+    }
+}
+
+/// Moves a device to other ports. Every access is handed on with the port moved back by `offset`, so the device
+/// keeps dispatching on its usual port numbers.
+pub struct RemappedDevice {
+    device: Arc<Mutex<dyn IoDevice + Send>>,
+    offset: u16,
+}
+
+impl RemappedDevice {
+    /// Wraps `device` so a port `offset` above one of its own reaches it as that port.
+    pub fn new(device: Arc<Mutex<dyn IoDevice + Send>>, offset: u16) -> Self {
+        Self { device, offset }
+    }
+
+    fn port(&self, port: u16) -> u16 {
+        port.wrapping_sub(self.offset)
+    }
+}
+
+impl IoDevice for RemappedDevice {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        self.device.lock().unwrap().read_u8(self.port(port))
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        self.device.lock().unwrap().read_u16(self.port(port))
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        self.device.lock().unwrap().read_u32(self.port(port))
+    }
+
+    fn read_u64(&mut self, port: u16) -> u64 {
+        self.device.lock().unwrap().read_u64(self.port(port))
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.device.lock().unwrap().write_u8(self.port(port), value);
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        self.device
+            .lock()
+            .unwrap()
+            .write_u16(self.port(port), value);
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        self.device
+            .lock()
+            .unwrap()
+            .write_u32(self.port(port), value);
+    }
+
+    fn write_u64(&mut self, port: u16, value: u64) {
+        self.device
+            .lock()
+            .unwrap()
+            .write_u64(self.port(port), value);
+    }
+
+    fn tick(&mut self, memory_bus: &Mutex<MemoryBus48>, signals: &mut Vec<MachineSignal>) {
+        self.device.lock().unwrap().tick(memory_bus, signals);
+    }
+
+    fn wants_tick(&self) -> bool {
+        self.device.lock().unwrap().wants_tick()
+    }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.device.lock().unwrap().set_clock(clock);
+    }
+
+    fn handle_event(
+        &mut self,
+        event: u64,
+        memory_bus: &Mutex<MemoryBus48>,
+        signals: &mut Vec<MachineSignal>,
+    ) {
+        self.device
+            .lock()
+            .unwrap()
+            .handle_event(event, memory_bus, signals);
+    }
+}
+
 pub struct IoBus {
-    pub io_handlers: HashMap<u16, IoHandler>,
+    pub io_handlers: HashMap<u16, Arc<Mutex<dyn IoDevice>>>,
+    devices: Vec<(DeviceId, Arc<Mutex<dyn IoDevice>>)>,
+    /// The attached devices that want ticking after every instruction.
+    ticking: Vec<Arc<Mutex<dyn IoDevice>>>,
+    next_device_id: DeviceId,
+    scheduler: Arc<Mutex<Scheduler>>,
+}
+
+impl Default for IoBus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IoBus {
     pub fn new() -> Self {
-        IoBus {io_handlers: HashMap::new()}
+        IoBus {
+            io_handlers: HashMap::new(),
+            devices: Vec::new(),
+            ticking: Vec::new(),
+            next_device_id: 0,
+            scheduler: Arc::new(Mutex::new(Scheduler::new())),
+        }
+    }
+
+    /// Registers a stateless handler on a single port.
+    pub fn register_handler(&mut self, port: u16, handler: IoHandler) {
+        self.attach_device(&[port], Arc::new(Mutex::new(handler)));
+    }
+
+    /// Attaches a device to every port in `ports`. A device spanning several ports shares its state between them.
+    /// A device new to the bus gets its clock.
+    pub fn attach_device(&mut self, ports: &[u16], device: Arc<Mutex<dyn IoDevice>>) {
+        for &port in ports {
+            if self.io_handlers.insert(port, device.clone()).is_some() {
+                log::warn!(
+                    "IO Bus: Port {:#06X} was already in use. Replacing its device.",
+                    port
+                );
+            }
+        }
+        self.prune_devices();
+        if !self
+            .devices
+            .iter()
+            .any(|(_, attached)| Arc::ptr_eq(attached, &device))
+        {
+            let id = self.next_device_id;
+            self.next_device_id += 1;
+            let mut attached = device.lock().unwrap();
+            attached.set_clock(Clock::new(self.scheduler.clone(), id));
+            if attached.wants_tick() {
+                self.ticking.push(device.clone());
+            }
+            drop(attached);
+            self.devices.push((id, device));
+        }
+    }
+
+    pub fn detach_port(&mut self, port: u16) {
+        self.io_handlers.remove(&port);
+        self.prune_devices();
+    }
+
+    /// The timeline the attached devices schedule their events on.
+    pub fn scheduler(&self) -> Arc<Mutex<Scheduler>> {
+        self.scheduler.clone()
+    }
+
+    /// Moves time on by `cycles`, ticks the devices that want it and runs the events that came due, returning what the devices
+    /// asked of the machine.
+    pub fn advance(&self, cycles: u64, memory_bus: &Mutex<MemoryBus48>) -> Vec<MachineSignal> {
+        self.scheduler.lock().unwrap().advance(cycles);
+        let mut signals = self.tick_devices(memory_bus);
+        self.run_events(memory_bus, &mut signals);
+        signals
+    }
+
+    /// Ticks every attached device that wants it once, no matter how many ports it spans, and returns what they asked
+    /// of the machine.
+    pub fn tick_devices(&self, memory_bus: &Mutex<MemoryBus48>) -> Vec<MachineSignal> {
+        let mut signals = Vec::new();
+        for device in &self.ticking {
+            device.lock().unwrap().tick(memory_bus, &mut signals);
+        }
+        signals
+    }
+
+    /// Hands every event that has come due to its device. Events a device schedules for now while handling one run
+    /// in the same pass.
+    fn run_events(&self, memory_bus: &Mutex<MemoryBus48>, signals: &mut Vec<MachineSignal>) {
+        loop {
+            // The scheduler has to be unlocked while the device runs, so it can schedule its next event
+            let due = self.scheduler.lock().unwrap().pop_due();
+            let Some((id, event)) = due else {
+                break;
+            };
+            if let Some((_, device)) = self.devices.iter().find(|(attached, _)| *attached == id) {
+                device
+                    .lock()
+                    .unwrap()
+                    .handle_event(event, memory_bus, signals);
+            }
+        }
+    }
+
+    /// Forgets devices that no longer own any port, along with their events.
+    fn prune_devices(&mut self) {
+        let io_handlers = &self.io_handlers;
+        let mut scheduler = self.scheduler.lock().unwrap();
+        self.devices.retain(|(id, device)| {
+            let attached = io_handlers
+                .values()
+                .any(|handler| Arc::ptr_eq(handler, device));
+            if !attached {
+                scheduler.cancel_all(*id);
+            }
+            attached
+        });
+        let devices = &self.devices;
+        self.ticking.retain(|device| {
+            devices
+                .iter()
+                .any(|(_, attached)| Arc::ptr_eq(attached, device))
+        });
     }
 
     pub fn read_u8(&self, port: u16) -> u8 {
         let handler = &self.io_handlers.get(&port);
         if let Some(handler) = handler {
-            (handler.read_u8)(port)
+            handler.lock().unwrap().read_u8(port)
         } else {
             0
         }
@@ -32,7 +342,7 @@ impl IoBus {
     pub fn read_u16(&self, port: u16) -> u16 {
         let handler = &self.io_handlers.get(&port);
         if let Some(handler) = handler {
-            (handler.read_u16)(port)
+            handler.lock().unwrap().read_u16(port)
         } else {
             0
         }
@@ -41,7 +351,7 @@ impl IoBus {
     pub fn read_u32(&self, port: u16) -> u32 {
         let handler = &self.io_handlers.get(&port);
         if let Some(handler) = handler {
-            (handler.read_u32)(port)
+            handler.lock().unwrap().read_u32(port)
         } else {
             0
         }
@@ -50,7 +360,7 @@ impl IoBus {
     pub fn read_u64(&self, port: u16) -> u64 {
         let handler = &self.io_handlers.get(&port);
         if let Some(handler) = handler {
-            (handler.read_u64)(port)
+            handler.lock().unwrap().read_u64(port)
         } else {
             0
         }
@@ -59,28 +369,81 @@ impl IoBus {
     pub fn write_u8(&mut self, port: u16, value: u8) {
         let handler = &self.io_handlers.get(&port);
         if let Some(handler) = handler {
-            (handler.write_u8)(port, value);
+            handler.lock().unwrap().write_u8(port, value);
         }
     }
 
     pub fn write_u16(&mut self, port: u16, value: u16) {
         let handler = &self.io_handlers.get(&port);
         if let Some(handler) = handler {
-            (handler.write_u16)(port, value);
+            handler.lock().unwrap().write_u16(port, value);
         }
     }
 
     pub fn write_u32(&mut self, port: u16, value: u32) {
         let handler = &self.io_handlers.get(&port);
         if let Some(handler) = handler {
-            (handler.write_u32)(port, value);
+            handler.lock().unwrap().write_u32(port, value);
         }
     }
 
     pub fn write_u64(&mut self, port: u16, value: u64) {
         let handler = &self.io_handlers.get(&port);
         if let Some(handler) = handler {
-            (handler.write_u64)(port, value);
+            handler.lock().unwrap().write_u64(port, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        ticks: u64,
+        wants_tick: bool,
+    }
+
+    impl IoDevice for Counter {
+        fn read_u64(&mut self, _port: u16) -> u64 {
+            self.ticks
+        }
+
+        fn tick(&mut self, _memory_bus: &Mutex<MemoryBus48>, _signals: &mut Vec<MachineSignal>) {
+            self.ticks += 1;
+        }
+
+        fn wants_tick(&self) -> bool {
+            self.wants_tick
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn only_devices_that_want_it_are_ticked() {
+        let memory_bus = Mutex::new(MemoryBus48::new());
+        let mut io_bus = IoBus::new();
+        let ticking = Counter {
+            wants_tick: true,
+            ..Counter::default()
+        };
+        io_bus.attach_device(&[0x10, 0x11], Arc::new(Mutex::new(ticking)));
+        io_bus.attach_device(&[0x20], Arc::new(Mutex::new(Counter::default())));
+        let remapped = Counter {
+            wants_tick: true,
+            ..Counter::default()
+        };
+        let remapped = RemappedDevice::new(Arc::new(Mutex::new(remapped)), 0x10);
+        io_bus.attach_device(&[0x30], Arc::new(Mutex::new(remapped)));
+
+        for _ in 0..3 {
+            io_bus.advance(1, &memory_bus);
+        }
+        assert_eq!(io_bus.read_u64(0x10), 3);
+        assert_eq!(io_bus.read_u64(0x20), 0);
+        assert_eq!(io_bus.read_u64(0x30), 3);
+
+        io_bus.detach_port(0x30);
+        assert_eq!(io_bus.ticking.len(), 1);
+    }
+}
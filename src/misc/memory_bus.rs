@@ -1,10 +1,171 @@
+pub const MEMORY_READ: u8 = 0b1;
+pub const MEMORY_WRITE: u8 = 0b10;
+pub const MEMORY_EXECUTE: u8 = 0b100;
+pub const MEMORY_ALL: u8 = MEMORY_READ | MEMORY_WRITE | MEMORY_EXECUTE;
+
+/// Reads permissions written as any of `r`, `w` and `x`, e.g. `"rx"`.
+pub fn parse_permissions(text: &str) -> Option<u8> {
+    text.chars().try_fold(0, |bits, flag| match flag {
+        'r' => Some(bits | MEMORY_READ),
+        'w' => Some(bits | MEMORY_WRITE),
+        'x' => Some(bits | MEMORY_EXECUTE),
+        _ => None,
+    })
+}
+
+/// Memory a machine has besides main RAM and its cartridge, such as a firmware ROM. Clearing the bus drops it, so a
+/// motherboard maps it again every time it powers on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryRegion {
+    Rom {
+        base: u64,
+        data: Vec<u8>,
+        permissions: u8,
+    },
+    Ram {
+        base: u64,
+        data: Vec<u8>,
+    },
+}
+
+impl MemoryRegion {
+    pub fn map(&self, memory_bus: &mut MemoryBus48) {
+        match self {
+            MemoryRegion::Rom {
+                base,
+                data,
+                permissions,
+            } => memory_bus.map_rom(*base, data.clone(), *permissions),
+            MemoryRegion::Ram { base, data } => memory_bus.map_ram(*base, data.clone()),
+        }
+    }
+}
+
+/// A range of memory with restricted permissions. Memory outside of any region can be read, written and executed.
+struct ProtectedRegion {
+    start: u64,
+    end: u64,
+    permissions: u8,
+}
+
+/// What reads of memory that nothing drives return, like a bus with pull-ups.
+const OPEN_BUS: u8 = 0xFF;
+
+/// How many bytes either side of the boundary between the fixed area and the bank window are kept in `seam`, enough
+/// for any read of up to 8 bytes to cross it.
+const ROM_SEAM_SIZE: usize = 7;
+
+/// Read-only memory mapped outside of RAM, such as a cartridge.
+///
+/// Banked ROM shows `fixed_size` bytes from the start of `data`, followed by a `bank_size` window onto whichever bank
+/// is selected. Plain ROM is all fixed. `data` is padded with open bus bytes to fill the fixed area and whole banks,
+/// so every read inside the window finds something.
+struct RomRegion {
+    base: u64,
+    data: Vec<u8>,
+    permissions: u8,
+    fixed_size: u64,
+    bank_size: u64,
+    bank_offset: u64,
+    /// The end of the fixed area followed by the start of the selected bank, which aren't next to each other in
+    /// `data`, so that reads crossing from one into the other still have a slice to come from.
+    seam: Vec<u8>,
+}
+
+impl RomRegion {
+    /// The address just past the window, or `None` if the window would run past the end of the address space.
+    fn end(&self) -> Option<u64> {
+        self.base
+            .checked_add(self.fixed_size)?
+            .checked_add(self.bank_size)
+    }
+
+    fn contains(&self, address: u64, length: usize) -> bool {
+        address >= self.base
+            && address
+                .checked_add(length as u64)
+                .zip(self.end())
+                .is_some_and(|(end, window_end)| end <= window_end)
+    }
+
+    /// Returns the bytes visible at `address`, or `None` if they are outside the window, or more than 8 of them
+    /// straddle the fixed area and the bank.
+    fn slice(&self, address: u64, length: usize) -> Option<&[u8]> {
+        if !self.contains(address, length) {
+            return None;
+        }
+        let offset = address - self.base;
+        let start = if offset < self.fixed_size {
+            if offset + length as u64 > self.fixed_size {
+                let seam_start = self.fixed_size - (self.seam.len() / 2) as u64;
+                let start = offset.checked_sub(seam_start)? as usize;
+                return self.seam.get(start..start + length);
+            }
+            offset
+        } else {
+            self.bank_offset + offset - self.fixed_size
+        };
+        self.data.get(start as usize..start as usize + length)
+    }
+
+    fn update_seam(&mut self) {
+        if self.bank_size == 0 {
+            return;
+        }
+        let fixed_size = self.fixed_size as usize;
+        let bank_offset = self.bank_offset as usize;
+        let length = ROM_SEAM_SIZE.min(fixed_size).min(self.bank_size as usize);
+        self.seam.clear();
+        self.seam
+            .extend_from_slice(&self.data[fixed_size - length..fixed_size]);
+        self.seam
+            .extend_from_slice(&self.data[bank_offset..bank_offset + length]);
+    }
+}
+
+/// Writable memory mapped outside of main RAM, such as a cartridge's save RAM.
+struct RamRegion {
+    base: u64,
+    data: Vec<u8>,
+}
+
+impl RamRegion {
+    fn range(&self, address: u64, length: usize) -> Option<std::ops::Range<usize>> {
+        let start = address.checked_sub(self.base)? as usize;
+        let end = start.checked_add(length)?;
+        (end <= self.data.len()).then_some(start..end)
+    }
+}
+
+/// How much RAM a bus made with `new` has.
+pub const DEFAULT_RAM_SIZE: usize = 1024 * 4;
+
 pub struct MemoryBus48 {
-    ram: [u8; 1024 * 4], // 4KB of RAM for simplicity
+    ram: Vec<u8>,
+    protected_regions: Vec<ProtectedRegion>,
+    rom_regions: Vec<RomRegion>,
+    ram_regions: Vec<RamRegion>,
+}
+
+impl Default for MemoryBus48 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemoryBus48 {
     pub fn new() -> Self {
-        Self { ram: [0; 1024 * 4] }
+        Self::with_ram_size(DEFAULT_RAM_SIZE)
+    }
+
+    /// A bus with `size` bytes of RAM starting at address 0.
+    pub fn with_ram_size(size: usize) -> Self {
+        Self {
+            ram: vec![0; size],
+            protected_regions: Vec::new(),
+            rom_regions: Vec::new(),
+            ram_regions: Vec::new(),
+        }
     }
 
     pub fn dump_memory(&self, start: u64, length: usize) -> String {
@@ -18,8 +179,19 @@ impl MemoryBus48 {
     }
 
     pub fn read_bytes(&self, address: u64, length: usize) -> &[u8] {
-        if address + length as u64 <= self.ram.len() as u64 {
+        if self.in_ram(address, length) {
             &self.ram[address as usize..(address as usize + length)]
+        } else if let Some((region, range)) = self.ram_regions.iter().find_map(|region| {
+            let range = region.range(address, length)?;
+            Some((region, range))
+        }) {
+            &region.data[range]
+        } else if let Some(bytes) = self
+            .rom_regions
+            .iter()
+            .find_map(|rom| rom.slice(address, length))
+        {
+            bytes
         } else {
             log::error!(
                 "Monad Motherboard: Attempted to read beyond RAM bounds: {:#X} + {} bytes",
@@ -32,9 +204,20 @@ impl MemoryBus48 {
 
     pub fn write_bytes(&mut self, base_address: u64, value: &[u8]) {
         let length = value.len();
-        if base_address + length as u64 <= self.ram.len() as u64 {
+        if !self.has_permissions(base_address, length, MEMORY_WRITE) {
+            log::error!(
+                "Monad Motherboard: Attempted to write to read-only memory: {:#X} + {} bytes",
+                base_address,
+                length
+            );
+        } else if self.in_ram(base_address, length) {
             self.ram[base_address as usize..(base_address as usize + length)]
                 .copy_from_slice(value);
+        } else if let Some((region, range)) = self.ram_regions.iter_mut().find_map(|region| {
+            let range = region.range(base_address, length)?;
+            Some((region, range))
+        }) {
+            region.data[range].copy_from_slice(value);
         } else {
             log::error!(
                 "Monad Motherboard: Attempted to write beyond RAM bounds: {:#X} + {} bytes",
@@ -44,7 +227,233 @@ impl MemoryBus48 {
         }
     }
 
+    /// Clears RAM and drops every protection and ROM mapping, as if the machine had just been powered on.
+    pub fn clear(&mut self) {
+        self.ram.fill(0);
+        self.protected_regions.clear();
+        self.rom_regions.clear();
+        self.ram_regions.clear();
+    }
+
+    /// Maps `data` as extra RAM at `base`, outside of main RAM. Its contents can be read back with `read_bytes`.
+    pub fn map_ram(&mut self, base: u64, data: Vec<u8>) {
+        if base < self.ram.len() as u64 || self.ram_regions.iter().any(|region| region.base == base)
+        {
+            log::warn!(
+                "Monad Motherboard: RAM mapped at {:#X} overlaps existing memory.",
+                base
+            );
+        }
+        self.ram_regions.push(RamRegion { base, data });
+    }
+
+    pub fn unmap_ram(&mut self, base: u64) {
+        self.ram_regions.retain(|region| region.base != base);
+    }
+
+    /// Maps `data` as ROM at `base`. It never takes up RAM and can't be written, whatever `permissions` says.
+    pub fn map_rom(&mut self, base: u64, data: Vec<u8>, permissions: u8) {
+        let fixed_size = data.len() as u64;
+        self.add_rom_region(base, data, permissions, fixed_size, 0);
+    }
+
+    /// Maps `data` as banked ROM at `base`. The first `fixed_size` bytes are always visible, followed by a `bank_size`
+    /// window that starts out on bank 0 and can be moved with `select_rom_bank`.
+    pub fn map_banked_rom(
+        &mut self,
+        base: u64,
+        data: Vec<u8>,
+        permissions: u8,
+        fixed_size: u64,
+        bank_size: u64,
+    ) {
+        self.add_rom_region(base, data, permissions, fixed_size, bank_size);
+    }
+
+    /// Points the bank window of the ROM at `base` at `bank`. Returns false if there is no banked ROM there or the
+    /// bank doesn't fit in its data, in which case the window stays where it was.
+    pub fn select_rom_bank(&mut self, base: u64, bank: u64) -> bool {
+        let Some(rom) = self
+            .rom_regions
+            .iter_mut()
+            .find(|rom| rom.base == base && rom.bank_size != 0)
+        else {
+            return false;
+        };
+        // Banks start on multiples of the bank size and have to fit in the data whole, even when the fixed area
+        // doesn't end on a bank boundary
+        match bank.checked_mul(rom.bank_size) {
+            Some(offset)
+                if offset
+                    .checked_add(rom.bank_size)
+                    .is_some_and(|end| end <= rom.data.len() as u64) =>
+            {
+                rom.bank_offset = offset;
+                rom.update_seam();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn add_rom_region(
+        &mut self,
+        base: u64,
+        mut data: Vec<u8>,
+        permissions: u8,
+        fixed_size: u64,
+        bank_size: u64,
+    ) {
+        if base
+            .checked_add(fixed_size)
+            .and_then(|end| end.checked_add(bank_size))
+            .is_none()
+        {
+            log::error!(
+                "Monad Motherboard: ROM mapped at {:#X} runs past the end of the address space. Not mapping it.",
+                base
+            );
+            return;
+        }
+        if base < self.ram.len() as u64 || self.rom_regions.iter().any(|rom| rom.base == base) {
+            log::warn!(
+                "Monad Motherboard: ROM mapped at {:#X} overlaps existing memory.",
+                base
+            );
+        }
+        // A partial last bank, or an image smaller than the window, reads as open bus past its end
+        let mut size = fixed_size;
+        if bank_size != 0 {
+            size = size.max((data.len() as u64).div_ceil(bank_size).max(1) * bank_size);
+        }
+        data.resize(size as usize, OPEN_BUS);
+
+        let mut rom = RomRegion {
+            base,
+            data,
+            permissions: permissions & !MEMORY_WRITE,
+            fixed_size,
+            bank_size,
+            bank_offset: 0,
+            seam: Vec::new(),
+        };
+        rom.update_seam();
+        self.rom_regions.push(rom);
+    }
+
+    pub fn unmap_rom(&mut self, base: u64) {
+        self.rom_regions.retain(|rom| rom.base != base);
+    }
+
+    /// Restricts what can be done with `length` bytes starting at `start`. Later calls take precedence where they overlap.
+    pub fn protect(&mut self, start: u64, length: u64, permissions: u8) {
+        self.protected_regions.push(ProtectedRegion {
+            start,
+            end: start.saturating_add(length),
+            permissions,
+        });
+    }
+
+    /// Checks that every byte in the range allows all of `permissions`.
+    pub fn has_permissions(&self, address: u64, length: usize, permissions: u8) -> bool {
+        let end = address.saturating_add(length as u64);
+        (address..end).all(|byte_address| {
+            let granted = if let Some(rom) = self
+                .rom_regions
+                .iter()
+                .find(|rom| rom.contains(byte_address, 1))
+            {
+                rom.permissions
+            } else {
+                self.protected_regions
+                    .iter()
+                    .rev()
+                    .find(|region| (region.start..region.end).contains(&byte_address))
+                    .map_or(MEMORY_ALL, |region| region.permissions)
+            };
+            granted & permissions == permissions
+        })
+    }
+
     pub fn get_size(&self) -> usize {
         self.ram.len()
     }
+
+    /// Whether `length` bytes at `address` are all in main RAM. Addresses come from the guest, so they can be
+    /// anything, including right at the top of the address space.
+    fn in_ram(&self, address: u64, length: usize) -> bool {
+        address
+            .checked_add(length as u64)
+            .is_some_and(|end| end <= self.ram.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banked_rom_reads_are_always_whole() {
+        let mut memory_bus = MemoryBus48::with_ram_size(0x100);
+        // A fixed area of 0x10 bytes and two and a half banks of 0x10
+        let data: Vec<u8> = (0..0x28).collect();
+        memory_bus.map_banked_rom(0x1000, data, MEMORY_READ, 0x10, 0x10);
+
+        assert_eq!(
+            memory_bus.read_bytes(0x100C, 8),
+            &[12, 13, 14, 15, 0, 1, 2, 3]
+        );
+        assert!(memory_bus.select_rom_bank(0x1000, 2));
+        assert_eq!(
+            memory_bus.read_bytes(0x100C, 8),
+            &[12, 13, 14, 15, 0x20, 0x21, 0x22, 0x23]
+        );
+        assert_eq!(
+            memory_bus.read_bytes(0x1016, 8),
+            &[
+                0x26, 0x27, OPEN_BUS, OPEN_BUS, OPEN_BUS, OPEN_BUS, OPEN_BUS, OPEN_BUS
+            ]
+        );
+        assert!(!memory_bus.select_rom_bank(0x1000, 3));
+    }
+
+    #[test]
+    fn small_images_fill_the_fixed_area_with_open_bus() {
+        let mut memory_bus = MemoryBus48::with_ram_size(0x100);
+        memory_bus.map_banked_rom(0x1000, vec![1, 2, 3, 4], MEMORY_READ, 0x10, 0x10);
+
+        assert_eq!(
+            memory_bus.read_bytes(0x1000, 8),
+            &[1, 2, 3, 4, OPEN_BUS, OPEN_BUS, OPEN_BUS, OPEN_BUS]
+        );
+        assert_eq!(memory_bus.read_bytes(0x1018, 8), &[OPEN_BUS; 8]);
+    }
+    #[test]
+    fn refuses_rom_past_the_end_of_the_address_space() {
+        let mut memory_bus = MemoryBus48::with_ram_size(0x100);
+        memory_bus.map_banked_rom(u64::MAX - 0x18, vec![1; 0x20], MEMORY_READ, 0x10, 0x10);
+
+        assert!(memory_bus.read_bytes(u64::MAX - 0x18, 8).is_empty());
+        assert!(!memory_bus.select_rom_bank(u64::MAX - 0x18, 1));
+    }
+    #[test]
+    fn banks_fit_whole_after_an_uneven_fixed_area() {
+        let mut memory_bus = MemoryBus48::with_ram_size(0x100);
+        // A fixed area of one and a half banks, followed by a window of one bank
+        let data: Vec<u8> = (0..0x30).collect();
+        memory_bus.map_banked_rom(0x1000, data, MEMORY_READ, 0x18, 0x10);
+
+        assert_eq!(
+            memory_bus.read_bytes(0x1014, 8),
+            &[0x14, 0x15, 0x16, 0x17, 0, 1, 2, 3]
+        );
+        assert!(memory_bus.select_rom_bank(0x1000, 2));
+        assert_eq!(memory_bus.read_bytes(0x1024, 4), &[0x2C, 0x2D, 0x2E, 0x2F]);
+        assert!(!memory_bus.select_rom_bank(0x1000, 3));
+
+        // Too small to hold the second bank past the fixed area
+        memory_bus.map_banked_rom(0x2000, vec![1; 8], MEMORY_READ, 0x18, 0x10);
+        assert!(!memory_bus.select_rom_bank(0x2000, 1));
+        assert_eq!(memory_bus.read_bytes(0x2018, 4), &[1, 1, 1, 1]);
+    }
 }
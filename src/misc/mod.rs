@@ -1,2 +1,5 @@
+pub mod crc32;
 pub mod memory_bus;
-pub mod io_bus;
\ No newline at end of file
+pub mod io_bus;
+pub mod symbols;
+pub mod scheduler;
\ No newline at end of file
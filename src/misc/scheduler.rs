@@ -0,0 +1,125 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::{Arc, Mutex},
+};
+
+/// Identifies a device on the timeline. The IO bus hands one out to every device it attaches.
+pub type DeviceId = u64;
+
+/// An event waiting on the timeline. Events due at the same cycle run in the order they were scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ScheduledEvent {
+    at: u64,
+    sequence: u64,
+    device: DeviceId,
+    event: u64,
+}
+
+/// The machine's timeline, counted in CPU cycles since the machine was built.
+///
+/// The motherboard moves time on by however many cycles each instruction took, and devices put events on the
+/// timeline for the moments they need to act, such as a timer running out or the next audio sample being due. That
+/// way a device only runs when something happens instead of polling after every instruction.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    now: u64,
+    events: BinaryHeap<Reverse<ScheduledEvent>>,
+    sequence: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current cycle.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Queues `event` for `device` at cycle `at`. An event in the past comes due straight away.
+    pub fn schedule(&mut self, at: u64, device: DeviceId, event: u64) {
+        self.events.push(Reverse(ScheduledEvent {
+            at,
+            sequence: self.sequence,
+            device,
+            event,
+        }));
+        self.sequence += 1;
+    }
+
+    /// Drops every queued `event` of `device`.
+    pub fn cancel(&mut self, device: DeviceId, event: u64) {
+        self.events
+            .retain(|Reverse(scheduled)| scheduled.device != device || scheduled.event != event);
+    }
+
+    /// Drops everything `device` has queued, for when it is taken off the bus.
+    pub fn cancel_all(&mut self, device: DeviceId) {
+        self.events
+            .retain(|Reverse(scheduled)| scheduled.device != device);
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    /// The cycle the next event is due at, if any are queued.
+    pub fn next_event_at(&self) -> Option<u64> {
+        self.events.peek().map(|Reverse(scheduled)| scheduled.at)
+    }
+
+    /// Takes the earliest event that has come due, returning the device it is for and the event.
+    pub fn pop_due(&mut self) -> Option<(DeviceId, u64)> {
+        if self.next_event_at()? > self.now {
+            return None;
+        }
+        self.events
+            .pop()
+            .map(|Reverse(scheduled)| (scheduled.device, scheduled.event))
+    }
+}
+
+/// A device's handle on the shared timeline, handed to it by `IoDevice::set_clock` when it is attached.
+///
+/// What an event means is up to the device, which gets it back in `IoDevice::handle_event`. A device that hasn't been
+/// attached yet has a clock of its own that never moves, so it can be set up before it is plugged in and schedule
+/// its events again once it gets the real one.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    scheduler: Arc<Mutex<Scheduler>>,
+    device: DeviceId,
+}
+
+impl Clock {
+    pub fn new(scheduler: Arc<Mutex<Scheduler>>, device: DeviceId) -> Self {
+        Self { scheduler, device }
+    }
+
+    /// The current cycle.
+    pub fn now(&self) -> u64 {
+        self.scheduler.lock().unwrap().now()
+    }
+
+    /// Wakes the device with `event` at cycle `at`.
+    pub fn schedule_at(&self, at: u64, event: u64) {
+        self.scheduler
+            .lock()
+            .unwrap()
+            .schedule(at, self.device, event);
+    }
+
+    /// Wakes the device with `event` once `delay` cycles have passed. A delay of 0 runs it as soon as the current
+    /// instruction retires.
+    pub fn schedule_in(&self, delay: u64, event: u64) {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let at = scheduler.now().saturating_add(delay);
+        scheduler.schedule(at, self.device, event);
+    }
+
+    /// Drops every queued `event` of this device.
+    pub fn cancel(&self, event: u64) {
+        self.scheduler.lock().unwrap().cancel(self.device, event);
+    }
+}
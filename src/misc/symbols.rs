@@ -0,0 +1,112 @@
+use std::{fmt, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolKind {
+    Function,
+    Object,
+    #[default]
+    Label,
+}
+
+/// A named address in a cartridge, as listed in its `.tags` file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u64,
+    /// Size in bytes. Zero means the size isn't known, and the symbol covers everything up to the next one.
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub kind: SymbolKind,
+}
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolError::Io(error) => write!(f, "failed to read symbols: {}", error),
+            SymbolError::Parse(error) => write!(f, "malformed symbols: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+/// Symbols for a cartridge, kept sorted by address so an address can be turned into `label+offset`.
+///
+/// The `.tags` sidecar next to a cartridge is a JSON array of symbols:
+///
+/// ```text
+/// [
+///     { "name": "main", "address": 4096, "size": 64, "kind": "function" },
+///     { "name": "counter", "address": 8192, "size": 8, "kind": "object" }
+/// ]
+/// ```
+///
+/// `size` and `kind` can be left out, making the symbol a label.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.address);
+        Self { symbols }
+    }
+
+    pub fn parse(json: &str) -> Result<Self, SymbolError> {
+        serde_json::from_str(json)
+            .map(Self::new)
+            .map_err(SymbolError::Parse)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SymbolError> {
+        Self::parse(&fs::read_to_string(path).map_err(SymbolError::Io)?)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.symbols).unwrap()
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The symbol `address` falls in, and how far into it the address is.
+    pub fn lookup(&self, address: u64) -> Option<(&Symbol, u64)> {
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address);
+        let symbol = self.symbols[..index].last()?;
+        let offset = address - symbol.address;
+        (symbol.size == 0 || offset < symbol.size).then_some((symbol, offset))
+    }
+
+    /// Formats `address` as `label+0x10`, or just `label` right on the symbol. Returns `None` outside any symbol.
+    pub fn symbolize(&self, address: u64) -> Option<String> {
+        self.lookup(address).map(|(symbol, offset)| {
+            if offset == 0 {
+                symbol.name.clone()
+            } else {
+                format!("{}+{:#X}", symbol.name, offset)
+            }
+        })
+    }
+}
@@ -1,6 +1,6 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use crate::misc::memory_bus::MemoryBus48;
+use crate::misc::{io_bus::IoDevice, memory_bus::MemoryBus48};
 
 pub mod monad;
 
@@ -9,6 +9,10 @@ pub trait Monarch64Motherboard {
     fn get_cpu_mut(&mut self) -> &mut dyn crate::cpus::Monarch64CPU;
     fn set_cpu(&mut self, cpu: Box<dyn crate::cpus::Monarch64CPU>);
     fn run_cpu(&mut self, memory_bus: &Mutex<MemoryBus48>);
+    /// Makes `run_cpu` return once this many instructions have run in total. `None` runs until the CPU halts.
+    fn set_instruction_limit(&mut self, limit: Option<u64>);
+    /// Plugs `device` into the board's IO bus on every port in `ports`.
+    fn attach_device(&mut self, ports: &[u16], device: Arc<Mutex<dyn IoDevice>>);
 
     fn init(&mut self, memory_bus: &Mutex<MemoryBus48>);
-}
+}
\ No newline at end of file
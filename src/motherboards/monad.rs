@@ -1,14 +1,52 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use crate::{
-    misc::memory_bus::MemoryBus48, motherboards::Monarch64Motherboard,
-    peripherals::storage::monad_boot_cartridge::MonadBootCartridge,
+    config::{ConfigError, MachineConfig},
+    misc::{
+        io_bus::{IoDevice, MachineSignal},
+        memory_bus::{MEMORY_ALL, MEMORY_READ, MEMORY_WRITE, MemoryBus48, MemoryRegion},
+    },
+    motherboards::Monarch64Motherboard,
+    peripherals::{
+        debug::debug_console::{DEBUG_CONSOLE_PORT, DebugConsole},
+        storage::{
+            bank_controller::{BANK_CONTROLLER_PORTS, BankController},
+            cartridge_slot::{CARTRIDGE_SLOT_PORTS, CartridgeSlot},
+            monad_boot_cartridge::{
+                CartridgeMapper, LATEST_CARTRIDGE_REVISION, MonadBootCartridge,
+            },
+            save_ram::{SAVE_RAM_CONTROL_PORT, SaveRam},
+            verification::VerificationPolicy,
+        },
+    },
 };
 
+/// How many cycles the Monad board runs per second of emulated time. Devices use it to keep time.
+pub const MONAD_CLOCK_HZ: u64 = 1_000_000;
+
+/// Cartridge segments loaded into this window are mapped as ROM instead of being copied into RAM.
+/// They are always read-only, even if the cartridge asks for them to be writable.
+pub const MONAD_CARTRIDGE_ROM_BASE: u64 = 0x1000_0000;
+pub const MONAD_CARTRIDGE_ROM_SIZE: u64 = 0x10_0000;
+/// Banked cartridges keep their first bank in the lower half of the ROM window and switch the upper half.
+/// Their ROM has to be a single segment loaded at `MONAD_CARTRIDGE_ROM_BASE`, and can be as large as it likes.
+pub const MONAD_CARTRIDGE_BANK_SIZE: u64 = MONAD_CARTRIDGE_ROM_SIZE / 2;
+
+/// Where a cartridge's battery-backed save RAM shows up, and the most a cartridge can ask for.
+pub const MONAD_SAVE_RAM_BASE: u64 = 0x1800_0000;
+pub const MONAD_SAVE_RAM_MAX_SIZE: u64 = 0x1_0000;
+
 pub struct MonadMotherboard {
     pub cpu: Box<dyn crate::cpus::Monarch64CPU>,
     pub io_bus: Mutex<crate::misc::io_bus::IoBus>,
     pub boot_cartridge: Option<MonadBootCartridge>,
+    save_ram: Option<Arc<Mutex<SaveRam>>>,
+    cartridge_slot: Arc<Mutex<CartridgeSlot>>,
+    memory_regions: Vec<MemoryRegion>,
+    /// Checked on every cartridge before it is loaded, whether it is booted or swapped in.
+    verification_policy: VerificationPolicy,
+    instruction_limit: Option<u64>,
+    instructions: u64,
 }
 
 impl Monarch64Motherboard for MonadMotherboard {
@@ -25,52 +63,379 @@ impl Monarch64Motherboard for MonadMotherboard {
     }
 
     fn run_cpu(&mut self, memory_bus: &Mutex<MemoryBus48>) {
-        self.cpu.run_cpu(memory_bus, &self.io_bus);
+        // We drive the loop ourselves rather than calling into the CPU's run_cpu so the clock moves on, and devices get
+        // ticked, between instructions
+        self.cpu.set_running(true);
+        while self.cpu.is_running() {
+            if self
+                .instruction_limit
+                .is_some_and(|limit| self.instructions >= limit)
+            {
+                log::info!(
+                    "Monad Motherboard: Stopping after {} instructions.",
+                    self.instructions
+                );
+                break;
+            }
+            let cycles = self.cpu.execute_cycle(memory_bus, &self.io_bus);
+            self.instructions += 1;
+            let signals = self.io_bus.lock().unwrap().advance(cycles, memory_bus);
+            for signal in signals {
+                self.handle_signal(signal, memory_bus);
+            }
+        }
+        self.flush_save_ram(memory_bus);
+    }
+
+    fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_limit = limit;
+    }
+
+    fn attach_device(&mut self, ports: &[u16], device: Arc<Mutex<dyn IoDevice>>) {
+        self.io_bus.lock().unwrap().attach_device(ports, device);
     }
 
     fn init(&mut self, memory_bus: &Mutex<MemoryBus48>) {
+        // A swap queued before power on just decides what we boot from
+        let pending = self.cartridge_slot.lock().unwrap().take_pending();
+        if let Some(cartridge) = pending {
+            self.boot_cartridge = cartridge;
+        }
+        self.detach_cartridge_devices();
+
+        for region in &self.memory_regions {
+            region.map(&mut memory_bus.lock().unwrap());
+        }
+
         // First, we have to load the boot cartridge into RAM if it exists
+        let loaded = self.load_cartridge(memory_bus, true);
+        if loaded && let Some(cartridge) = &self.boot_cartridge {
+            self.cpu
+                .set_reset_vector(cartridge.get_entry_point(), cartridge.get_stack_pointer());
+            self.cpu.reset();
+        }
+        self.cartridge_slot
+            .lock()
+            .unwrap()
+            .set_inserted(loaded, false);
+    }
+}
+
+impl MonadMotherboard {
+    pub fn new(cpu: Box<dyn crate::cpus::Monarch64CPU>) -> Self {
+        let mut io_bus = crate::misc::io_bus::IoBus::new();
+        // The debug console is always present so boot code can log before any driver is set up
+        io_bus.attach_device(
+            &[DEBUG_CONSOLE_PORT],
+            Arc::new(Mutex::new(DebugConsole::new())),
+        );
+        let cartridge_slot = Arc::new(Mutex::new(CartridgeSlot::new()));
+        io_bus.attach_device(&CARTRIDGE_SLOT_PORTS, cartridge_slot.clone());
+
+        Self {
+            cpu,
+            boot_cartridge: None,
+            io_bus: Mutex::new(io_bus),
+            save_ram: None,
+            cartridge_slot,
+            memory_regions: Vec::new(),
+            verification_policy: VerificationPolicy::default(),
+            instruction_limit: None,
+            instructions: 0,
+        }
+    }
+
+    /// Builds the board around `cpu`, with the memory regions, devices and cartridges `config` describes.
+    pub fn from_config(
+        config: &MachineConfig,
+        cpu: Box<dyn crate::cpus::Monarch64CPU>,
+    ) -> Result<Self, ConfigError> {
+        // Flat RAM is looked at before anything mapped, so it would hide the cartridge's ROM and save RAM
+        if config.ram_size as u64 > MONAD_CARTRIDGE_ROM_BASE {
+            return Err(ConfigError::Invalid(format!(
+                "{:#X} bytes of RAM runs into the cartridge ROM at {:#X}",
+                config.ram_size, MONAD_CARTRIDGE_ROM_BASE
+            )));
+        }
+        let mut motherboard = Self::new(cpu);
+        for region in &config.memory {
+            motherboard = motherboard.with_memory_region(region.load()?);
+        }
+        for device in &config.devices {
+            device.attach(&mut motherboard)?;
+        }
+        if let Some(cartridge) = &config.cartridge {
+            motherboard = motherboard
+                .with_verification_policy(cartridge.policy()?)
+                .with_boot_cartridge(cartridge.load()?);
+            for (cycle, next) in cartridge.load_swaps()? {
+                motherboard
+                    .cartridge_slot
+                    .lock()
+                    .unwrap()
+                    .schedule(cycle, next);
+            }
+        }
+        Ok(motherboard)
+    }
+
+    /// Maps the inserted cartridge's ROM and save RAM, and copies its RAM segments in if `load_ram` is set.
+    /// Returns whether the cartridge could be loaded.
+    fn load_cartridge(&mut self, memory_bus: &Mutex<MemoryBus48>, load_ram: bool) -> bool {
         if let Some(cartridge) = &self.boot_cartridge {
-            if cartridge.get_revision() > 0 {
+            if let Err(error) = self.verification_policy.check_cartridge(cartridge) {
+                log::error!(
+                    "Monad Motherboard: Boot cartridge failed verification: {}. No data loaded.",
+                    error
+                );
+            } else if cartridge.get_revision() > LATEST_CARTRIDGE_REVISION {
                 log::error!(
                     "Monad Motherboard: Unsupported boot cartridge revision: {}. No data loaded.",
                     cartridge.get_revision()
                 );
             } else {
-                let data = cartridge.get_data();
-                if data.len() <= memory_bus.lock().unwrap().get_size() {
-                    memory_bus.lock().unwrap().write_bytes(0, data);
+                log::debug!(
+                    "Monad Motherboard: Loading revision {} boot cartridge, {} bytes in {} segments.",
+                    cartridge.get_revision(),
+                    cartridge.get_size(),
+                    cartridge.get_segments().len()
+                );
+                let ram_size = memory_bus.lock().unwrap().get_size() as u64;
+                let rom_window =
+                    MONAD_CARTRIDGE_ROM_BASE..MONAD_CARTRIDGE_ROM_BASE + MONAD_CARTRIDGE_ROM_SIZE;
+                let banked = cartridge.get_mapper() == CartridgeMapper::Banked;
+                let fits = cartridge.get_segments().iter().all(|segment| {
+                    let end = segment
+                        .load_address
+                        .saturating_add(segment.data.len() as u64 + segment.zero_fill as u64);
+                    if banked && rom_window.contains(&segment.load_address) {
+                        segment.load_address == MONAD_CARTRIDGE_ROM_BASE
+                    } else if rom_window.contains(&segment.load_address) {
+                        end <= rom_window.end
+                    } else {
+                        end <= ram_size
+                    }
+                }) && cartridge.get_save_ram_size() as u64 <= MONAD_SAVE_RAM_MAX_SIZE;
+
+                if fits {
+                    let mut memory_bus = memory_bus.lock().unwrap();
+                    for segment in cartridge.get_segments() {
+                        if rom_window.contains(&segment.load_address) {
+                            let mut rom = segment.data.clone();
+                            rom.resize(rom.len() + segment.zero_fill as usize, 0);
+                            if banked {
+                                let bank_count =
+                                    (rom.len() as u64).div_ceil(MONAD_CARTRIDGE_BANK_SIZE);
+                                log::debug!(
+                                    "Monad Motherboard: Mapping {} banks of cartridge ROM.",
+                                    bank_count
+                                );
+                                memory_bus.map_banked_rom(
+                                    segment.load_address,
+                                    rom,
+                                    segment.flags as u8,
+                                    MONAD_CARTRIDGE_BANK_SIZE,
+                                    MONAD_CARTRIDGE_BANK_SIZE,
+                                );
+                                self.io_bus.lock().unwrap().attach_device(
+                                    &BANK_CONTROLLER_PORTS,
+                                    Arc::new(Mutex::new(BankController::new(
+                                        segment.load_address,
+                                        bank_count,
+                                    ))),
+                                );
+                            } else {
+                                memory_bus.map_rom(segment.load_address, rom, segment.flags as u8);
+                            }
+                            continue;
+                        }
+
+                        if !load_ram {
+                            log::warn!(
+                                "Monad Motherboard: Skipping RAM segment at {:#X} until the next power cycle.",
+                                segment.load_address
+                            );
+                            continue;
+                        }
+                        memory_bus.write_bytes(segment.load_address, &segment.data);
+                        let bss_address = segment.load_address + segment.data.len() as u64;
+                        memory_bus.write_bytes(bss_address, &vec![0; segment.zero_fill as usize]);
+                    }
+                    // Protections go on last, otherwise read-only segments couldn't be written in the first place
+                    for segment in cartridge.get_segments() {
+                        if load_ram
+                            && !rom_window.contains(&segment.load_address)
+                            && segment.flags & MEMORY_ALL as u32 != MEMORY_ALL as u32
+                        {
+                            let length = segment.data.len() as u64 + segment.zero_fill as u64;
+                            memory_bus.protect(segment.load_address, length, segment.flags as u8);
+                        }
+                    }
+
+                    if cartridge.get_save_ram_size() > 0 {
+                        let save_ram = Self::load_save_ram(cartridge, &mut memory_bus);
+                        self.io_bus
+                            .lock()
+                            .unwrap()
+                            .attach_device(&[SAVE_RAM_CONTROL_PORT], save_ram.clone());
+                        self.save_ram = Some(save_ram);
+                    }
+
+                    self.cpu.set_symbols(cartridge.get_symbols().cloned());
+                    if !cartridge.get_title().is_empty() {
+                        log::info!(
+                            "Monad Motherboard: Loaded boot cartridge \"{}\".",
+                            cartridge.get_title()
+                        );
+                    }
+                    return true;
                 } else {
                     log::error!(
-                        "Monad Motherboard: Boot cartridge data is larger than RAM size. No data loaded."
+                        "Monad Motherboard: Boot cartridge data doesn't fit in RAM, the ROM window or save RAM. No data loaded."
                     );
                 }
             }
         } else {
             log::error!("Monad Motherboard: No boot cartridge inserted. No data loaded.");
         }
+        false
     }
-}
 
-impl MonadMotherboard {
-    pub fn new(cpu: Box<dyn crate::cpus::Monarch64CPU>) -> Self {
-        Self {
-            cpu,
-            boot_cartridge: None,
-            io_bus: Mutex::new(crate::misc::io_bus::IoBus::new()),
+    /// Unmaps the inserted cartridge's ROM and save RAM. Whatever it copied into RAM stays there.
+    fn unload_cartridge(&mut self, memory_bus: &Mutex<MemoryBus48>) {
+        self.flush_save_ram(memory_bus);
+        self.detach_cartridge_devices();
+        self.cpu.set_symbols(None);
+
+        let mut memory_bus = memory_bus.lock().unwrap();
+        memory_bus.unmap_ram(MONAD_SAVE_RAM_BASE);
+        if let Some(cartridge) = &self.boot_cartridge {
+            let rom_window =
+                MONAD_CARTRIDGE_ROM_BASE..MONAD_CARTRIDGE_ROM_BASE + MONAD_CARTRIDGE_ROM_SIZE;
+            for segment in cartridge.get_segments() {
+                if rom_window.contains(&segment.load_address) {
+                    memory_bus.unmap_rom(segment.load_address);
+                }
+            }
+        }
+    }
+
+    /// Devices belonging to the last cartridge would point at memory that's no longer there.
+    fn detach_cartridge_devices(&mut self) {
+        let mut io_bus = self.io_bus.lock().unwrap();
+        for port in BANK_CONTROLLER_PORTS {
+            io_bus.detach_port(port);
+        }
+        io_bus.detach_port(SAVE_RAM_CONTROL_PORT);
+        self.save_ram = None;
+    }
+
+    /// Carries out a swap queued in the cartridge slot while the machine runs. The CPU keeps going, so only the
+    /// ROM and save RAM change until the next power cycle boots the new cartridge properly.
+    fn swap_cartridge(&mut self, memory_bus: &Mutex<MemoryBus48>) {
+        let Some(cartridge) = self.cartridge_slot.lock().unwrap().take_pending() else {
+            return;
+        };
+
+        self.unload_cartridge(memory_bus);
+        if self.boot_cartridge.is_some() {
+            log::info!("Monad Motherboard: Boot cartridge removed.");
+        }
+        self.boot_cartridge = cartridge;
+        let inserted = self.boot_cartridge.is_some() && self.load_cartridge(memory_bus, false);
+        self.cartridge_slot
+            .lock()
+            .unwrap()
+            .set_inserted(inserted, true);
+    }
+
+    /// Maps the cartridge's save RAM, filled from its save file if there is one.
+    fn load_save_ram(
+        cartridge: &MonadBootCartridge,
+        memory_bus: &mut MemoryBus48,
+    ) -> Arc<Mutex<SaveRam>> {
+        let size = cartridge.get_save_ram_size() as usize;
+        let save_path = cartridge.get_save_path();
+        if save_path.is_none() {
+            log::warn!(
+                "Monad Motherboard: Boot cartridge has no save file, its save RAM won't be kept."
+            );
+        }
+        let save_ram = SaveRam::open(save_path, MONAD_SAVE_RAM_BASE, size, MONAD_CLOCK_HZ)
+            .unwrap_or_else(|error| {
+                log::error!(
+                    "Monad Motherboard: Failed to load save RAM from {:?}: {}. Starting blank.",
+                    save_path,
+                    error
+                );
+                SaveRam::open(None, MONAD_SAVE_RAM_BASE, size, MONAD_CLOCK_HZ).unwrap()
+            });
+
+        memory_bus.map_ram(MONAD_SAVE_RAM_BASE, save_ram.contents().to_vec());
+        memory_bus.protect(MONAD_SAVE_RAM_BASE, size as u64, MEMORY_READ | MEMORY_WRITE);
+        Arc::new(Mutex::new(save_ram))
+    }
+
+    fn flush_save_ram(&self, memory_bus: &Mutex<MemoryBus48>) {
+        if let Some(save_ram) = &self.save_ram {
+            save_ram.lock().unwrap().flush(memory_bus);
         }
     }
 
+    fn handle_signal(&mut self, signal: MachineSignal, memory_bus: &Mutex<MemoryBus48>) {
+        match signal {
+            MachineSignal::Reset => {
+                log::info!("Monad Motherboard: Resetting CPU.");
+                self.cpu.reset();
+            }
+            MachineSignal::PowerCycle => {
+                log::info!("Monad Motherboard: Power cycling.");
+                // Save RAM is battery-backed, so it has to survive the power going off
+                self.flush_save_ram(memory_bus);
+                memory_bus.lock().unwrap().clear();
+                self.init(memory_bus);
+                self.cpu.reset();
+            }
+            MachineSignal::Interrupt(vector) => self.cpu.raise_interrupt(vector),
+            MachineSignal::CartridgeSwap => self.swap_cartridge(memory_bus),
+        }
+    }
+
+    pub fn with_io_device(mut self, ports: &[u16], device: Arc<Mutex<dyn IoDevice>>) -> Self {
+        self.attach_device(ports, device);
+        self
+    }
+
+    /// Adds memory beyond main RAM, mapped whenever the machine powers on.
+    pub fn with_memory_region(mut self, region: MemoryRegion) -> Self {
+        self.memory_regions.push(region);
+        self
+    }
+
+    /// Sets how cartridges are verified before they are booted or swapped in. The default policy only refuses
+    /// cartridges whose checksum didn't match when they were loaded.
+    pub fn with_verification_policy(mut self, policy: VerificationPolicy) -> Self {
+        self.verification_policy = policy;
+        self
+    }
+
     pub fn with_boot_cartridge(mut self, cartridge: MonadBootCartridge) -> Self {
         self.boot_cartridge = Some(cartridge);
         self
     }
 
+    /// Swaps in `cartridge`. Before `init` it is simply what the machine boots from, afterwards it is hot-swapped
+    /// between instructions the next time the machine runs.
     pub fn set_boot_cartridge(&mut self, cartridge: MonadBootCartridge) {
-        self.boot_cartridge.replace(cartridge);
+        self.cartridge_slot.lock().unwrap().insert(cartridge);
     }
 
     pub fn remove_boot_cartridge(&mut self) {
-        self.boot_cartridge = None;
+        self.cartridge_slot.lock().unwrap().eject();
+    }
+
+    /// The slot the boot cartridge sits in. The host can hold on to it to swap cartridges while the machine runs.
+    pub fn cartridge_slot(&self) -> Arc<Mutex<CartridgeSlot>> {
+        self.cartridge_slot.clone()
     }
 }
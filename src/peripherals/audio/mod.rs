@@ -0,0 +1,2 @@
+pub mod sound;
+pub mod wav;
\ No newline at end of file
@@ -0,0 +1,263 @@
+use std::{io, sync::Mutex};
+
+use crate::{
+    misc::{
+        io_bus::{IoDevice, MachineSignal},
+        memory_bus::MemoryBus48,
+        scheduler::Clock,
+    },
+    peripherals::audio::wav::WavWriter,
+};
+
+/// Each channel has four consecutive ports starting here: control, frequency, volume and one reserved.
+pub const SOUND_CHANNEL_BASE_PORT: u16 = 0x0110;
+pub const SOUND_CHANNEL_COUNT: usize = 4;
+
+/// Bit 0 enables the channel, bits 1-2 pick the waveform.
+pub const CHANNEL_CONTROL: u16 = 0;
+/// Frequency in Hz.
+pub const CHANNEL_FREQUENCY: u16 = 1;
+/// Volume from 0 to 255.
+pub const CHANNEL_VOLUME: u16 = 2;
+
+pub const SOUND_PORTS: [u16; SOUND_CHANNEL_COUNT * 4] = {
+    let mut ports = [0; SOUND_CHANNEL_COUNT * 4];
+    let mut index = 0;
+    while index < ports.len() {
+        ports[index] = SOUND_CHANNEL_BASE_PORT + index as u16;
+        index += 1;
+    }
+    ports
+};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 22050;
+
+const CONTROL_ENABLE: u64 = 0b1;
+
+const SAMPLE_EVENT: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Noise,
+}
+
+impl Waveform {
+    fn from_control(control: u64) -> Self {
+        match (control >> 1) & 0b11 {
+            0 => Waveform::Square,
+            1 => Waveform::Triangle,
+            2 => Waveform::Sawtooth,
+            _ => Waveform::Noise,
+        }
+    }
+}
+
+struct Channel {
+    control: u64,
+    frequency: u64,
+    volume: u64,
+    phase: f64,
+    lfsr: u16,
+    noise_level: f64,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            control: 0,
+            frequency: 0,
+            volume: 0,
+            phase: 0.0,
+            lfsr: 1,
+            noise_level: 1.0,
+        }
+    }
+
+    /// Advances the channel by one output sample and returns its level between -1 and 1.
+    fn next_sample(&mut self, sample_rate: u32) -> f64 {
+        if self.control & CONTROL_ENABLE == 0 || self.frequency == 0 {
+            return 0.0;
+        }
+
+        let level = match Waveform::from_control(self.control) {
+            Waveform::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * self.phase - 1.0,
+            Waveform::Noise => self.noise_level,
+        };
+
+        self.phase += self.frequency as f64 / sample_rate as f64;
+        while self.phase >= 1.0 {
+            self.phase -= 1.0;
+            // The noise generator is a 15-bit LFSR clocked once per period
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            self.noise_level = if self.lfsr & 1 == 0 { 1.0 } else { -1.0 };
+        }
+
+        level * self.volume.min(255) as f64 / 255.0
+    }
+}
+
+/// A tone and noise generator that renders to a WAV file.
+///
+/// Samples are produced against emulated time, where `clock_hz` cycles make up one second, so the output is
+/// identical on every run no matter how fast the host is.
+pub struct SoundDevice {
+    channels: [Channel; SOUND_CHANNEL_COUNT],
+    /// Dropped after the first failed write, so a full disk is reported once rather than for every sample.
+    output: Option<WavWriter>,
+    clock_hz: u64,
+    sample_rate: u32,
+    clock: Clock,
+    /// The cycle sample 0 was due at, and how many samples have been rendered since.
+    start: u64,
+    samples: u64,
+}
+
+impl SoundDevice {
+    /// Fails if `sample_rate` is 0, as there would be no samples to put on the clock.
+    pub fn new(output: WavWriter, clock_hz: u64, sample_rate: u32) -> io::Result<Self> {
+        if sample_rate == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the sample rate can't be 0",
+            ));
+        }
+        Ok(Self {
+            channels: std::array::from_fn(|_| Channel::new()),
+            output: Some(output),
+            clock_hz,
+            sample_rate,
+            clock: Clock::default(),
+            start: 0,
+            samples: 0,
+        })
+    }
+
+    fn register(&mut self, port: u16) -> Option<&mut u64> {
+        let offset = port.checked_sub(SOUND_CHANNEL_BASE_PORT)?;
+        let channel = self.channels.get_mut((offset / 4) as usize)?;
+        match offset % 4 {
+            CHANNEL_CONTROL => Some(&mut channel.control),
+            CHANNEL_FREQUENCY => Some(&mut channel.frequency),
+            CHANNEL_VOLUME => Some(&mut channel.volume),
+            _ => None,
+        }
+    }
+
+    fn read(&mut self, port: u16) -> u64 {
+        self.register(port).map_or(0, |register| *register)
+    }
+
+    fn write(&mut self, port: u16, value: u64) {
+        if let Some(register) = self.register(port) {
+            *register = value;
+        }
+    }
+
+    /// Puts the next sample on the clock. Working from the sample count keeps the rate exact even though a sample
+    /// doesn't last a whole number of cycles.
+    fn schedule_sample(&self) {
+        let offset = ((self.samples + 1) * self.clock_hz).div_ceil(self.sample_rate as u64);
+        self.clock.schedule_at(self.start + offset, SAMPLE_EVENT);
+    }
+
+    fn render_sample(&mut self) {
+        let sample_rate = self.sample_rate;
+        let mix: f64 = self
+            .channels
+            .iter_mut()
+            .map(|channel| channel.next_sample(sample_rate))
+            .sum::<f64>()
+            / SOUND_CHANNEL_COUNT as f64;
+
+        if let Some(output) = &mut self.output
+            && let Err(error) = output.write_sample((mix * i16::MAX as f64) as i16)
+        {
+            log::error!(
+                "Sound Device: Failed to write sample: {}. No more audio will be recorded.",
+                error
+            );
+            self.output = None;
+        }
+    }
+}
+
+impl IoDevice for SoundDevice {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        self.read(port) as u8
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        self.read(port) as u16
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        self.read(port) as u32
+    }
+
+    fn read_u64(&mut self, port: u16) -> u64 {
+        self.read(port)
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u64(&mut self, port: u16, value: u64) {
+        self.write(port, value);
+    }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.start = self.clock.now();
+        self.samples = 0;
+        self.schedule_sample();
+    }
+
+    fn handle_event(
+        &mut self,
+        _event: u64,
+        _memory_bus: &Mutex<MemoryBus48>,
+        _signals: &mut Vec<MachineSignal>,
+    ) {
+        self.render_sample();
+        self.samples += 1;
+        self.schedule_sample();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn refuses_a_zero_sample_rate() {
+        let path = std::env::temp_dir().join(format!("m64-sound-{}.wav", std::process::id()));
+        let output = WavWriter::create(&path, 0).unwrap();
+        let error = SoundDevice::new(output, 1_000_000, 0).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        fs::remove_file(path).unwrap();
+    }
+}
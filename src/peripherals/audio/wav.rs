@@ -0,0 +1,122 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const HEADER_SIZE: u32 = 44;
+/// The sizes in the header are u32s, so the samples have to stop short of 4 GiB.
+const MAX_DATA_SIZE: u64 = (u32::MAX - (HEADER_SIZE - 8)) as u64 & !1;
+
+/// Streams mono 16-bit PCM samples into a WAV file.
+///
+/// The sizes in the header are only known at the end, so they are patched in by `finish`, which also runs on drop.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    samples_written: u64,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            sample_rate,
+            samples_written: 0,
+            finished: false,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        // write_sample never goes past the limit, so this always fits
+        let data_size = (self.samples_written * 2) as u32;
+        let block_align: u16 = 2;
+
+        self.file.write_all(b"RIFF")?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.file.write_all(b"WAVE")?;
+        self.file.write_all(b"fmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?;
+        // PCM, one channel
+        self.file.write_all(&1u16.to_le_bytes())?;
+        self.file.write_all(&1u16.to_le_bytes())?;
+        self.file.write_all(&self.sample_rate.to_le_bytes())?;
+        self.file.write_all(
+            &self
+                .sample_rate
+                .saturating_mul(block_align as u32)
+                .to_le_bytes(),
+        )?;
+        self.file.write_all(&block_align.to_le_bytes())?;
+        self.file.write_all(&16u16.to_le_bytes())?;
+        self.file.write_all(b"data")?;
+        self.file.write_all(&data_size.to_le_bytes())
+    }
+
+    /// Fails with `FileTooLarge` once the file can't describe any more samples.
+    pub fn write_sample(&mut self, sample: i16) -> io::Result<()> {
+        if (self.samples_written + 1) * 2 > MAX_DATA_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "WAV files can't hold more than 4 GiB of samples",
+            ));
+        }
+        self.file.write_all(&sample.to_le_bytes())?;
+        self.samples_written += 1;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(error) = self.finish() {
+            log::error!("WAV Writer: Failed to finish file: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn stops_before_the_header_sizes_overflow() {
+        let path = std::env::temp_dir().join(format!("m64-wav-{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path, 44100).unwrap();
+        writer.write_sample(1).unwrap();
+        writer.write_sample(-1).unwrap();
+        writer.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), HEADER_SIZE as usize + 4);
+        assert_eq!(&data[4..8], &(HEADER_SIZE - 8 + 4).to_le_bytes());
+        assert_eq!(&data[40..44], &4u32.to_le_bytes());
+
+        let mut writer = WavWriter::create(&path, 44100).unwrap();
+        writer.samples_written = MAX_DATA_SIZE / 2;
+        let error = writer.write_sample(0).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+        writer.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        let riff_size = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        assert_eq!(riff_size as u64, HEADER_SIZE as u64 - 8 + MAX_DATA_SIZE);
+
+        fs::remove_file(path).unwrap();
+    }
+}
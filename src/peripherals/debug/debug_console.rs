@@ -0,0 +1,83 @@
+use crate::misc::io_bus::IoDevice;
+
+/// The port the debug console is attached to on the Monad motherboard.
+pub const DEBUG_CONSOLE_PORT: u16 = 0x00E9;
+
+/// Lines longer than this are flushed early so a guest that never writes a newline can't grow the buffer forever.
+const MAX_LINE_LENGTH: usize = 256;
+
+/// A write-only port that forwards guest output to the host logger, one line at a time.
+///
+/// Every byte written is appended to a line buffer, which is logged under the `guest` target when the guest
+/// writes a newline. Wider writes are treated as their little endian bytes, so `outq` can push up to 8 characters at once.
+pub struct DebugConsole {
+    line: Vec<u8>,
+}
+
+impl Default for DebugConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugConsole {
+    pub fn new() -> Self {
+        Self { line: Vec::new() }
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.flush(),
+            // Carriage returns and the padding of wider writes carry no text
+            b'\r' | 0 => {}
+            _ => {
+                self.line.push(byte);
+                if self.line.len() >= MAX_LINE_LENGTH {
+                    self.flush();
+                }
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        log::info!(target: "guest", "{}", String::from_utf8_lossy(&self.line));
+        self.line.clear();
+    }
+}
+
+impl IoDevice for DebugConsole {
+    fn write_u8(&mut self, _port: u16, value: u8) {
+        self.push_byte(value);
+    }
+
+    fn write_u16(&mut self, _port: u16, value: u16) {
+        value
+            .to_le_bytes()
+            .into_iter()
+            .for_each(|byte| self.push_byte(byte));
+    }
+
+    fn write_u32(&mut self, _port: u16, value: u32) {
+        value
+            .to_le_bytes()
+            .into_iter()
+            .for_each(|byte| self.push_byte(byte));
+    }
+
+    fn write_u64(&mut self, _port: u16, value: u64) {
+        value
+            .to_le_bytes()
+            .into_iter()
+            .for_each(|byte| self.push_byte(byte));
+    }
+}
+
+impl Drop for DebugConsole {
+    fn drop(&mut self) {
+        // Don't lose a trailing line the guest never terminated
+        self.flush();
+    }
+}
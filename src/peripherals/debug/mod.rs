@@ -0,0 +1,2 @@
+pub mod debug_console;
+pub mod semihosting;
\ No newline at end of file
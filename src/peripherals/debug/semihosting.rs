@@ -0,0 +1,338 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::misc::{
+    io_bus::{IoDevice, MachineSignal},
+    memory_bus::MemoryBus48,
+};
+
+/// The doorbell port. Writing the address of a request block here queues it, reading returns the last result.
+pub const SEMIHOSTING_PORT: u16 = 0x00EA;
+
+/// Size of a request block in guest memory.
+///
+/// ```text
+/// 0x00 u64 operation
+/// 0x08 u64 argument 0
+/// 0x10 u64 argument 1
+/// 0x18 u64 argument 2
+/// 0x20 i64 result, written back by the host
+/// ```
+pub const REQUEST_BLOCK_SIZE: usize = 0x28;
+
+pub const OP_OPEN: u64 = 1;
+pub const OP_READ: u64 = 2;
+pub const OP_WRITE: u64 = 3;
+pub const OP_CLOSE: u64 = 4;
+pub const OP_SEEK: u64 = 5;
+
+// Flags for OP_OPEN
+pub const OPEN_READ: u64 = 0b1;
+pub const OPEN_WRITE: u64 = 0b10;
+pub const OPEN_CREATE: u64 = 0b100;
+pub const OPEN_TRUNCATE: u64 = 0b1000;
+pub const OPEN_APPEND: u64 = 0b10000;
+
+// Negative results reported back to the guest
+pub const ERR_BAD_REQUEST: i64 = -1;
+pub const ERR_NOT_FOUND: i64 = -2;
+pub const ERR_DENIED: i64 = -3;
+pub const ERR_BAD_HANDLE: i64 = -4;
+pub const ERR_IO: i64 = -5;
+
+/// Lets guest programs open, read, write, seek and close files on the host.
+///
+/// The guest fills in a request block and writes its address to `SEMIHOSTING_PORT`. The request is run after the
+/// `out` instruction retires, so the result is in the block before the next instruction executes.
+/// Every path is resolved relative to `root` and anything that would escape it is refused.
+///
+/// | Operation | Argument 0 | Argument 1 | Argument 2 | Result |
+/// |-----------|------------|------------|------------|--------|
+/// | open | path address | path length | `OPEN_*` flags | handle |
+/// | read | handle | buffer address | length | bytes read |
+/// | write | handle | buffer address | length | bytes written |
+/// | close | handle | | | 0 |
+/// | seek | handle | offset (signed) | 0 = start, 1 = current, 2 = end | new position |
+pub struct Semihosting {
+    root: PathBuf,
+    files: HashMap<u64, File>,
+    next_handle: u64,
+    pending_request: Option<u64>,
+    last_result: i64,
+}
+
+impl Semihosting {
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+            files: HashMap::new(),
+            next_handle: 1,
+            pending_request: None,
+            last_result: 0,
+        })
+    }
+
+    fn handle_request(&mut self, address: u64, memory_bus: &Mutex<MemoryBus48>) {
+        let block = memory_bus
+            .lock()
+            .unwrap()
+            .read_bytes(address, REQUEST_BLOCK_SIZE)
+            .to_vec();
+        if block.len() != REQUEST_BLOCK_SIZE {
+            log::error!(
+                "Semihosting: Request block at {:#X} is out of bounds.",
+                address
+            );
+            self.last_result = ERR_BAD_REQUEST;
+            return;
+        }
+
+        let field =
+            |index: usize| u64::from_le_bytes(block[index * 8..index * 8 + 8].try_into().unwrap());
+        let (operation, arguments) = (field(0), [field(1), field(2), field(3)]);
+
+        let result = match operation {
+            OP_OPEN => self.open(arguments, memory_bus),
+            OP_READ => self.read(arguments, memory_bus),
+            OP_WRITE => self.write(arguments, memory_bus),
+            OP_CLOSE => self.close(arguments),
+            OP_SEEK => self.seek(arguments),
+            _ => {
+                log::error!("Semihosting: Unknown operation {:#X}.", operation);
+                ERR_BAD_REQUEST
+            }
+        };
+
+        self.last_result = result;
+        memory_bus
+            .lock()
+            .unwrap()
+            .write_bytes(address + 0x20, &result.to_le_bytes());
+    }
+
+    fn open(
+        &mut self,
+        [path_address, path_length, flags]: [u64; 3],
+        memory_bus: &Mutex<MemoryBus48>,
+    ) -> i64 {
+        let path_bytes = memory_bus
+            .lock()
+            .unwrap()
+            .read_bytes(path_address, path_length as usize)
+            .to_vec();
+        if path_bytes.len() as u64 != path_length {
+            return ERR_BAD_REQUEST;
+        }
+        let Ok(guest_path) = String::from_utf8(path_bytes) else {
+            return ERR_BAD_REQUEST;
+        };
+        let host_path = match self.resolve(&guest_path) {
+            Ok(host_path) => host_path,
+            Err(ERR_DENIED) => {
+                log::warn!(
+                    "Semihosting: Refused to open {:?}, it is outside the sandbox.",
+                    guest_path
+                );
+                return ERR_DENIED;
+            }
+            Err(error) => return error,
+        };
+
+        let file = OpenOptions::new()
+            .read(flags & OPEN_READ != 0)
+            .write(flags & OPEN_WRITE != 0)
+            .create(flags & OPEN_CREATE != 0)
+            .truncate(flags & OPEN_TRUNCATE != 0)
+            .append(flags & OPEN_APPEND != 0)
+            .open(&host_path);
+
+        match file {
+            Ok(file) => {
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.files.insert(handle, file);
+                log::debug!("Semihosting: Opened {:?} as handle {}.", host_path, handle);
+                handle as i64
+            }
+            Err(error) => Self::error_code(&error),
+        }
+    }
+
+    fn read(&mut self, [handle, buffer, length]: [u64; 3], memory_bus: &Mutex<MemoryBus48>) -> i64 {
+        let Some(file) = self.files.get_mut(&handle) else {
+            return ERR_BAD_HANDLE;
+        };
+        let memory_size = memory_bus.lock().unwrap().get_size() as u64;
+        if buffer
+            .checked_add(length)
+            .is_none_or(|end| end > memory_size)
+        {
+            return ERR_BAD_REQUEST;
+        }
+
+        let mut data = vec![0; length as usize];
+        match file.read(&mut data) {
+            Ok(count) => {
+                memory_bus
+                    .lock()
+                    .unwrap()
+                    .write_bytes(buffer, &data[..count]);
+                count as i64
+            }
+            Err(error) => Self::error_code(&error),
+        }
+    }
+
+    fn write(
+        &mut self,
+        [handle, buffer, length]: [u64; 3],
+        memory_bus: &Mutex<MemoryBus48>,
+    ) -> i64 {
+        let Some(file) = self.files.get_mut(&handle) else {
+            return ERR_BAD_HANDLE;
+        };
+        let data = memory_bus
+            .lock()
+            .unwrap()
+            .read_bytes(buffer, length as usize)
+            .to_vec();
+        if data.len() as u64 != length {
+            return ERR_BAD_REQUEST;
+        }
+
+        match file.write_all(&data) {
+            Ok(()) => length as i64,
+            Err(error) => Self::error_code(&error),
+        }
+    }
+
+    fn close(&mut self, [handle, _, _]: [u64; 3]) -> i64 {
+        match self.files.remove(&handle) {
+            Some(_) => 0,
+            None => ERR_BAD_HANDLE,
+        }
+    }
+
+    fn seek(&mut self, [handle, offset, whence]: [u64; 3]) -> i64 {
+        let Some(file) = self.files.get_mut(&handle) else {
+            return ERR_BAD_HANDLE;
+        };
+        let position = match whence {
+            0 => SeekFrom::Start(offset),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return ERR_BAD_REQUEST,
+        };
+
+        match file.seek(position) {
+            Ok(position) => position as i64,
+            Err(error) => Self::error_code(&error),
+        }
+    }
+
+    /// Maps a guest path onto the host. Paths that would leave the sandbox fail with `ERR_DENIED`, and paths under a
+    /// directory that doesn't exist with `ERR_NOT_FOUND`.
+    fn resolve(&self, guest_path: &str) -> Result<PathBuf, i64> {
+        let relative = Path::new(guest_path);
+        // Only plain names are allowed, which rules out absolute paths and `..`
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(ERR_DENIED);
+        }
+
+        let host_path = self.root.join(relative);
+        // Symlinks inside the sandbox could still point out of it, so check where the path really ends up. A dangling
+        // symlink doesn't resolve and is refused, as creating it would create whatever it points at.
+        let real_path = match host_path.symlink_metadata() {
+            Ok(_) => host_path.canonicalize().map_err(|_| ERR_DENIED)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => host_path
+                .parent()
+                .ok_or(ERR_DENIED)?
+                .canonicalize()
+                .map_err(|error| Self::error_code(&error))?,
+            Err(error) => return Err(Self::error_code(&error)),
+        };
+        if real_path.starts_with(&self.root) {
+            Ok(host_path)
+        } else {
+            Err(ERR_DENIED)
+        }
+    }
+
+    fn error_code(error: &io::Error) -> i64 {
+        match error.kind() {
+            io::ErrorKind::NotFound => ERR_NOT_FOUND,
+            io::ErrorKind::PermissionDenied => ERR_DENIED,
+            _ => ERR_IO,
+        }
+    }
+}
+
+impl IoDevice for Semihosting {
+    fn read_u64(&mut self, _port: u16) -> u64 {
+        self.last_result as u64
+    }
+
+    fn write_u64(&mut self, _port: u16, value: u64) {
+        self.pending_request = Some(value);
+    }
+
+    fn tick(&mut self, memory_bus: &Mutex<MemoryBus48>, _signals: &mut Vec<MachineSignal>) {
+        if let Some(address) = self.pending_request.take() {
+            self.handle_request(address, memory_bus);
+        }
+    }
+
+    fn wants_tick(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn sandbox(name: &str) -> (PathBuf, Semihosting) {
+        let root =
+            std::env::temp_dir().join(format!("m64-semihosting-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("inside")).unwrap();
+        let semihosting = Semihosting::new(root.join("inside")).unwrap();
+        (root, semihosting)
+    }
+
+    #[test]
+    fn resolves_paths_inside_the_sandbox() {
+        let (root, semihosting) = sandbox("inside");
+        assert!(semihosting.resolve("new.txt").is_ok());
+        assert_eq!(semihosting.resolve("../escape.txt"), Err(ERR_DENIED));
+        assert_eq!(semihosting.resolve("/etc/passwd"), Err(ERR_DENIED));
+        assert_eq!(semihosting.resolve("missing/new.txt"), Err(ERR_NOT_FOUND));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_sandbox() {
+        use std::os::unix::fs::symlink;
+
+        let (root, semihosting) = sandbox("symlinks");
+        fs::write(root.join("outside.txt"), b"secret").unwrap();
+        symlink(root.join("outside.txt"), root.join("inside/existing")).unwrap();
+        symlink(root.join("created.txt"), root.join("inside/dangling")).unwrap();
+
+        assert_eq!(semihosting.resolve("existing"), Err(ERR_DENIED));
+        assert_eq!(semihosting.resolve("dangling"), Err(ERR_DENIED));
+        assert!(!root.join("created.txt").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    io::BufRead,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    misc::{
+        io_bus::{IoDevice, MachineSignal},
+        memory_bus::MemoryBus48,
+        scheduler::Clock,
+    },
+    peripherals::input::recording::{InputEvent, InputRecorder, InputRecording},
+};
+
+/// Bitmask of the buttons currently held, see the `BUTTON_*` constants.
+pub const GAMEPAD_BUTTONS_PORT: u16 = 0x0120;
+/// Signed 16-bit axis positions.
+pub const GAMEPAD_AXIS_X_PORT: u16 = 0x0121;
+pub const GAMEPAD_AXIS_Y_PORT: u16 = 0x0122;
+/// Bit 0 enables the change interrupt.
+pub const GAMEPAD_CONTROL_PORT: u16 = 0x0123;
+/// Bit 0 is set when the state changed. Writing a 1 clears it.
+pub const GAMEPAD_STATUS_PORT: u16 = 0x0124;
+pub const GAMEPAD_INTERRUPT_VECTOR_PORT: u16 = 0x0125;
+
+const REPLAY_EVENT: u64 = 0;
+
+pub const GAMEPAD_PORTS: [u16; 6] = [
+    GAMEPAD_BUTTONS_PORT,
+    GAMEPAD_AXIS_X_PORT,
+    GAMEPAD_AXIS_Y_PORT,
+    GAMEPAD_CONTROL_PORT,
+    GAMEPAD_STATUS_PORT,
+    GAMEPAD_INTERRUPT_VECTOR_PORT,
+];
+
+pub const BUTTON_UP: u16 = 1 << 0;
+pub const BUTTON_DOWN: u16 = 1 << 1;
+pub const BUTTON_LEFT: u16 = 1 << 2;
+pub const BUTTON_RIGHT: u16 = 1 << 3;
+pub const BUTTON_A: u16 = 1 << 4;
+pub const BUTTON_B: u16 = 1 << 5;
+pub const BUTTON_X: u16 = 1 << 6;
+pub const BUTTON_Y: u16 = 1 << 7;
+pub const BUTTON_START: u16 = 1 << 8;
+pub const BUTTON_SELECT: u16 = 1 << 9;
+pub const BUTTON_L: u16 = 1 << 10;
+pub const BUTTON_R: u16 = 1 << 11;
+
+const CONTROL_CHANGE_INTERRUPT: u64 = 0b1;
+const STATUS_CHANGED: u64 = 0b1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GamepadState {
+    pub buttons: u16,
+    pub axes: [i16; 2],
+}
+
+/// Where the gamepad gets its input from.
+pub enum GamepadSource {
+    /// State set by the host at any time, e.g. from a keyboard. It is sampled between instructions.
+    Live(Arc<Mutex<GamepadState>>),
+    /// A previous session played back cycle for cycle.
+    Replay(InputRecording),
+}
+
+/// Maps host keys onto gamepad buttons.
+pub struct KeyMap {
+    keys: HashMap<char, u16>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self {
+            keys: HashMap::from([
+                ('w', BUTTON_UP),
+                ('s', BUTTON_DOWN),
+                ('a', BUTTON_LEFT),
+                ('d', BUTTON_RIGHT),
+                ('j', BUTTON_A),
+                ('k', BUTTON_B),
+                ('u', BUTTON_X),
+                ('i', BUTTON_Y),
+                ('m', BUTTON_START),
+                ('n', BUTTON_SELECT),
+                ('q', BUTTON_L),
+                ('e', BUTTON_R),
+            ]),
+        }
+    }
+}
+
+impl KeyMap {
+    pub fn bind(&mut self, key: char, button: u16) {
+        self.keys.insert(key.to_ascii_lowercase(), button);
+    }
+
+    /// The buttons held when all of `keys` are down. Unmapped keys are ignored.
+    pub fn buttons_for(&self, keys: &str) -> u16 {
+        keys.chars()
+            .filter_map(|key| self.keys.get(&key.to_ascii_lowercase()))
+            .fold(0, |buttons, button| buttons | button)
+    }
+
+    /// Feeds the gamepad from stdin on a background thread. Each line lists the keys held from then on,
+    /// so `wj` holds up and A, and an empty line lets go of everything.
+    pub fn spawn_stdin_feeder(self, state: Arc<Mutex<GamepadState>>) {
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                state.lock().unwrap().buttons = self.buttons_for(line.trim());
+            }
+        });
+    }
+}
+
+/// A gamepad with buttons, two axes and an interrupt for state changes.
+///
+/// Every change is stamped with the cycle it became visible to the guest at. Recording those and
+/// playing them back puts each change at exactly the same point in the program.
+pub struct Gamepad {
+    source: GamepadSource,
+    recorder: Option<InputRecorder>,
+    state: GamepadState,
+    control: u64,
+    status: u64,
+    interrupt_vector: u8,
+    clock: Clock,
+}
+
+impl Gamepad {
+    pub fn new(source: GamepadSource) -> Self {
+        Self {
+            source,
+            recorder: None,
+            state: GamepadState::default(),
+            control: 0,
+            status: 0,
+            interrupt_vector: 0,
+            clock: Clock::default(),
+        }
+    }
+
+    pub fn with_recorder(mut self, recorder: InputRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Starts with `vector` in the interrupt vector register instead of 0, as if the guest had set it.
+    pub fn with_interrupt_vector(mut self, vector: u8) -> Self {
+        self.interrupt_vector = vector;
+        self
+    }
+
+    /// Wakes the gamepad when the next recorded change is due.
+    fn schedule_replay(&self) {
+        if let GamepadSource::Replay(recording) = &self.source
+            && let Some(cycle) = recording.next_cycle()
+        {
+            self.clock.schedule_at(cycle, REPLAY_EVENT);
+        }
+    }
+
+    /// Shows `new_state` to the guest, recording it and raising the change interrupt if it differs from the last.
+    fn update(&mut self, new_state: GamepadState, signals: &mut Vec<MachineSignal>) {
+        if new_state == self.state {
+            return;
+        }
+
+        self.state = new_state;
+        if let Some(recorder) = &mut self.recorder {
+            let event = InputEvent {
+                cycle: self.clock.now(),
+                state: new_state,
+            };
+            if let Err(error) = recorder.record(event) {
+                log::error!("Gamepad: Failed to record input: {}", error);
+            }
+        }
+
+        self.status |= STATUS_CHANGED;
+        if self.control & CONTROL_CHANGE_INTERRUPT != 0 {
+            signals.push(MachineSignal::Interrupt(self.interrupt_vector));
+        }
+    }
+
+    fn read(&self, port: u16) -> u64 {
+        match port {
+            GAMEPAD_BUTTONS_PORT => self.state.buttons as u64,
+            GAMEPAD_AXIS_X_PORT => self.state.axes[0] as u16 as u64,
+            GAMEPAD_AXIS_Y_PORT => self.state.axes[1] as u16 as u64,
+            GAMEPAD_CONTROL_PORT => self.control,
+            GAMEPAD_STATUS_PORT => self.status,
+            GAMEPAD_INTERRUPT_VECTOR_PORT => self.interrupt_vector as u64,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u64) {
+        match port {
+            GAMEPAD_CONTROL_PORT => self.control = value & CONTROL_CHANGE_INTERRUPT,
+            GAMEPAD_STATUS_PORT => self.status &= !value,
+            GAMEPAD_INTERRUPT_VECTOR_PORT => self.interrupt_vector = value as u8,
+            _ => {}
+        }
+    }
+}
+
+impl IoDevice for Gamepad {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        self.read(port) as u8
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        self.read(port) as u16
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        self.read(port) as u32
+    }
+
+    fn read_u64(&mut self, port: u16) -> u64 {
+        self.read(port)
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u64(&mut self, port: u16, value: u64) {
+        self.write(port, value);
+    }
+
+    fn tick(&mut self, _memory_bus: &Mutex<MemoryBus48>, signals: &mut Vec<MachineSignal>) {
+        // The host can change live input at any moment, so it is sampled after every instruction
+        if let GamepadSource::Live(state) = &self.source {
+            let state = *state.lock().unwrap();
+            self.update(state, signals);
+        }
+    }
+
+    fn wants_tick(&self) -> bool {
+        // Replayed input comes in as events on the clock
+        matches!(self.source, GamepadSource::Live(_))
+    }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.schedule_replay();
+    }
+
+    fn handle_event(
+        &mut self,
+        _event: u64,
+        _memory_bus: &Mutex<MemoryBus48>,
+        signals: &mut Vec<MachineSignal>,
+    ) {
+        let now = self.clock.now();
+        if let GamepadSource::Replay(recording) = &mut self.source
+            && let Some(state) = recording.poll(now)
+        {
+            self.update(state, signals);
+        }
+        self.schedule_replay();
+    }
+}
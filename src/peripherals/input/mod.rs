@@ -0,0 +1,2 @@
+pub mod gamepad;
+pub mod recording;
\ No newline at end of file
@@ -0,0 +1,170 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::peripherals::input::gamepad::GamepadState;
+
+/// A gamepad state change stamped with the cycle it took effect at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub cycle: u64,
+    pub state: GamepadState,
+}
+
+/// Plays back a recording made by `InputRecorder`.
+///
+/// Recordings are text, one event per line: `<cycle> <buttons in hex> <x axis> <y axis>`.
+/// Blank lines and lines starting with `#` are skipped, so recordings can be written or annotated by hand.
+pub struct InputRecording {
+    events: Vec<InputEvent>,
+    next: usize,
+}
+
+impl InputRecording {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut events = Vec::new();
+        for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let event = Self::parse_event(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid input event on line {}: {}", index + 1, line),
+                )
+            })?;
+            if events
+                .last()
+                .is_some_and(|last: &InputEvent| last.cycle > event.cycle)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Input event on line {} goes back in time", index + 1),
+                ));
+            }
+            events.push(event);
+        }
+
+        Ok(Self { events, next: 0 })
+    }
+
+    fn parse_event(line: &str) -> Option<InputEvent> {
+        let mut fields = line.split_whitespace();
+        let event = InputEvent {
+            cycle: fields.next()?.parse().ok()?,
+            state: GamepadState {
+                buttons: u16::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?,
+                axes: [fields.next()?.parse().ok()?, fields.next()?.parse().ok()?],
+            },
+        };
+        fields.next().is_none().then_some(event)
+    }
+
+    /// The cycle the next change is due at, or `None` once the recording has played out.
+    pub fn next_cycle(&self) -> Option<u64> {
+        self.events.get(self.next).map(|event| event.cycle)
+    }
+
+    /// Returns the latest state due at `cycle`, if any event has come due since the last call.
+    pub fn poll(&mut self, cycle: u64) -> Option<GamepadState> {
+        let mut state = None;
+        while let Some(event) = self
+            .events
+            .get(self.next)
+            .filter(|event| event.cycle <= cycle)
+        {
+            state = Some(event.state);
+            self.next += 1;
+        }
+        state
+    }
+}
+
+/// Writes every state change the gamepad sees, so the session can be replayed with `InputRecording`.
+pub struct InputRecorder {
+    file: BufWriter<File>,
+}
+
+impl InputRecorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "# cycle buttons x y")?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, event: InputEvent) -> io::Result<()> {
+        writeln!(
+            self.file,
+            "{} {:04x} {} {}",
+            event.cycle, event.state.buttons, event.state.axes[0], event.state.axes[1]
+        )?;
+        // Flush straight away so the recording survives the emulator being killed
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("m64-recording-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn plays_back_what_was_recorded() {
+        let path = temp_path("round-trip");
+        let events = [
+            InputEvent {
+                cycle: 10,
+                state: GamepadState {
+                    buttons: 0x0001,
+                    axes: [0, 0],
+                },
+            },
+            InputEvent {
+                cycle: 20,
+                state: GamepadState {
+                    buttons: 0x8010,
+                    axes: [-32768, 32767],
+                },
+            },
+        ];
+        let mut recorder = InputRecorder::create(&path).unwrap();
+        for event in events {
+            recorder.record(event).unwrap();
+        }
+
+        let mut recording = InputRecording::load(&path).unwrap();
+        assert_eq!(recording.next_cycle(), Some(10));
+        assert_eq!(recording.poll(5), None);
+        assert_eq!(recording.poll(25), Some(events[1].state));
+        assert_eq!(recording.next_cycle(), None);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_malformed_recordings() {
+        let path = temp_path("malformed");
+        for recording in [
+            "10 0001 0 0 7\n",
+            "10 0001 0\n",
+            "ten 0001 0 0\n",
+            "10 0001 0 40000\n",
+            "20 0001 0 0\n10 0000 0 0\n",
+        ] {
+            fs::write(&path, format!("# cycle buttons x y\n\n{}", recording)).unwrap();
+            let error = InputRecording::load(&path).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", recording);
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
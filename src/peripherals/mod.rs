@@ -1 +1,6 @@
+pub mod audio;
+pub mod debug;
+pub mod input;
+pub mod network;
 pub mod storage;
+pub mod system;
\ No newline at end of file
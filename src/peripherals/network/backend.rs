@@ -0,0 +1,222 @@
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{fs::FileTypeExt, net::UnixDatagram},
+    path::PathBuf,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// The largest frame we accept from the host side.
+pub const MAX_FRAME_SIZE: usize = 65535;
+
+/// Where the frames of a virtual NIC go to and come from on the host.
+pub trait NetworkBackend: Send {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+    /// Returns the next received frame, or `None` if nothing is waiting. Must never block.
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Parses a backend description of the form `unix:<local socket>:<peer socket>` or `file:<rx file>:<tx file>`. Unix
+/// sockets are only available on unix hosts.
+pub fn backend_from_spec(spec: &str) -> io::Result<Box<dyn NetworkBackend>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid network backend: {}", spec),
+        )
+    };
+    let mut parts = spec.splitn(3, ':');
+    let (Some(kind), Some(first), Some(second)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+
+    match kind {
+        #[cfg(unix)]
+        "unix" => Ok(Box::new(UnixSocketBackend::new(first, second)?)),
+        #[cfg(not(unix))]
+        "unix" => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix socket network backends need a unix host",
+        )),
+        "file" => Ok(Box::new(FilePairBackend::new(first, second)?)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Exchanges frames as datagrams with another process on the same machine, such as a second emulator.
+#[cfg(unix)]
+pub struct UnixSocketBackend {
+    socket: UnixDatagram,
+    peer: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocketBackend {
+    /// Binds `local`, replacing a stale socket left over from an earlier run, and sends everything to `peer`. Anything
+    /// else already at `local` is left alone and refused.
+    pub fn new(local: impl AsRef<Path>, peer: impl AsRef<Path>) -> io::Result<Self> {
+        let local = local.as_ref();
+        match fs::symlink_metadata(local) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(local)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} already exists and isn't a socket", local),
+                ));
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        let socket = UnixDatagram::bind(local)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            peer: peer.as_ref().to_path_buf(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl NetworkBackend for UnixSocketBackend {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self.socket.send_to(frame, &self.peer) {
+            Ok(_) => Ok(()),
+            // Nobody is listening on the other end, which on a real wire just means the frame is lost
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::NotFound
+                        | io::ErrorKind::ConnectionRefused
+                        | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                log::debug!("Network: Dropped frame to {:?}: {}", self.peer, error);
+                Ok(())
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buffer = vec![0; MAX_FRAME_SIZE];
+        match self.socket.recv(&mut buffer) {
+            Ok(length) => {
+                buffer.truncate(length);
+                Ok(Some(buffer))
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Reads frames from one file and appends them to another, each prefixed with its length as a little endian u32.
+///
+/// The receive file is followed like `tail -f`, so a test harness can keep appending frames while the guest runs.
+pub struct FilePairBackend {
+    rx: File,
+    tx: File,
+}
+
+impl FilePairBackend {
+    pub fn new(rx: impl AsRef<Path>, tx: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            rx: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(rx)?,
+            tx: OpenOptions::new().append(true).create(true).open(tx)?,
+        })
+    }
+}
+
+impl NetworkBackend for FilePairBackend {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.tx.write_all(frame)
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let start = self.rx.stream_position()?;
+        let available = self.rx.metadata()?.len().saturating_sub(start);
+        if available < 4 {
+            return Ok(None);
+        }
+
+        let mut length = [0; 4];
+        self.rx.read_exact(&mut length)?;
+        let length = u32::from_le_bytes(length) as u64;
+        if length > MAX_FRAME_SIZE as u64 {
+            // Skip the frame rather than buffer it, so the frames after it can still be read
+            self.rx.seek(SeekFrom::Current(length as i64))?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes is larger than the {} a frame can be",
+                    length, MAX_FRAME_SIZE
+                ),
+            ));
+        }
+        if available < 4 + length {
+            // The rest of the frame hasn't been written yet, try again later
+            self.rx.seek(SeekFrom::Start(start))?;
+            return Ok(None);
+        }
+
+        let mut frame = vec![0; length as usize];
+        self.rx.read_exact(&mut frame)?;
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn skips_frames_that_are_too_large() {
+        let rx = std::env::temp_dir().join(format!("m64-nic-rx-{}", std::process::id()));
+        let tx = rx.with_extension("tx");
+        let oversized = MAX_FRAME_SIZE + 1;
+        let mut frames = (oversized as u32).to_le_bytes().to_vec();
+        frames.resize(4 + oversized, 0xAA);
+        frames.extend_from_slice(&3u32.to_le_bytes());
+        frames.extend_from_slice(b"abc");
+        fs::write(&rx, &frames).unwrap();
+
+        let mut backend = FilePairBackend::new(&rx, &tx).unwrap();
+        let error = backend.receive().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(backend.receive().unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(backend.receive().unwrap(), None);
+
+        fs::remove_file(rx).unwrap();
+        fs::remove_file(tx).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_replaces_stale_sockets() {
+        let local = std::env::temp_dir().join(format!("m64-nic-local-{}", std::process::id()));
+        let peer = local.with_extension("peer");
+
+        fs::write(&local, b"not a socket").unwrap();
+        let error = UnixSocketBackend::new(&local, &peer).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&local).unwrap(), b"not a socket");
+        fs::remove_file(&local).unwrap();
+
+        drop(UnixSocketBackend::new(&local, &peer).unwrap());
+        UnixSocketBackend::new(&local, &peer).unwrap();
+        fs::remove_file(local).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+use std::sync::Mutex;
+
+use crate::{
+    misc::{
+        io_bus::{IoDevice, MachineSignal},
+        memory_bus::MemoryBus48,
+        scheduler::Clock,
+    },
+    peripherals::network::backend::NetworkBackend,
+};
+
+/// Bit 0 enables the NIC, bit 1 enables the receive interrupt.
+pub const NIC_CONTROL_PORT: u16 = 0x0100;
+/// Bit 0 is set when frames have been received. Writing a 1 to a bit clears it.
+pub const NIC_STATUS_PORT: u16 = 0x0101;
+pub const NIC_TX_RING_PORT: u16 = 0x0102;
+pub const NIC_RX_RING_PORT: u16 = 0x0103;
+/// Number of descriptors in each ring. Changing it or either ring address rewinds both rings.
+pub const NIC_RING_SIZE_PORT: u16 = 0x0104;
+/// Doorbell. The guest writes the index one past the last descriptor it has filled in.
+pub const NIC_TX_TAIL_PORT: u16 = 0x0105;
+/// Doorbell. The guest writes the index one past the last empty buffer it is handing over.
+pub const NIC_RX_TAIL_PORT: u16 = 0x0106;
+/// Index of the next descriptor the NIC will use. Everything before it is done.
+pub const NIC_TX_HEAD_PORT: u16 = 0x0107;
+pub const NIC_RX_HEAD_PORT: u16 = 0x0108;
+pub const NIC_INTERRUPT_VECTOR_PORT: u16 = 0x0109;
+
+pub const NIC_PORTS: [u16; 10] = [
+    NIC_CONTROL_PORT,
+    NIC_STATUS_PORT,
+    NIC_TX_RING_PORT,
+    NIC_RX_RING_PORT,
+    NIC_RING_SIZE_PORT,
+    NIC_TX_TAIL_PORT,
+    NIC_RX_TAIL_PORT,
+    NIC_TX_HEAD_PORT,
+    NIC_RX_HEAD_PORT,
+    NIC_INTERRUPT_VECTOR_PORT,
+];
+
+const CONTROL_ENABLE: u64 = 0b1;
+const CONTROL_RX_INTERRUPT: u64 = 0b10;
+
+const STATUS_RX: u64 = 0b1;
+
+/// Size of a ring descriptor.
+///
+/// ```text
+/// 0x00 u64 buffer address
+/// 0x08 u32 length. Frame length for TX, buffer capacity for RX (replaced by the frame length once filled)
+/// 0x0C u32 flags, written by the NIC
+/// ```
+pub const DESCRIPTOR_SIZE: u64 = 16;
+pub const DESCRIPTOR_DONE: u32 = 0b1;
+/// The received frame didn't fit in the buffer and was cut short.
+pub const DESCRIPTOR_TRUNCATED: u32 = 0b10;
+
+/// Polling the host for frames is a syscall, so we only do it every this many cycles.
+const RX_POLL_INTERVAL: u64 = 1024;
+
+const TX_EVENT: u64 = 0;
+const RX_POLL_EVENT: u64 = 1;
+
+/// A virtual network card with descriptor rings in guest memory.
+///
+/// Frames never touch a real network, they go to whatever `NetworkBackend` the host plugged in.
+pub struct Nic {
+    backend: Box<dyn NetworkBackend>,
+    control: u64,
+    status: u64,
+    tx_ring: u64,
+    rx_ring: u64,
+    ring_size: u64,
+    tx_head: u64,
+    tx_tail: u64,
+    rx_head: u64,
+    rx_tail: u64,
+    interrupt_vector: u8,
+    clock: Clock,
+}
+
+impl Nic {
+    pub fn new(backend: Box<dyn NetworkBackend>) -> Self {
+        Self {
+            backend,
+            control: 0,
+            status: 0,
+            tx_ring: 0,
+            rx_ring: 0,
+            ring_size: 0,
+            tx_head: 0,
+            tx_tail: 0,
+            rx_head: 0,
+            rx_tail: 0,
+            interrupt_vector: 0,
+            clock: Clock::default(),
+        }
+    }
+
+    /// Starts with `vector` in the interrupt vector register instead of 0, as if the guest had set it.
+    pub fn with_interrupt_vector(mut self, vector: u8) -> Self {
+        self.interrupt_vector = vector;
+        self
+    }
+
+    /// Sends the queued frames once the instruction that rang the doorbell retires.
+    fn schedule_transmit(&self) {
+        self.clock.cancel(TX_EVENT);
+        self.clock.schedule_in(0, TX_EVENT);
+    }
+
+    fn enabled(&self) -> bool {
+        self.control & CONTROL_ENABLE != 0 && self.ring_size != 0
+    }
+
+    fn rewind_rings(&mut self) {
+        self.tx_head = 0;
+        self.tx_tail = 0;
+        self.rx_head = 0;
+        self.rx_tail = 0;
+    }
+
+    fn read(&self, port: u16) -> u64 {
+        match port {
+            NIC_CONTROL_PORT => self.control,
+            NIC_STATUS_PORT => self.status,
+            NIC_TX_RING_PORT => self.tx_ring,
+            NIC_RX_RING_PORT => self.rx_ring,
+            NIC_RING_SIZE_PORT => self.ring_size,
+            NIC_TX_TAIL_PORT => self.tx_tail,
+            NIC_RX_TAIL_PORT => self.rx_tail,
+            NIC_TX_HEAD_PORT => self.tx_head,
+            NIC_RX_HEAD_PORT => self.rx_head,
+            NIC_INTERRUPT_VECTOR_PORT => self.interrupt_vector as u64,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u64) {
+        match port {
+            NIC_CONTROL_PORT => {
+                self.control = value & (CONTROL_ENABLE | CONTROL_RX_INTERRUPT);
+                // Frames queued while the NIC was off go out as soon as it is turned on
+                self.schedule_transmit();
+            }
+            NIC_STATUS_PORT => self.status &= !value,
+            NIC_TX_RING_PORT => {
+                self.tx_ring = value;
+                self.rewind_rings();
+            }
+            NIC_RX_RING_PORT => {
+                self.rx_ring = value;
+                self.rewind_rings();
+            }
+            NIC_RING_SIZE_PORT => {
+                self.ring_size = value;
+                self.rewind_rings();
+            }
+            NIC_TX_TAIL_PORT if value < self.ring_size => {
+                self.tx_tail = value;
+                self.schedule_transmit();
+            }
+            NIC_RX_TAIL_PORT if value < self.ring_size => self.rx_tail = value,
+            NIC_TX_TAIL_PORT | NIC_RX_TAIL_PORT => {
+                log::error!(
+                    "NIC: Doorbell index {} is outside a ring of {} descriptors.",
+                    value,
+                    self.ring_size
+                );
+            }
+            NIC_INTERRUPT_VECTOR_PORT => self.interrupt_vector = value as u8,
+            _ => {}
+        }
+    }
+
+    /// Reads descriptor `index` of the ring at `ring`, returning its address, buffer and length. The ring and index
+    /// come from the guest, so the descriptor can be anywhere at all, including past the top of the address space.
+    fn read_descriptor(memory_bus: &MemoryBus48, ring: u64, index: u64) -> Option<(u64, u64, u32)> {
+        let address = index
+            .checked_mul(DESCRIPTOR_SIZE)
+            .and_then(|offset| ring.checked_add(offset))?;
+        let descriptor = memory_bus.read_bytes(address, DESCRIPTOR_SIZE as usize);
+        if descriptor.len() != DESCRIPTOR_SIZE as usize {
+            return None;
+        }
+        Some((
+            address,
+            u64::from_le_bytes(descriptor[0..8].try_into().unwrap()),
+            u32::from_le_bytes(descriptor[8..12].try_into().unwrap()),
+        ))
+    }
+
+    fn transmit(&mut self, memory_bus: &Mutex<MemoryBus48>) {
+        while self.tx_head != self.tx_tail {
+            let mut memory_bus = memory_bus.lock().unwrap();
+            let Some((descriptor_address, buffer, length)) =
+                Self::read_descriptor(&memory_bus, self.tx_ring, self.tx_head)
+            else {
+                log::error!(
+                    "NIC: TX descriptor {} of the ring at {:#X} is out of bounds. Disabling.",
+                    self.tx_head,
+                    self.tx_ring
+                );
+                self.control = 0;
+                return;
+            };
+
+            let frame = memory_bus.read_bytes(buffer, length as usize);
+            if frame.len() == length as usize {
+                if let Err(error) = self.backend.send(frame) {
+                    log::error!("NIC: Failed to send frame: {}", error);
+                }
+            } else {
+                log::error!(
+                    "NIC: TX buffer at {:#X} is out of bounds. Frame dropped.",
+                    buffer
+                );
+            }
+
+            memory_bus.write_bytes(descriptor_address + 12, &DESCRIPTOR_DONE.to_le_bytes());
+            self.tx_head = (self.tx_head + 1) % self.ring_size;
+        }
+    }
+
+    fn receive(&mut self, memory_bus: &Mutex<MemoryBus48>, signals: &mut Vec<MachineSignal>) {
+        let mut received = false;
+        while self.rx_head != self.rx_tail {
+            // The descriptor is checked before the frame is taken, so a bad ring leaves it waiting in the backend
+            let mut memory_bus = memory_bus.lock().unwrap();
+            let Some((descriptor_address, buffer, capacity)) =
+                Self::read_descriptor(&memory_bus, self.rx_ring, self.rx_head)
+            else {
+                log::error!(
+                    "NIC: RX descriptor {} of the ring at {:#X} is out of bounds. Disabling.",
+                    self.rx_head,
+                    self.rx_ring
+                );
+                self.control = 0;
+                return;
+            };
+
+            let frame = match self.backend.receive() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(error) => {
+                    log::error!("NIC: Failed to receive frame: {}", error);
+                    break;
+                }
+            };
+
+            let mut flags = DESCRIPTOR_DONE;
+            let length = if frame.len() > capacity as usize {
+                flags |= DESCRIPTOR_TRUNCATED;
+                capacity
+            } else {
+                frame.len() as u32
+            };
+            memory_bus.write_bytes(buffer, &frame[..length as usize]);
+            memory_bus.write_bytes(descriptor_address + 8, &length.to_le_bytes());
+            memory_bus.write_bytes(descriptor_address + 12, &flags.to_le_bytes());
+
+            self.rx_head = (self.rx_head + 1) % self.ring_size;
+            received = true;
+        }
+
+        if received {
+            self.status |= STATUS_RX;
+            if self.control & CONTROL_RX_INTERRUPT != 0 {
+                signals.push(MachineSignal::Interrupt(self.interrupt_vector));
+            }
+        }
+    }
+}
+
+impl IoDevice for Nic {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        self.read(port) as u8
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        self.read(port) as u16
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        self.read(port) as u32
+    }
+
+    fn read_u64(&mut self, port: u16) -> u64 {
+        self.read(port)
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u64(&mut self, port: u16, value: u64) {
+        self.write(port, value);
+    }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.clock.schedule_in(RX_POLL_INTERVAL, RX_POLL_EVENT);
+        self.schedule_transmit();
+    }
+
+    fn handle_event(
+        &mut self,
+        event: u64,
+        memory_bus: &Mutex<MemoryBus48>,
+        signals: &mut Vec<MachineSignal>,
+    ) {
+        match event {
+            TX_EVENT if self.enabled() => self.transmit(memory_bus),
+            RX_POLL_EVENT => {
+                if self.enabled() {
+                    self.receive(memory_bus, signals);
+                }
+                self.clock.schedule_in(RX_POLL_INTERVAL, RX_POLL_EVENT);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    struct Loopback(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl NetworkBackend for Loopback {
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().push(frame.to_vec());
+            Ok(())
+        }
+
+        fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().pop())
+        }
+    }
+
+    fn nic(tx_ring: u64) -> (Nic, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let mut nic = Nic::new(Box::new(Loopback(frames.clone())));
+        nic.write_u64(NIC_TX_RING_PORT, tx_ring);
+        nic.write_u64(NIC_RING_SIZE_PORT, 4);
+        nic.write_u64(NIC_CONTROL_PORT, CONTROL_ENABLE);
+        nic.write_u64(NIC_TX_TAIL_PORT, 1);
+        (nic, frames)
+    }
+
+    #[test]
+    fn transmits_frames_from_the_ring() {
+        let memory_bus = Mutex::new(MemoryBus48::new());
+        {
+            let mut memory_bus = memory_bus.lock().unwrap();
+            memory_bus.write_bytes(0x100, &0x200u64.to_le_bytes());
+            memory_bus.write_bytes(0x108, &3u32.to_le_bytes());
+            memory_bus.write_bytes(0x200, b"abc");
+        }
+        let (mut nic, frames) = nic(0x100);
+        nic.handle_event(TX_EVENT, &memory_bus, &mut Vec::new());
+
+        assert_eq!(*frames.lock().unwrap(), [b"abc".to_vec()]);
+        assert_eq!(nic.read_u64(NIC_TX_HEAD_PORT), 1);
+        assert_eq!(
+            memory_bus.lock().unwrap().read_bytes(0x10C, 4),
+            DESCRIPTOR_DONE.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn disables_itself_on_rings_past_the_address_space() {
+        let memory_bus = Mutex::new(MemoryBus48::new());
+        let (mut nic, frames) = nic(u64::MAX - 8);
+        nic.write_u64(NIC_TX_TAIL_PORT, 3);
+        nic.handle_event(TX_EVENT, &memory_bus, &mut Vec::new());
+
+        assert!(frames.lock().unwrap().is_empty());
+        assert_eq!(nic.read_u64(NIC_CONTROL_PORT), 0);
+    }
+    #[test]
+    fn leaves_frames_waiting_on_a_bad_rx_ring() {
+        let memory_bus = Mutex::new(MemoryBus48::new());
+        let (mut nic, frames) = nic(0x100);
+        frames.lock().unwrap().push(b"abc".to_vec());
+        nic.write_u64(NIC_RX_RING_PORT, u64::MAX - 8);
+        nic.write_u64(NIC_RX_TAIL_PORT, 1);
+        nic.handle_event(RX_POLL_EVENT, &memory_bus, &mut Vec::new());
+
+        assert_eq!(*frames.lock().unwrap(), [b"abc".to_vec()]);
+        assert_eq!(nic.read_u64(NIC_CONTROL_PORT), 0);
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::Mutex;
+
+use crate::misc::{
+    io_bus::{IoDevice, MachineSignal},
+    memory_bus::MemoryBus48,
+};
+
+/// Bank-select register. Writing picks the bank shown in the switchable half of the ROM window, reading returns it.
+pub const BANK_SELECT_PORT: u16 = 0x0130;
+/// Read-only, the number of banks on the cartridge.
+pub const BANK_COUNT_PORT: u16 = 0x0131;
+
+pub const BANK_CONTROLLER_PORTS: [u16; 2] = [BANK_SELECT_PORT, BANK_COUNT_PORT];
+
+/// The bank-select register of a cartridge with a banked mapper.
+///
+/// A new bank is switched in after the `out` instruction retires, so the very next instruction already sees it.
+/// Selecting a bank the cartridge doesn't have is ignored and the old bank stays in place.
+pub struct BankController {
+    rom_base: u64,
+    bank_count: u64,
+    selected: u64,
+    pending: Option<u64>,
+}
+
+impl BankController {
+    /// Controls the banked ROM mapped at `rom_base`, which starts out on bank 0.
+    pub fn new(rom_base: u64, bank_count: u64) -> Self {
+        Self {
+            rom_base,
+            bank_count,
+            selected: 0,
+            pending: None,
+        }
+    }
+
+    fn read(&self, port: u16) -> u64 {
+        match port {
+            BANK_SELECT_PORT => self.selected,
+            BANK_COUNT_PORT => self.bank_count,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u64) {
+        if port == BANK_SELECT_PORT {
+            self.pending = Some(value);
+        }
+    }
+}
+
+impl IoDevice for BankController {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        self.read(port) as u8
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        self.read(port) as u16
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        self.read(port) as u32
+    }
+
+    fn read_u64(&mut self, port: u16) -> u64 {
+        self.read(port)
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u64(&mut self, port: u16, value: u64) {
+        self.write(port, value);
+    }
+
+    fn tick(&mut self, memory_bus: &Mutex<MemoryBus48>, _signals: &mut Vec<MachineSignal>) {
+        let Some(bank) = self.pending.take() else {
+            return;
+        };
+        if bank < self.bank_count
+            && memory_bus
+                .lock()
+                .unwrap()
+                .select_rom_bank(self.rom_base, bank)
+        {
+            self.selected = bank;
+        } else {
+            log::warn!(
+                "Bank Controller: Bank {} doesn't exist, the cartridge has {}.",
+                bank,
+                self.bank_count
+            );
+        }
+    }
+
+    fn wants_tick(&self) -> bool {
+        true
+    }
+}
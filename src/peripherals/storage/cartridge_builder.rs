@@ -0,0 +1,395 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::SigningKey;
+use serde::Deserialize;
+
+use crate::{
+    misc::{
+        memory_bus::{MEMORY_ALL, parse_permissions},
+        symbols::{Symbol, SymbolTable},
+    },
+    peripherals::storage::{
+        monad_boot_cartridge::{
+            CartridgeMapper, CartridgeSegment, LATEST_CARTRIDGE_REVISION, MonadBootCartridge,
+            SEGMENT_EXECUTE, SEGMENT_READ, SEGMENT_WRITE,
+        },
+        verification::{SignatureStatus, sign_image},
+    },
+};
+
+/// Describes a cartridge to build, read from a TOML file.
+///
+/// ```text
+/// title = "Demo"
+/// revision = 2
+/// entry_point = 0x1000_0000
+/// stack_pointer = 0x800
+/// save_ram_size = 0x100
+/// mapper = "none"
+/// symbols_file = "code.tags"
+///
+/// [[segments]]
+/// load_address = 0x1000_0000
+/// file = "code.bin"
+/// flags = "rx"
+///
+/// [[segments]]
+/// load_address = 0x400
+/// file = "data.bin"
+/// zero_fill = 0x100
+/// flags = "rw"
+///
+/// [[symbols]]
+/// name = "main"
+/// address = 0x1000_0000
+/// kind = "function"
+/// ```
+///
+/// Segment and symbol files are relative to the manifest. The symbols file is a `.tags` file like the one written
+/// next to every cartridge, such as one produced by the toolchain, and its symbols are added to any listed inline.
+/// Revision 0 and 1 cartridges take exactly one segment, and in revision 1 its load address becomes the header's
+/// load address.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CartridgeManifest {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default = "latest_revision")]
+    pub revision: u8,
+    #[serde(default)]
+    pub entry_point: u64,
+    #[serde(default)]
+    pub stack_pointer: u64,
+    #[serde(default)]
+    pub save_ram_size: u32,
+    #[serde(default)]
+    pub mapper: CartridgeMapper,
+    pub segments: Vec<ManifestSegment>,
+    #[serde(default)]
+    pub symbols: Vec<Symbol>,
+    #[serde(default)]
+    pub symbols_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestSegment {
+    pub load_address: u64,
+    pub file: PathBuf,
+    #[serde(default)]
+    pub zero_fill: u32,
+    /// Any of `r`, `w` and `x`.
+    #[serde(default = "all_flags")]
+    pub flags: String,
+}
+
+fn latest_revision() -> u8 {
+    LATEST_CARTRIDGE_REVISION
+}
+
+fn all_flags() -> String {
+    "rwx".to_string()
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Manifest(toml::de::Error),
+    /// The manifest parsed but describes a cartridge that can't be built.
+    Invalid(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            BuildError::Manifest(error) => write!(f, "bad manifest: {}", error),
+            BuildError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl CartridgeManifest {
+    pub fn parse(manifest: &str) -> Result<Self, BuildError> {
+        toml::from_str(manifest).map_err(BuildError::Manifest)
+    }
+
+    /// Reads a manifest, resolving its segment files against the directory it is in.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BuildError> {
+        let path = path.as_ref();
+        let manifest = fs::read_to_string(path).map_err(|error| BuildError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let mut manifest = Self::parse(&manifest)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for segment in &mut manifest.segments {
+            segment.file = directory.join(&segment.file);
+        }
+        if let Some(symbols_file) = &mut manifest.symbols_file {
+            *symbols_file = directory.join(&*symbols_file);
+        }
+        Ok(manifest)
+    }
+
+    /// Builds the cartridge, checking it loads back the same way before handing it over.
+    pub fn build(&self) -> Result<MonadBootCartridge, BuildError> {
+        if self.revision > LATEST_CARTRIDGE_REVISION {
+            return Err(BuildError::Invalid(format!(
+                "revision {} is newer than the latest, {}",
+                self.revision, LATEST_CARTRIDGE_REVISION
+            )));
+        }
+        if self.revision < 2 && self.segments.len() != 1 {
+            return Err(BuildError::Invalid(format!(
+                "revision {} cartridges take exactly one segment, the manifest has {}",
+                self.revision,
+                self.segments.len()
+            )));
+        }
+
+        let mut segments = Vec::new();
+        for segment in &self.segments {
+            let data = fs::read(&segment.file).map_err(|error| BuildError::Io {
+                path: segment.file.clone(),
+                error,
+            })?;
+            segments.push(CartridgeSegment {
+                load_address: segment.load_address,
+                data,
+                zero_fill: segment.zero_fill,
+                flags: parse_flags(&segment.flags)?,
+            });
+        }
+
+        let mut cartridge = MonadBootCartridge::new(&[]);
+        cartridge.segments = segments;
+        cartridge.revision = self.revision;
+        cartridge.entry_point = self.entry_point;
+        cartridge.stack_pointer = self.stack_pointer;
+        cartridge.title = self.title.clone();
+        cartridge.mapper = self.mapper;
+        cartridge.save_ram_size = self.save_ram_size;
+
+        let image = cartridge.to_bytes()?;
+        let mut built = MonadBootCartridge::from_bytes(&image)
+            .map_err(|error| BuildError::Invalid(format!("built an invalid image: {}", error)))?;
+        let mut symbols = self.symbols.clone();
+        if let Some(symbols_file) = &self.symbols_file {
+            let table = SymbolTable::load(symbols_file).map_err(|error| {
+                BuildError::Invalid(format!("{}: {}", symbols_file.display(), error))
+            })?;
+            symbols.extend_from_slice(table.symbols());
+        }
+        if !symbols.is_empty() {
+            built = built.with_symbols(SymbolTable::new(symbols));
+        }
+        Ok(built)
+    }
+}
+
+fn parse_flags(flags: &str) -> Result<u32, BuildError> {
+    parse_permissions(flags)
+        .map(u32::from)
+        .ok_or_else(|| BuildError::Invalid(format!("unknown segment flags {:?}", flags)))
+}
+
+fn format_flags(flags: u32) -> String {
+    [
+        (SEGMENT_READ, 'r'),
+        (SEGMENT_WRITE, 'w'),
+        (SEGMENT_EXECUTE, 'x'),
+    ]
+    .iter()
+    .map(|&(bit, name)| if flags & bit != 0 { name } else { '-' })
+    .collect()
+}
+
+/// Writes the cartridge image to `path`, signed with `signing_key` if given, and its symbols to the `.tags` file next
+/// to it.
+pub fn write_cartridge(
+    cartridge: &MonadBootCartridge,
+    path: impl AsRef<Path>,
+    signing_key: Option<&SigningKey>,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut image = cartridge
+        .to_bytes()
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))?;
+    if let Some(key) = signing_key {
+        if cartridge.get_revision() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "revision 0 cartridges have no header to hold a signature",
+            ));
+        }
+        sign_image(&mut image, key);
+    }
+    fs::write(path, image)?;
+
+    let mut tags_path = path.as_os_str().to_owned();
+    tags_path.push(".tags");
+    let symbols = cartridge
+        .get_symbols()
+        .map_or_else(SymbolTable::default, |symbols| (**symbols).clone());
+    fs::write(tags_path, symbols.to_json())
+}
+
+/// A human readable summary of everything in the cartridge.
+pub fn inspect(cartridge: &MonadBootCartridge) -> String {
+    let mut report = String::new();
+    let mut line = |text: String| {
+        report.push_str(&text);
+        report.push('\n');
+    };
+
+    line(format!("Title:         {}", cartridge.get_title()));
+    line(format!("Revision:      {}", cartridge.get_revision()));
+    line(format!("Entry point:   {:#X}", cartridge.get_entry_point()));
+    line(format!(
+        "Stack pointer: {:#X}",
+        cartridge.get_stack_pointer()
+    ));
+    line(format!("Mapper:        {:?}", cartridge.get_mapper()));
+    line(format!(
+        "Signature:     {}",
+        match cartridge.get_signature_status() {
+            SignatureStatus::Unsigned => "none",
+            SignatureStatus::Unchecked => "Ed25519, not checked against any key",
+            SignatureStatus::Untrusted => "Ed25519, not by a trusted key",
+            SignatureStatus::Verified(_) => "Ed25519, verified",
+        }
+    ));
+    line(format!(
+        "Save RAM:      {:#X} bytes",
+        cartridge.get_save_ram_size()
+    ));
+    line(format!("Segments:      {}", cartridge.get_segments().len()));
+    for (index, segment) in cartridge.get_segments().iter().enumerate() {
+        line(format!(
+            "  {:>3}  {:#018X}  {:#10X} bytes  +{:#X} zeroed  {}",
+            index,
+            segment.load_address,
+            segment.data.len(),
+            segment.zero_fill,
+            format_flags(segment.flags)
+        ));
+    }
+    if let Some(symbols) = cartridge.get_symbols() {
+        line(format!("Symbols:       {}", symbols.symbols().len()));
+        for symbol in symbols.symbols() {
+            line(format!(
+                "  {:#018X}  {:#8X}  {:<8}  {}",
+                symbol.address,
+                symbol.size,
+                // Debug output ignores the width, so it has to be padded as a string
+                format!("{:?}", symbol.kind),
+                symbol.name
+            ));
+        }
+    }
+    report
+}
+
+/// Checks for mistakes that still make a well-formed image, returning a description of each one found.
+pub fn validate(cartridge: &MonadBootCartridge) -> Vec<String> {
+    let mut problems = Vec::new();
+    let segments = cartridge.get_segments();
+    let end = |segment: &CartridgeSegment| {
+        segment
+            .load_address
+            .saturating_add(segment.data.len() as u64 + segment.zero_fill as u64)
+    };
+
+    let entry_point = cartridge.get_entry_point();
+    if !segments.iter().any(|segment| {
+        segment.flags & SEGMENT_EXECUTE != 0
+            && (segment.load_address..end(segment)).contains(&entry_point)
+    }) {
+        problems.push(format!(
+            "entry point {:#X} is not in an executable segment",
+            entry_point
+        ));
+    }
+
+    for (index, segment) in segments.iter().enumerate() {
+        if segment.flags & !(MEMORY_ALL as u32) != 0 {
+            problems.push(format!(
+                "segment {} has unknown flags {:#X}",
+                index, segment.flags
+            ));
+        }
+        for (other_index, other) in segments.iter().enumerate().skip(index + 1) {
+            if segment.load_address < end(other) && other.load_address < end(segment) {
+                problems.push(format!("segments {} and {} overlap", index, other_index));
+            }
+        }
+    }
+
+    if let Some(symbols) = cartridge.get_symbols() {
+        for symbol in symbols.symbols() {
+            if !segments
+                .iter()
+                .any(|segment| (segment.load_address..end(segment)).contains(&symbol.address))
+            {
+                problems.push(format!(
+                    "symbol {} at {:#X} is outside every segment",
+                    symbol.name, symbol.address
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_symbols_from_a_tags_file_next_to_the_manifest() {
+        let directory = std::env::temp_dir().join(format!("m64-manifest-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("code.bin"), [0x0D, 0x03, 0, 0, 0, 0, 0, 0]).unwrap();
+        fs::write(
+            directory.join("code.tags"),
+            r#"[{ "name": "start", "address": 4096, "kind": "function" }]"#,
+        )
+        .unwrap();
+        let manifest_path = directory.join("cartridge.toml");
+        fs::write(
+            &manifest_path,
+            r#"
+            entry_point = 0x1000
+            symbols_file = "code.tags"
+
+            [[segments]]
+            load_address = 0x1000
+            file = "code.bin"
+
+            [[symbols]]
+            name = "end"
+            address = 0x1008
+            "#,
+        )
+        .unwrap();
+
+        let cartridge = CartridgeManifest::load(&manifest_path)
+            .unwrap()
+            .build()
+            .unwrap();
+        let symbols = cartridge.get_symbols().unwrap();
+        assert_eq!(symbols.find("start").unwrap().address, 0x1000);
+        assert_eq!(symbols.find("end").unwrap().address, 0x1008);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
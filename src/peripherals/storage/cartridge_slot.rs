@@ -0,0 +1,178 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use crate::{
+    misc::{
+        io_bus::{IoDevice, MachineSignal},
+        memory_bus::MemoryBus48,
+        scheduler::Clock,
+    },
+    peripherals::storage::monad_boot_cartridge::MonadBootCartridge,
+};
+
+/// Bit 0 enables the insertion/removal interrupt.
+pub const CARTRIDGE_SLOT_CONTROL_PORT: u16 = 0x0134;
+/// Bit 0 is set while a cartridge is inserted. Bit 1 is set when a cartridge was inserted or removed, writing a 1
+/// clears it.
+pub const CARTRIDGE_SLOT_STATUS_PORT: u16 = 0x0135;
+pub const CARTRIDGE_SLOT_INTERRUPT_VECTOR_PORT: u16 = 0x0136;
+
+pub const CARTRIDGE_SLOT_PORTS: [u16; 3] = [
+    CARTRIDGE_SLOT_CONTROL_PORT,
+    CARTRIDGE_SLOT_STATUS_PORT,
+    CARTRIDGE_SLOT_INTERRUPT_VECTOR_PORT,
+];
+
+pub const SLOT_STATUS_INSERTED: u64 = 0b1;
+pub const SLOT_STATUS_CHANGED: u64 = 0b10;
+
+const CONTROL_CHANGE_INTERRUPT: u64 = 0b1;
+
+const SWAP_EVENT: u64 = 0;
+
+/// The slot the boot cartridge sits in, which lets the host swap cartridges while the machine runs.
+///
+/// The host queues a swap with `insert` or `eject`, from any thread, or schedules one for a given cycle so a test
+/// plays out the same way every run. The motherboard carries out the swap between instructions, remapping
+/// the cartridge's ROM and save RAM, and the guest then sees the new status and gets the change interrupt if enabled.
+#[derive(Default)]
+pub struct CartridgeSlot {
+    /// `Some(None)` is a queued removal.
+    pending: Option<Option<MonadBootCartridge>>,
+    scheduled: VecDeque<(u64, Option<MonadBootCartridge>)>,
+    control: u64,
+    status: u64,
+    interrupt_vector: u8,
+    notify: bool,
+    clock: Clock,
+}
+
+impl CartridgeSlot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `cartridge` to replace whatever is in the slot.
+    pub fn insert(&mut self, cartridge: MonadBootCartridge) {
+        self.pending = Some(Some(cartridge));
+    }
+
+    /// Queues the removal of the cartridge in the slot.
+    pub fn eject(&mut self) {
+        self.pending = Some(None);
+    }
+
+    /// Queues a swap once the machine reaches `cycle`. `None` ejects the cartridge.
+    pub fn schedule(&mut self, cycle: u64, cartridge: Option<MonadBootCartridge>) {
+        let index = self.scheduled.partition_point(|(at, _)| *at <= cycle);
+        self.scheduled.insert(index, (cycle, cartridge));
+        self.clock.schedule_at(cycle, SWAP_EVENT);
+    }
+
+    /// Hands the queued swap over to the motherboard. `Some(None)` means the slot should be emptied.
+    pub fn take_pending(&mut self) -> Option<Option<MonadBootCartridge>> {
+        self.pending.take()
+    }
+
+    /// Called by the motherboard once a swap is done. `notify` raises the change for the guest to see, which is
+    /// skipped for the cartridge the machine boots with.
+    pub fn set_inserted(&mut self, inserted: bool, notify: bool) {
+        self.status &= !SLOT_STATUS_INSERTED;
+        if inserted {
+            self.status |= SLOT_STATUS_INSERTED;
+        }
+        if notify {
+            self.status |= SLOT_STATUS_CHANGED;
+            self.notify = true;
+        }
+    }
+
+    fn read(&self, port: u16) -> u64 {
+        match port {
+            CARTRIDGE_SLOT_CONTROL_PORT => self.control,
+            CARTRIDGE_SLOT_STATUS_PORT => self.status,
+            CARTRIDGE_SLOT_INTERRUPT_VECTOR_PORT => self.interrupt_vector as u64,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u64) {
+        match port {
+            CARTRIDGE_SLOT_CONTROL_PORT => self.control = value & CONTROL_CHANGE_INTERRUPT,
+            CARTRIDGE_SLOT_STATUS_PORT => self.status &= !(value & SLOT_STATUS_CHANGED),
+            CARTRIDGE_SLOT_INTERRUPT_VECTOR_PORT => self.interrupt_vector = value as u8,
+            _ => {}
+        }
+    }
+}
+
+impl IoDevice for CartridgeSlot {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        self.read(port) as u8
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        self.read(port) as u16
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        self.read(port) as u32
+    }
+
+    fn read_u64(&mut self, port: u16) -> u64 {
+        self.read(port)
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u64(&mut self, port: u16, value: u64) {
+        self.write(port, value);
+    }
+
+    fn tick(&mut self, _memory_bus: &Mutex<MemoryBus48>, signals: &mut Vec<MachineSignal>) {
+        // The host can queue a swap from another thread at any moment, so it is checked after every instruction
+        if self.pending.is_some() {
+            signals.push(MachineSignal::CartridgeSwap);
+        }
+        if self.notify {
+            self.notify = false;
+            if self.control & CONTROL_CHANGE_INTERRUPT != 0 {
+                signals.push(MachineSignal::Interrupt(self.interrupt_vector));
+            }
+        }
+    }
+
+    fn wants_tick(&self) -> bool {
+        true
+    }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        for (cycle, _) in &self.scheduled {
+            self.clock.schedule_at(*cycle, SWAP_EVENT);
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        _event: u64,
+        _memory_bus: &Mutex<MemoryBus48>,
+        signals: &mut Vec<MachineSignal>,
+    ) {
+        let now = self.clock.now();
+        if self.scheduled.front().is_some_and(|(at, _)| *at <= now) {
+            let (_, cartridge) = self.scheduled.pop_front().unwrap();
+            self.pending = Some(cartridge);
+            signals.push(MachineSignal::CartridgeSwap);
+        }
+    }
+}
@@ -0,0 +1,712 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::{
+    misc::{
+        memory_bus::MEMORY_ALL,
+        symbols::{Symbol, SymbolKind, SymbolTable},
+    },
+    peripherals::storage::monad_boot_cartridge::{
+        CartridgeSegment, LATEST_CARTRIDGE_REVISION, MonadBootCartridge, SEGMENT_EXECUTE,
+        SEGMENT_READ, SEGMENT_WRITE,
+    },
+};
+
+pub const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+/// There is no machine number assigned to the Monarch64, so this is ours. `EM_NONE` is accepted as well.
+pub const ELF_MACHINE_MONARCH64: u16 = 0x4D40;
+
+const ELF_HEADER_SIZE: usize = 0x40;
+const ELF_PROGRAM_HEADER_SIZE: usize = 0x38;
+const ELF_SECTION_HEADER_SIZE: usize = 0x40;
+const ELF_SYMBOL_SIZE: usize = 0x18;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_NONE: u16 = 0;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 0b1;
+const PF_W: u32 = 0b10;
+const PF_R: u32 = 0b100;
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_NOTYPE: u8 = 0;
+const SHN_UNDEF: u16 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecutableError {
+    /// The ELF file isn't one we can load, or its headers point outside the file.
+    BadElf(&'static str),
+    /// A line in a HEX or S-record image is malformed.
+    BadRecord { line: usize, reason: &'static str },
+}
+
+impl fmt::Display for ExecutableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutableError::BadElf(reason) => write!(f, "bad ELF file: {}", reason),
+            ExecutableError::BadRecord { line, reason } => {
+                write!(f, "bad record on line {}: {}", line, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExecutableError {}
+
+/// The formats other than our own cartridges that an image can be loaded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ExecutableFormat {
+    #[serde(rename = "elf")]
+    Elf,
+    #[serde(rename = "hex")]
+    IntelHex,
+    #[serde(rename = "srec")]
+    SRecord,
+}
+
+impl FromStr for ExecutableFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "elf" => Ok(ExecutableFormat::Elf),
+            "hex" => Ok(ExecutableFormat::IntelHex),
+            "srec" => Ok(ExecutableFormat::SRecord),
+            _ => Err(format!(
+                "unknown image format {:?}, expected elf, hex or srec",
+                format
+            )),
+        }
+    }
+}
+
+impl ExecutableFormat {
+    /// Recognises ELF files by their magic number. HEX and S-record files are text that a raw image can happen to
+    /// look like, such as one starting with `:` or `S1`, so they are only ever loaded by extension or when asked for.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        data.starts_with(&ELF_MAGIC)
+            .then_some(ExecutableFormat::Elf)
+    }
+
+    /// The format the extension of `path` stands for, if any.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "elf" => Some(ExecutableFormat::Elf),
+            "hex" | "ihex" => Some(ExecutableFormat::IntelHex),
+            "srec" | "s19" | "s28" | "s37" | "mot" => Some(ExecutableFormat::SRecord),
+            _ => None,
+        }
+    }
+
+    pub fn load(self, data: &[u8]) -> Result<MonadBootCartridge, ExecutableError> {
+        match self {
+            ExecutableFormat::Elf => load_elf(data),
+            ExecutableFormat::IntelHex => load_intel_hex(data),
+            ExecutableFormat::SRecord => load_srecord(data),
+        }
+    }
+}
+
+fn cartridge_from_segments(
+    segments: Vec<CartridgeSegment>,
+    entry_point: u64,
+) -> MonadBootCartridge {
+    let mut cartridge = MonadBootCartridge::new(&[]);
+    cartridge.segments = segments;
+    cartridge.revision = LATEST_CARTRIDGE_REVISION;
+    cartridge.entry_point = entry_point;
+    cartridge
+}
+
+fn elf_u16(data: &[u8], offset: usize) -> Result<u16, ExecutableError> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ExecutableError::BadElf("header is truncated"))
+}
+
+fn elf_u32(data: &[u8], offset: usize) -> Result<u32, ExecutableError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ExecutableError::BadElf("header is truncated"))
+}
+
+fn elf_u64(data: &[u8], offset: usize) -> Result<u64, ExecutableError> {
+    data.get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ExecutableError::BadElf("header is truncated"))
+}
+
+/// `count` table entries of `size` bytes from `offset`, each at least `minimum` bytes long.
+fn elf_table(
+    data: &[u8],
+    offset: u64,
+    count: usize,
+    size: usize,
+    minimum: usize,
+) -> Result<Vec<&[u8]>, ExecutableError> {
+    if count > 0 && size < minimum {
+        return Err(ExecutableError::BadElf("table entries are too small"));
+    }
+    (0..count)
+        .map(|index| {
+            let start = (offset as usize).saturating_add(index * size);
+            data.get(start..start.saturating_add(size))
+                .ok_or(ExecutableError::BadElf("table lies outside the file"))
+        })
+        .collect()
+}
+
+/// Loads a statically linked 64-bit little endian ELF executable. Every `PT_LOAD` segment is loaded at its physical
+/// address, with the part past the file contents zeroed, and the symbol table becomes the cartridge's symbols.
+pub fn load_elf(data: &[u8]) -> Result<MonadBootCartridge, ExecutableError> {
+    if data.len() < ELF_HEADER_SIZE || !data.starts_with(&ELF_MAGIC) {
+        return Err(ExecutableError::BadElf("header is truncated"));
+    }
+    if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+        return Err(ExecutableError::BadElf("not a 64-bit little endian file"));
+    }
+    if elf_u16(data, 0x10)? != ET_EXEC {
+        return Err(ExecutableError::BadElf("not an executable"));
+    }
+    let machine = elf_u16(data, 0x12)?;
+    if machine != EM_NONE && machine != ELF_MACHINE_MONARCH64 {
+        return Err(ExecutableError::BadElf("built for another machine"));
+    }
+    let entry_point = elf_u64(data, 0x18)?;
+
+    let program_headers = elf_table(
+        data,
+        elf_u64(data, 0x20)?,
+        elf_u16(data, 0x38)? as usize,
+        elf_u16(data, 0x36)? as usize,
+        ELF_PROGRAM_HEADER_SIZE,
+    )?;
+    let mut segments = Vec::new();
+    for header in program_headers {
+        if elf_u32(header, 0)? != PT_LOAD {
+            continue;
+        }
+        let flags = elf_u32(header, 4)?;
+        let offset = elf_u64(header, 0x08)? as usize;
+        let address = elf_u64(header, 0x18)?;
+        let file_size = elf_u64(header, 0x20)? as usize;
+        let memory_size = elf_u64(header, 0x28)? as usize;
+        let contents = data
+            .get(offset..offset.saturating_add(file_size))
+            .ok_or(ExecutableError::BadElf("segment lies outside the file"))?;
+        let zero_fill = memory_size
+            .checked_sub(file_size)
+            .and_then(|size| u32::try_from(size).ok())
+            .ok_or(ExecutableError::BadElf("segment has a bad memory size"))?;
+
+        segments.push(CartridgeSegment {
+            load_address: address,
+            data: contents.to_vec(),
+            zero_fill,
+            flags: [
+                (PF_R, SEGMENT_READ),
+                (PF_W, SEGMENT_WRITE),
+                (PF_X, SEGMENT_EXECUTE),
+            ]
+            .iter()
+            .filter(|(bit, _)| flags & bit != 0)
+            .fold(0, |bits, (_, flag)| bits | flag),
+        });
+    }
+    if segments.is_empty() {
+        return Err(ExecutableError::BadElf("no loadable segments"));
+    }
+
+    let cartridge = cartridge_from_segments(segments, entry_point);
+    let symbols = load_elf_symbols(data)?;
+    Ok(if symbols.is_empty() {
+        cartridge
+    } else {
+        cartridge.with_symbols(symbols)
+    })
+}
+
+fn load_elf_symbols(data: &[u8]) -> Result<SymbolTable, ExecutableError> {
+    let sections = elf_table(
+        data,
+        elf_u64(data, 0x28)?,
+        elf_u16(data, 0x3C)? as usize,
+        elf_u16(data, 0x3A)? as usize,
+        ELF_SECTION_HEADER_SIZE,
+    )?;
+    let mut symbols = Vec::new();
+    for section in &sections {
+        if elf_u32(section, 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let strings = sections
+            .get(elf_u32(section, 0x28)? as usize)
+            .ok_or(ExecutableError::BadElf("symbol table has no string table"))?;
+        let strings = elf_table(
+            data,
+            elf_u64(strings, 0x18)?,
+            1,
+            elf_u64(strings, 0x20)? as usize,
+            0,
+        )?[0];
+        let entries = elf_table(
+            data,
+            elf_u64(section, 0x18)?,
+            elf_u64(section, 0x20)? as usize / ELF_SYMBOL_SIZE,
+            ELF_SYMBOL_SIZE,
+            ELF_SYMBOL_SIZE,
+        )?;
+
+        for entry in entries {
+            let kind = match entry[4] & 0xF {
+                STT_FUNC => SymbolKind::Function,
+                STT_OBJECT => SymbolKind::Object,
+                STT_NOTYPE => SymbolKind::Label,
+                // Sections and files aren't addresses worth naming
+                _ => continue,
+            };
+            if elf_u16(entry, 6)? == SHN_UNDEF {
+                continue;
+            }
+            let name = strings
+                .get(elf_u32(entry, 0)? as usize..)
+                .and_then(|name| name.split(|&byte| byte == 0).next())
+                .and_then(|name| std::str::from_utf8(name).ok())
+                .ok_or(ExecutableError::BadElf(
+                    "symbol name lies outside the string table",
+                ))?;
+            if name.is_empty() {
+                continue;
+            }
+            symbols.push(Symbol {
+                name: name.to_string(),
+                address: elf_u64(entry, 8)?,
+                size: elf_u64(entry, 0x10)?,
+                kind,
+            });
+        }
+    }
+    Ok(SymbolTable::new(symbols))
+}
+
+/// Gathers data records into segments, joining records that follow on from each other.
+#[derive(Default)]
+struct SegmentCollector {
+    segments: Vec<CartridgeSegment>,
+}
+
+impl SegmentCollector {
+    fn add(&mut self, address: u64, data: &[u8]) {
+        if let Some(last) = self.segments.last_mut()
+            && last.load_address + last.data.len() as u64 == address
+        {
+            last.data.extend_from_slice(data);
+            return;
+        }
+        self.segments.push(CartridgeSegment {
+            load_address: address,
+            data: data.to_vec(),
+            zero_fill: 0,
+            // Neither format says anything about permissions
+            flags: MEMORY_ALL as u32,
+        });
+    }
+}
+
+/// Splits a text image into its non-empty lines, each decoded from hex after the `skip` leading characters.
+fn record_lines(
+    data: &[u8],
+    skip: usize,
+) -> impl Iterator<Item = (usize, &[u8], Result<Vec<u8>, ExecutableError>)> {
+    data.split(|&byte| byte == b'\n')
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim_ascii()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(move |(line_number, line)| {
+            let bad = |reason| ExecutableError::BadRecord {
+                line: line_number,
+                reason,
+            };
+            let bytes = line
+                .get(skip..)
+                .filter(|digits| digits.len() % 2 == 0)
+                .ok_or(bad("odd number of hex digits"))
+                .and_then(|digits| {
+                    digits
+                        .chunks(2)
+                        .map(|pair| {
+                            std::str::from_utf8(pair)
+                                .ok()
+                                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                                .ok_or(bad("not a hex digit"))
+                        })
+                        .collect()
+                });
+            (line_number, line, bytes)
+        })
+}
+
+/// Loads an Intel HEX image, using extended linear and segment addresses for anything past 64 KiB. The start
+/// address record, if there is one, becomes the entry point.
+pub fn load_intel_hex(data: &[u8]) -> Result<MonadBootCartridge, ExecutableError> {
+    let mut collector = SegmentCollector::default();
+    let mut base = 0u64;
+    let mut entry_point = 0u64;
+
+    for (line, text, bytes) in record_lines(data, 1) {
+        let bad = |reason| ExecutableError::BadRecord { line, reason };
+        if text[0] != b':' {
+            return Err(bad("doesn't start with ':'"));
+        }
+        let bytes = bytes?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(bad("length doesn't match the record"));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(bad("checksum mismatch"));
+        }
+
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+        let payload = &bytes[4..bytes.len() - 1];
+        let value = || {
+            payload
+                .iter()
+                .fold(0u64, |value, &byte| value << 8 | byte as u64)
+        };
+        match (bytes[3], payload.len()) {
+            (0x00, _) => collector.add(base + address, payload),
+            (0x01, _) => break,
+            (0x02, 2) => base = value() << 4,
+            // CS:IP
+            (0x03, 4) => entry_point = (value() >> 16 << 4) + (value() & 0xFFFF),
+            (0x04, 2) => base = value() << 16,
+            (0x05, 4) => entry_point = value(),
+            _ => return Err(bad("unknown record type")),
+        }
+    }
+    Ok(cartridge_from_segments(collector.segments, entry_point))
+}
+
+/// Loads a Motorola S-record image. The `S0` header becomes the title, and the `S7`, `S8` or `S9` record the entry
+/// point.
+pub fn load_srecord(data: &[u8]) -> Result<MonadBootCartridge, ExecutableError> {
+    let mut collector = SegmentCollector::default();
+    let mut entry_point = 0u64;
+    let mut title = String::new();
+
+    for (line, text, bytes) in record_lines(data, 2) {
+        let bad = |reason| ExecutableError::BadRecord { line, reason };
+        if text[0] != b'S' {
+            return Err(bad("doesn't start with 'S'"));
+        }
+        let bytes = bytes?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(bad("length doesn't match the record"));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xFF {
+            return Err(bad("checksum mismatch"));
+        }
+
+        let address_size = match text[1] {
+            b'0' | b'1' | b'5' | b'9' => 2,
+            b'2' | b'6' | b'8' => 3,
+            b'3' | b'7' => 4,
+            _ => return Err(bad("unknown record type")),
+        };
+        let fields = &bytes[1..bytes.len() - 1];
+        if fields.len() < address_size {
+            return Err(bad("record is shorter than its address"));
+        }
+        let (address, payload) = fields.split_at(address_size);
+        let address = address
+            .iter()
+            .fold(0u64, |value, &byte| value << 8 | byte as u64);
+        match text[1] {
+            b'0' => {
+                title = String::from_utf8_lossy(payload)
+                    .trim_end_matches('\0')
+                    .to_string();
+            }
+            b'1' | b'2' | b'3' => collector.add(address, payload),
+            b'7' | b'8' | b'9' => entry_point = address,
+            // Record counts
+            _ => {}
+        }
+    }
+
+    let mut cartridge = cartridge_from_segments(collector.segments, entry_point);
+    cartridge.title = title;
+    Ok(cartridge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peripherals::storage::verification::VerificationPolicy;
+
+    /// An executable with one `R+X` segment of 8 bytes at 0x1000 followed by 0x10 zeroed, and a `main` symbol.
+    fn elf(machine: u16) -> Vec<u8> {
+        let mut data = vec![0; 0xB8 + 3 * ELF_SECTION_HEADER_SIZE];
+        let mut put =
+            |offset: usize, bytes: &[u8]| data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0x00, &ELF_MAGIC);
+        put(0x04, &[ELFCLASS64, ELFDATA2LSB, 1]);
+        put(0x10, &ET_EXEC.to_le_bytes());
+        put(0x12, &machine.to_le_bytes());
+        put(0x18, &0x1000u64.to_le_bytes());
+        put(0x20, &0x40u64.to_le_bytes());
+        put(0x28, &0xB8u64.to_le_bytes());
+        put(0x36, &(ELF_PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        put(0x38, &1u16.to_le_bytes());
+        put(0x3A, &(ELF_SECTION_HEADER_SIZE as u16).to_le_bytes());
+        put(0x3C, &3u16.to_le_bytes());
+
+        // The program header, and the segment's contents
+        put(0x40, &PT_LOAD.to_le_bytes());
+        put(0x44, &(PF_R | PF_X).to_le_bytes());
+        put(0x48, &0x78u64.to_le_bytes());
+        put(0x58, &0x1000u64.to_le_bytes());
+        put(0x60, &8u64.to_le_bytes());
+        put(0x68, &0x18u64.to_le_bytes());
+        put(0x78, &[0x0D, 0x03, 0, 0, 0, 0, 0, 0]);
+
+        // The string table, then the symbol table with the null symbol and `main`
+        put(0x80, b"\0main\0");
+        put(0xA0, &1u32.to_le_bytes());
+        put(0xA4, &[STT_FUNC]);
+        put(0xA6, &1u16.to_le_bytes());
+        put(0xA8, &0x1000u64.to_le_bytes());
+        put(0xB0, &8u64.to_le_bytes());
+
+        // Section headers: null, the symbol table and the string table it links to
+        let symtab = 0xB8 + ELF_SECTION_HEADER_SIZE;
+        put(symtab + 4, &SHT_SYMTAB.to_le_bytes());
+        put(symtab + 0x18, &0x88u64.to_le_bytes());
+        put(symtab + 0x20, &(2 * ELF_SYMBOL_SIZE as u64).to_le_bytes());
+        put(symtab + 0x28, &2u32.to_le_bytes());
+        let strtab = symtab + ELF_SECTION_HEADER_SIZE;
+        put(strtab + 4, &3u32.to_le_bytes());
+        put(strtab + 0x18, &0x80u64.to_le_bytes());
+        put(strtab + 0x20, &6u64.to_le_bytes());
+        data
+    }
+
+    /// An Intel HEX record with its length and checksum filled in.
+    fn hex_record(kind: u8, address: u16, payload: &[u8]) -> String {
+        let mut bytes = vec![payload.len() as u8];
+        bytes.extend_from_slice(&address.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(payload);
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes.push(sum.wrapping_neg());
+        let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!(":{}\n", hex)
+    }
+
+    /// An S-record with its length and checksum filled in.
+    fn srecord(kind: char, address: &[u8], payload: &[u8]) -> String {
+        let mut bytes = vec![(address.len() + payload.len() + 1) as u8];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(payload);
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes.push(!sum);
+        let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("S{}{}\n", kind, hex)
+    }
+
+    fn bad_record(line: usize, reason: &'static str) -> ExecutableError {
+        ExecutableError::BadRecord { line, reason }
+    }
+
+    #[test]
+    fn loads_elf_segments_and_symbols() {
+        let cartridge = load_elf(&elf(ELF_MACHINE_MONARCH64)).unwrap();
+        assert_eq!(cartridge.get_entry_point(), 0x1000);
+        assert_eq!(
+            cartridge.get_segments(),
+            [CartridgeSegment {
+                load_address: 0x1000,
+                data: vec![0x0D, 0x03, 0, 0, 0, 0, 0, 0],
+                zero_fill: 0x10,
+                flags: SEGMENT_READ | SEGMENT_EXECUTE,
+            }]
+        );
+        let main = cartridge.get_symbols().unwrap().find("main").unwrap();
+        assert_eq!(
+            (main.address, main.size, main.kind),
+            (0x1000, 8, SymbolKind::Function)
+        );
+
+        // Recognised by its magic number, whatever the file is called
+        let image = elf(EM_NONE);
+        let cartridge = MonadBootCartridge::from_bytes(&image).unwrap();
+        assert_eq!(cartridge.get_segments().len(), 1);
+    }
+
+    #[test]
+    fn refuses_malformed_elf_files() {
+        let bad = |image: &[u8]| load_elf(image).err().unwrap();
+
+        assert_eq!(
+            bad(&elf(0x3E)),
+            ExecutableError::BadElf("built for another machine")
+        );
+        assert_eq!(
+            bad(&elf(EM_NONE)[..0x30]),
+            ExecutableError::BadElf("header is truncated")
+        );
+
+        let mut image = elf(EM_NONE);
+        image[4] = 1;
+        assert_eq!(
+            bad(&image),
+            ExecutableError::BadElf("not a 64-bit little endian file")
+        );
+
+        let mut image = elf(EM_NONE);
+        image[0x60..0x68].copy_from_slice(&0x1000u64.to_le_bytes());
+        assert_eq!(
+            bad(&image),
+            ExecutableError::BadElf("segment lies outside the file")
+        );
+
+        let mut image = elf(EM_NONE);
+        image[0x68..0x70].copy_from_slice(&4u64.to_le_bytes());
+        assert_eq!(
+            bad(&image),
+            ExecutableError::BadElf("segment has a bad memory size")
+        );
+
+        let mut image = elf(EM_NONE);
+        image[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            bad(&image),
+            ExecutableError::BadElf("table lies outside the file")
+        );
+    }
+
+    #[test]
+    fn loads_intel_hex_records() {
+        let image = hex_record(0x04, 0, &[0x00, 0x01])
+            + &hex_record(0x00, 0x0000, &[1, 2, 3, 4])
+            + &hex_record(0x00, 0x0004, &[5, 6])
+            + &hex_record(0x00, 0x0100, &[7])
+            + &hex_record(0x05, 0, &[0x00, 0x01, 0x00, 0x00])
+            + &hex_record(0x01, 0, &[]);
+        let cartridge = load_intel_hex(image.as_bytes()).unwrap();
+
+        assert_eq!(cartridge.get_entry_point(), 0x1_0000);
+        let segments: Vec<_> = cartridge
+            .get_segments()
+            .iter()
+            .map(|segment| (segment.load_address, segment.data.clone()))
+            .collect();
+        assert_eq!(
+            segments,
+            [(0x1_0000, vec![1, 2, 3, 4, 5, 6]), (0x1_0100, vec![7])]
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_intel_hex_records() {
+        let good = hex_record(0x00, 0, &[1, 2]);
+        let bad = |line: &str| load_intel_hex((good.clone() + line).as_bytes()).err();
+
+        let mut corrupted = hex_record(0x00, 0, &[1, 2]);
+        corrupted.replace_range(9..11, "03");
+        assert_eq!(bad(&corrupted), Some(bad_record(2, "checksum mismatch")));
+        assert_eq!(
+            bad(":0200000001\n"),
+            Some(bad_record(2, "length doesn't match the record"))
+        );
+        assert_eq!(
+            bad(&hex_record(0x07, 0, &[])),
+            Some(bad_record(2, "unknown record type"))
+        );
+        assert_eq!(
+            bad("0000000001FF\n"),
+            Some(bad_record(2, "doesn't start with ':'"))
+        );
+        assert!(bad(":0G0000000\n").is_some());
+    }
+
+    #[test]
+    fn loads_srecords() {
+        let image = srecord('0', &[0, 0], b"Demo")
+            + &srecord('1', &[0x10, 0x00], &[1, 2, 3])
+            + &srecord('2', &[0x00, 0x10, 0x03], &[4])
+            + &srecord('5', &[0x00, 0x02], &[])
+            + &srecord('9', &[0x10, 0x00], &[]);
+        let cartridge = load_srecord(image.as_bytes()).unwrap();
+
+        assert_eq!(cartridge.get_title(), "Demo");
+        assert_eq!(cartridge.get_entry_point(), 0x1000);
+        assert_eq!(cartridge.get_segments().len(), 1);
+        assert_eq!(cartridge.get_segments()[0].data, [1, 2, 3, 4]);
+
+        // Read through the extension, and as a raw image without it
+        let path = std::env::temp_dir().join(format!("m64-srecord-{}.s19", std::process::id()));
+        std::fs::write(&path, &image).unwrap();
+        let cartridge = MonadBootCartridge::from_file(&path).unwrap();
+        assert_eq!(cartridge.get_title(), "Demo");
+        std::fs::remove_file(path).unwrap();
+        let cartridge = MonadBootCartridge::from_bytes(image.as_bytes()).unwrap();
+        assert_eq!(cartridge.get_revision(), 0);
+    }
+
+    #[test]
+    fn refuses_malformed_srecords() {
+        let good = srecord('1', &[0, 0], &[1]);
+        let bad = |line: &str| load_srecord((good.clone() + line).as_bytes()).err();
+
+        let mut corrupted = srecord('1', &[0, 0], &[1]);
+        corrupted.replace_range(8..10, "02");
+        assert_eq!(bad(&corrupted), Some(bad_record(2, "checksum mismatch")));
+        assert_eq!(
+            bad(&srecord('4', &[0, 0], &[])),
+            Some(bad_record(2, "unknown record type"))
+        );
+        assert_eq!(
+            bad("S10500000000\n"),
+            Some(bad_record(2, "length doesn't match the record"))
+        );
+        assert_eq!(
+            bad(&srecord('3', &[0, 0], &[])),
+            Some(bad_record(2, "record is shorter than its address"))
+        );
+        assert_eq!(
+            bad("X1030000FC\n"),
+            Some(bad_record(2, "doesn't start with 'S'"))
+        );
+    }
+
+    #[test]
+    fn text_formats_are_only_read_when_asked_for() {
+        // A raw image whose first instruction happens to be made of printable bytes
+        let raw = b":00000001FF\n";
+        let cartridge = MonadBootCartridge::from_bytes(raw).unwrap();
+        assert_eq!(cartridge.get_revision(), 0);
+        assert_eq!(cartridge.get_segments()[0].data, raw);
+
+        let cartridge = MonadBootCartridge::from_bytes_in_format(
+            raw,
+            Some(ExecutableFormat::IntelHex),
+            &VerificationPolicy::default(),
+        )
+        .unwrap();
+        assert!(cartridge.get_segments().is_empty());
+
+        assert_eq!(
+            ExecutableFormat::from_extension(Path::new("game.S19")),
+            Some(ExecutableFormat::SRecord)
+        );
+        assert_eq!(
+            ExecutableFormat::from_extension(Path::new("game.bin")),
+            None
+        );
+    }
+}
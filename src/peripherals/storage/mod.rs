@@ -1 +1,8 @@
+pub mod bank_controller;
+pub mod cartridge_builder;
+pub mod cartridge_slot;
+pub mod executable;
 pub mod monad_boot_cartridge;
+pub mod patch;
+pub mod save_ram;
+pub mod verification;
\ No newline at end of file
@@ -1,21 +1,941 @@
+use std::{
+    ffi::OsString,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    misc::{
+        crc32::crc32,
+        memory_bus::{MEMORY_ALL, MEMORY_EXECUTE, MEMORY_READ, MEMORY_WRITE},
+        symbols::SymbolTable,
+    },
+    peripherals::storage::{
+        cartridge_builder::BuildError,
+        executable::{ExecutableError, ExecutableFormat},
+        patch::{Patch, PatchError},
+        verification::{SignatureStatus, VerificationPolicy},
+    },
+};
+
+/// Every cartridge with a header starts with these bytes. Anything else is loaded as a raw revision 0 image.
+pub const CARTRIDGE_MAGIC: [u8; 4] = *b"M64C";
+pub const CARTRIDGE_HEADER_SIZE: usize = 0x100;
+/// The newest cartridge revision we know how to load.
+pub const LATEST_CARTRIDGE_REVISION: u8 = 2;
+pub const SEGMENT_ENTRY_SIZE: usize = 0x20;
+/// Where the signature sits in the header, and how long it is.
+pub const SIGNATURE_OFFSET: usize = 0x80;
+pub const SIGNATURE_LENGTH: usize = 64;
+pub const SIGNATURE_ALGORITHM_ED25519: u8 = 1;
+/// Cartridge files bigger than this are refused before being read.
+pub const MAX_CARTRIDGE_FILE_SIZE: u64 = 0x400_0000;
+
+pub const SEGMENT_READ: u32 = MEMORY_READ as u32;
+pub const SEGMENT_WRITE: u32 = MEMORY_WRITE as u32;
+pub const SEGMENT_EXECUTE: u32 = MEMORY_EXECUTE as u32;
+
+const TITLE_LENGTH: usize = 32;
+
+/// How a cartridge too big for the motherboard's ROM window is made visible to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CartridgeMapper {
+    /// Everything is mapped at once, so the cartridge has to fit in the window.
+    #[default]
+    None,
+    /// The first half of the ROM window always shows the start of the image, the second half shows the bank picked
+    /// through the bank-select register.
+    Banked,
+}
+
+impl CartridgeMapper {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CartridgeMapper::None),
+            1 => Some(CartridgeMapper::Banked),
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            CartridgeMapper::None => 0,
+            CartridgeMapper::Banked => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    /// The image is shorter than its header says it should be.
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    UnsupportedRevision(u8),
+    BadHeaderSize(u16),
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    InvalidTitle,
+    /// A header byte that has to be zero isn't, at this offset.
+    NonZeroReserved(usize),
+    /// A segment's data lies outside the payload.
+    BadSegment(usize),
+    UnsupportedMapper(u8),
+    UnsupportedSignature(u8),
+    /// The policy asks for a signature and the cartridge has none.
+    Unsigned,
+    /// The signature doesn't match any trusted key.
+    UntrustedSignature,
+    /// The image is an ELF, HEX or S-record file that couldn't be read.
+    Executable(ExecutableError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Truncated { expected, actual } => {
+                write!(
+                    f,
+                    "cartridge is truncated, expected {} bytes but got {}",
+                    expected, actual
+                )
+            }
+            CartridgeError::UnsupportedRevision(revision) => {
+                write!(f, "unsupported cartridge revision {}", revision)
+            }
+            CartridgeError::BadHeaderSize(size) => {
+                write!(f, "bad cartridge header size {:#X}", size)
+            }
+            CartridgeError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "cartridge checksum mismatch, header says {:#010X} but payload is {:#010X}",
+                expected, actual
+            ),
+            CartridgeError::InvalidTitle => write!(f, "cartridge title is not valid UTF-8"),
+            CartridgeError::NonZeroReserved(offset) => {
+                write!(
+                    f,
+                    "reserved cartridge header byte {:#X} is not zero",
+                    offset
+                )
+            }
+            CartridgeError::BadSegment(index) => {
+                write!(f, "cartridge segment {} lies outside the payload", index)
+            }
+            CartridgeError::UnsupportedMapper(mapper) => {
+                write!(f, "unsupported cartridge mapper {}", mapper)
+            }
+            CartridgeError::UnsupportedSignature(algorithm) => {
+                write!(f, "unsupported signature algorithm {}", algorithm)
+            }
+            CartridgeError::Unsigned => write!(f, "cartridge is not signed"),
+            CartridgeError::UntrustedSignature => {
+                write!(f, "cartridge signature doesn't match any trusted key")
+            }
+            CartridgeError::Executable(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+/// Why a cartridge file couldn't be loaded.
+#[derive(Debug)]
+pub enum CartridgeLoadError {
+    NotFound(PathBuf),
+    TooLarge {
+        path: PathBuf,
+        size: u64,
+    },
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// The file was read but isn't a valid cartridge.
+    Invalid {
+        path: PathBuf,
+        error: CartridgeError,
+    },
+    /// The patch couldn't be read, or doesn't apply to the cartridge.
+    Patch {
+        path: PathBuf,
+        error: PatchError,
+    },
+}
+
+impl fmt::Display for CartridgeLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeLoadError::NotFound(path) => {
+                write!(f, "cartridge {} does not exist", path.display())
+            }
+            CartridgeLoadError::TooLarge { path, size } => write!(
+                f,
+                "cartridge {} is {} bytes, the most we load is {}",
+                path.display(),
+                size,
+                MAX_CARTRIDGE_FILE_SIZE
+            ),
+            CartridgeLoadError::Io { path, error } => {
+                write!(f, "failed to read cartridge {}: {}", path.display(), error)
+            }
+            CartridgeLoadError::Invalid { path, error } => {
+                write!(f, "{} is not a valid cartridge: {}", path.display(), error)
+            }
+            CartridgeLoadError::Patch { path, error } => {
+                write!(f, "failed to apply patch {}: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeLoadError::Io { error, .. } => Some(error),
+            CartridgeLoadError::Invalid { error, .. } => Some(error),
+            CartridgeLoadError::Patch { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// The header at the start of every cartridge from revision 1 on. All fields are little endian.
+///
+/// ```text
+/// 0x00 [u8; 4]  magic, "M64C"
+/// 0x04 u8       revision
+/// 0x05 u8       reserved
+/// 0x06 u16      header size, always 0x100
+/// 0x08 u64      load address
+/// 0x10 u64      entry point
+/// 0x18 u64      initial stack pointer
+/// 0x20 u32      payload length
+/// 0x24 u32      payload checksum (CRC-32)
+/// 0x28 [u8; 32] title, UTF-8 padded with zeroes
+/// 0x48 u16      segment count, revision 2 and up
+/// 0x4A u8       mapper, 0 for none and 1 for banked
+/// 0x4B u8       reserved
+/// 0x4C u32      battery-backed save RAM size, 0 for none
+/// 0x50 u8       signature algorithm, 0 for unsigned and 1 for Ed25519
+/// 0x51          reserved up to 0x80, must be zero
+/// 0x80 [u8; 64] signature over the whole image with this field zeroed
+/// 0xC0          reserved up to 0x100, must be zero
+/// ```
+///
+/// The payload follows directly after the header. In revision 1 the whole payload is copied to the load address.
+/// From revision 2 the payload starts with a table of `segment count` entries, each one loaded on its own:
+///
+/// ```text
+/// 0x00 u64 load address
+/// 0x08 u32 offset of the segment's data, from the start of the payload
+/// 0x0C u32 size of the segment's data
+/// 0x10 u32 number of zero bytes following the data in memory, for BSS
+/// 0x14 u32 permission flags, see `SEGMENT_READ`, `SEGMENT_WRITE` and `SEGMENT_EXECUTE`
+/// 0x18     reserved up to 0x20
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub revision: u8,
+    pub load_address: u64,
+    pub entry_point: u64,
+    pub stack_pointer: u64,
+    pub payload_length: u32,
+    pub checksum: u32,
+    pub title: String,
+    pub segment_count: u16,
+    pub mapper: CartridgeMapper,
+    pub save_ram_size: u32,
+}
+
+impl CartridgeHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, CartridgeError> {
+        if data.len() < CARTRIDGE_HEADER_SIZE {
+            return Err(CartridgeError::Truncated {
+                expected: CARTRIDGE_HEADER_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        let revision = data[0x04];
+        if revision == 0 || revision > LATEST_CARTRIDGE_REVISION {
+            return Err(CartridgeError::UnsupportedRevision(revision));
+        }
+        if u16_at(0x06) as usize != CARTRIDGE_HEADER_SIZE {
+            return Err(CartridgeError::BadHeaderSize(u16_at(0x06)));
+        }
+
+        // Keeping these zero now leaves them free to mean something in later revisions
+        for range in [
+            0x51..SIGNATURE_OFFSET,
+            SIGNATURE_OFFSET + SIGNATURE_LENGTH..CARTRIDGE_HEADER_SIZE,
+        ] {
+            if let Some(offset) = range.clone().find(|&offset| data[offset] != 0) {
+                return Err(CartridgeError::NonZeroReserved(offset));
+            }
+        }
+
+        let title_bytes = &data[0x28..0x28 + TITLE_LENGTH];
+        let title_length = title_bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(TITLE_LENGTH);
+        let title = std::str::from_utf8(&title_bytes[..title_length])
+            .map_err(|_| CartridgeError::InvalidTitle)?
+            .to_string();
+        let mapper = CartridgeMapper::from_id(data[0x4A])
+            .ok_or(CartridgeError::UnsupportedMapper(data[0x4A]))?;
+        // Anything but no signature or Ed25519 would otherwise pass for unsigned, and be loaded without a check
+        if data[0x50] != 0 && data[0x50] != SIGNATURE_ALGORITHM_ED25519 {
+            return Err(CartridgeError::UnsupportedSignature(data[0x50]));
+        }
+
+        Ok(Self {
+            revision,
+            load_address: u64_at(0x08),
+            entry_point: u64_at(0x10),
+            stack_pointer: u64_at(0x18),
+            payload_length: u32_at(0x20),
+            checksum: u32_at(0x24),
+            title,
+            segment_count: if revision >= 2 { u16_at(0x48) } else { 0 },
+            mapper,
+            save_ram_size: u32_at(0x4C),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = vec![0; CARTRIDGE_HEADER_SIZE];
+        header[0x00..0x04].copy_from_slice(&CARTRIDGE_MAGIC);
+        header[0x04] = self.revision;
+        header[0x06..0x08].copy_from_slice(&(CARTRIDGE_HEADER_SIZE as u16).to_le_bytes());
+        header[0x08..0x10].copy_from_slice(&self.load_address.to_le_bytes());
+        header[0x10..0x18].copy_from_slice(&self.entry_point.to_le_bytes());
+        header[0x18..0x20].copy_from_slice(&self.stack_pointer.to_le_bytes());
+        header[0x20..0x24].copy_from_slice(&self.payload_length.to_le_bytes());
+        header[0x24..0x28].copy_from_slice(&self.checksum.to_le_bytes());
+        // Titles that don't fit are cut at a character boundary so the header stays valid UTF-8
+        let mut title_length = self.title.len().min(TITLE_LENGTH);
+        while !self.title.is_char_boundary(title_length) {
+            title_length -= 1;
+        }
+        header[0x28..0x28 + title_length].copy_from_slice(&self.title.as_bytes()[..title_length]);
+        header[0x48..0x4A].copy_from_slice(&self.segment_count.to_le_bytes());
+        header[0x4A] = self.mapper.id();
+        header[0x4C..0x50].copy_from_slice(&self.save_ram_size.to_le_bytes());
+        header
+    }
+}
+
+/// A piece of a cartridge that gets loaded into memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeSegment {
+    pub load_address: u64,
+    pub data: Vec<u8>,
+    /// Zero bytes placed straight after `data`.
+    pub zero_fill: u32,
+    pub flags: u32,
+}
+
+impl CartridgeSegment {
+    fn parse_table(header: &CartridgeHeader, payload: &[u8]) -> Result<Vec<Self>, CartridgeError> {
+        let table_size = header.segment_count as usize * SEGMENT_ENTRY_SIZE;
+        if payload.len() < table_size {
+            return Err(CartridgeError::Truncated {
+                expected: CARTRIDGE_HEADER_SIZE + table_size,
+                actual: CARTRIDGE_HEADER_SIZE + payload.len(),
+            });
+        }
+
+        (0..header.segment_count as usize)
+            .map(|index| {
+                let entry = &payload[index * SEGMENT_ENTRY_SIZE..(index + 1) * SEGMENT_ENTRY_SIZE];
+                let u32_at = |offset: usize| {
+                    u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap())
+                };
+
+                let offset = u32_at(0x08) as usize;
+                let size = u32_at(0x0C) as usize;
+                let data = payload
+                    .get(offset..offset + size)
+                    .ok_or(CartridgeError::BadSegment(index))?;
+
+                Ok(Self {
+                    load_address: u64::from_le_bytes(entry[0x00..0x08].try_into().unwrap()),
+                    data: data.to_vec(),
+                    zero_fill: u32_at(0x10),
+                    flags: u32_at(0x14),
+                })
+            })
+            .collect()
+    }
+
+    /// Builds the table entry for this segment, given where its data ends up in the payload.
+    pub fn to_table_entry(&self, offset: u32) -> [u8; SEGMENT_ENTRY_SIZE] {
+        let mut entry = [0; SEGMENT_ENTRY_SIZE];
+        entry[0x00..0x08].copy_from_slice(&self.load_address.to_le_bytes());
+        entry[0x08..0x0C].copy_from_slice(&offset.to_le_bytes());
+        entry[0x0C..0x10].copy_from_slice(&(self.data.len() as u32).to_le_bytes());
+        entry[0x10..0x14].copy_from_slice(&self.zero_fill.to_le_bytes());
+        entry[0x14..0x18].copy_from_slice(&self.flags.to_le_bytes());
+        entry
+    }
+}
+
 pub struct MonadBootCartridge {
-    pub(crate) data: Vec<u8>,
+    pub(crate) segments: Vec<CartridgeSegment>,
     pub(crate) revision: u8,
+    pub(crate) entry_point: u64,
+    pub(crate) stack_pointer: u64,
+    pub(crate) title: String,
+    pub(crate) mapper: CartridgeMapper,
+    pub(crate) save_ram_size: u32,
+    pub(crate) save_path: Option<PathBuf>,
+    pub(crate) symbols: Option<Arc<SymbolTable>>,
+    pub(crate) signature: SignatureStatus,
+    /// The checksum in the header and the one of the payload, when they didn't match and the policy let it load.
+    pub(crate) checksum_mismatch: Option<(u32, u32)>,
+}
+
+/// Reads a cartridge or patch file, refusing anything too big to be one.
+fn read_file(path: &Path) -> Result<Vec<u8>, CartridgeLoadError> {
+    let io_error = |error: io::Error| {
+        if error.kind() == io::ErrorKind::NotFound {
+            CartridgeLoadError::NotFound(path.to_path_buf())
+        } else {
+            CartridgeLoadError::Io {
+                path: path.to_path_buf(),
+                error,
+            }
+        }
+    };
+
+    let size = fs::metadata(path).map_err(io_error)?.len();
+    if size > MAX_CARTRIDGE_FILE_SIZE {
+        return Err(CartridgeLoadError::TooLarge {
+            path: path.to_path_buf(),
+            size,
+        });
+    }
+    fs::read(path).map_err(io_error)
 }
 
 impl MonadBootCartridge {
+    /// Wraps a raw revision 0 image, which is loaded at address 0 and started from there.
     pub fn new(data: &[u8]) -> Self {
         Self {
-            data: data.to_vec(),
+            segments: vec![CartridgeSegment {
+                load_address: 0,
+                data: data.to_vec(),
+                zero_fill: 0,
+                flags: MEMORY_ALL as u32,
+            }],
             revision: 0,
+            entry_point: 0,
+            stack_pointer: 0,
+            title: String::new(),
+            mapper: CartridgeMapper::None,
+            save_ram_size: 0,
+            save_path: None,
+            symbols: None,
+            signature: SignatureStatus::Unsigned,
+            checksum_mismatch: None,
         }
     }
 
-    pub(crate) fn get_revision(&self) -> u8 {
+    /// Loads a cartridge image, checking its header if it has one. ELF images are recognised by their contents and
+    /// turned into segments, and anything else without a header is a raw revision 0 image.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CartridgeError> {
+        Self::from_bytes_with_policy(data, &VerificationPolicy::default())
+    }
+
+    /// Loads a cartridge image, verifying its checksum and signature as `policy` says.
+    pub fn from_bytes_with_policy(
+        data: &[u8],
+        policy: &VerificationPolicy,
+    ) -> Result<Self, CartridgeError> {
+        Self::from_bytes_in_format(data, None, policy)
+    }
+
+    /// Loads an image like `from_bytes_with_policy`, reading it as `format` if one is given.
+    pub fn from_bytes_in_format(
+        data: &[u8],
+        format: Option<ExecutableFormat>,
+        policy: &VerificationPolicy,
+    ) -> Result<Self, CartridgeError> {
+        let signature = policy.check_signature(data)?;
+        let mut cartridge = Self::parse_image(data, format, policy)?;
+        cartridge.signature = signature;
+        Ok(cartridge)
+    }
+
+    /// Everything `from_bytes_in_format` checks except the signature.
+    fn parse_image(
+        data: &[u8],
+        format: Option<ExecutableFormat>,
+        policy: &VerificationPolicy,
+    ) -> Result<Self, CartridgeError> {
+        if let Some(format) = format.or_else(|| ExecutableFormat::detect(data)) {
+            return format.load(data).map_err(CartridgeError::Executable);
+        }
+        if !data.starts_with(&CARTRIDGE_MAGIC) {
+            return Ok(Self::new(data));
+        }
+
+        let header = CartridgeHeader::parse(data)?;
+        let payload_end = CARTRIDGE_HEADER_SIZE + header.payload_length as usize;
+        if data.len() < payload_end {
+            return Err(CartridgeError::Truncated {
+                expected: payload_end,
+                actual: data.len(),
+            });
+        }
+
+        let payload = &data[CARTRIDGE_HEADER_SIZE..payload_end];
+        let checksum = crc32(payload);
+        let checksum_mismatch =
+            (checksum != header.checksum).then_some((header.checksum, checksum));
+        if checksum_mismatch.is_some() {
+            policy.handle_failure(CartridgeError::ChecksumMismatch {
+                expected: header.checksum,
+                actual: checksum,
+            })?;
+        }
+
+        let segments = if header.revision >= 2 {
+            CartridgeSegment::parse_table(&header, payload)?
+        } else {
+            vec![CartridgeSegment {
+                load_address: header.load_address,
+                data: payload.to_vec(),
+                zero_fill: 0,
+                flags: MEMORY_ALL as u32,
+            }]
+        };
+
+        Ok(Self {
+            segments,
+            revision: header.revision,
+            entry_point: header.entry_point,
+            stack_pointer: header.stack_pointer,
+            title: header.title,
+            mapper: header.mapper,
+            save_ram_size: header.save_ram_size,
+            save_path: None,
+            symbols: None,
+            signature: SignatureStatus::Unsigned,
+            checksum_mismatch,
+        })
+    }
+
+    /// Loads a cartridge file, with its save RAM kept in a `.sav` file next to it. Symbols are read from the
+    /// `.tags` file alongside it if there is one, e.g. `game.bin.tags` for `game.bin`. Files ending in `.hex` or
+    /// `.srec` (or `.s19`, `.s28`, `.s37` and `.mot`) are read as Intel HEX and S-record images.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, CartridgeLoadError> {
+        Self::from_file_with_policy(path, &VerificationPolicy::default())
+    }
+
+    /// Loads a cartridge file like `from_file`, verifying it as `policy` says.
+    pub fn from_file_with_policy(
+        path: impl AsRef<Path>,
+        policy: &VerificationPolicy,
+    ) -> Result<Self, CartridgeLoadError> {
+        Self::from_file_patched(path, None, None::<&Path>, None, policy)
+    }
+
+    /// Loads a cartridge file like `from_file_with_policy`, applying an IPS or BPS patch to the image first.
+    ///
+    /// The checksum and signature are checked on the patched image, so a patch can't swap out the code of a signed
+    /// cartridge: with trusted keys, the patched image has to carry a signature from one of them itself. The patch
+    /// also has to be for the image it is applied to. A BPS patch records the checksum of that image, and for an IPS
+    /// patch it has to be given as `patch_checksum`.
+    ///
+    /// The image is read as `format` if one is given, and otherwise by the extension of `path`.
+    pub fn from_file_patched(
+        path: impl AsRef<Path>,
+        format: Option<ExecutableFormat>,
+        patch_path: Option<impl AsRef<Path>>,
+        patch_checksum: Option<u32>,
+        policy: &VerificationPolicy,
+    ) -> Result<Self, CartridgeLoadError> {
+        let path = path.as_ref();
+        let mut data = read_file(path)?;
+        let invalid = |error| CartridgeLoadError::Invalid {
+            path: path.to_path_buf(),
+            error,
+        };
+        if let Some(patch_path) = patch_path {
+            let patch_path = patch_path.as_ref();
+            let patch_error = |error| CartridgeLoadError::Patch {
+                path: patch_path.to_path_buf(),
+                error,
+            };
+            let mut patch = Patch::parse(&read_file(patch_path)?).map_err(patch_error)?;
+            if let Some(checksum) = patch_checksum {
+                patch = patch.with_source_checksum(checksum);
+            }
+            data = patch.apply(&data).map_err(patch_error)?;
+            log::info!(
+                "Applied patch {} to cartridge {}",
+                patch_path.display(),
+                path.display()
+            );
+        }
+
+        let format = format.or_else(|| ExecutableFormat::from_extension(path));
+        let mut cartridge = Self::from_bytes_in_format(&data, format, policy)
+            .map_err(invalid)?
+            .with_save_file(path.with_extension("sav"));
+
+        let mut tags_path = OsString::from(path);
+        tags_path.push(".tags");
+        let tags_path = PathBuf::from(tags_path);
+        if tags_path.exists() {
+            // Missing symbols only make traces harder to read, so they are no reason to refuse the cartridge
+            match SymbolTable::load(&tags_path) {
+                Ok(symbols) => cartridge = cartridge.with_symbols(symbols),
+                Err(error) => log::warn!("Ignoring {}: {}", tags_path.display(), error),
+            }
+        }
+        Ok(cartridge)
+    }
+
+    /// Builds the image for this cartridge in the layout of its revision.
+    ///
+    /// Fails rather than leave something out when the revision can't hold all of the cartridge: revision 0 and 1
+    /// images have a single segment loaded with every permission and no zero fill, and revision 0 has no header at
+    /// all. It also fails when the segments or payload are too big for the header to describe.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BuildError> {
+        let first_segment = self.segments.first();
+        if self.revision < 2
+            && (self.segments.len() > 1
+                || first_segment.is_some_and(|segment| {
+                    segment.zero_fill != 0 || segment.flags != MEMORY_ALL as u32
+                }))
+        {
+            return Err(BuildError::Invalid(format!(
+                "revision {} cartridges hold a single segment with every permission and no zero fill",
+                self.revision
+            )));
+        }
+        if self.revision == 0 {
+            if first_segment.is_some_and(|segment| segment.load_address != 0)
+                || self.entry_point != 0
+                || self.stack_pointer != 0
+                || !self.title.is_empty()
+                || self.mapper != CartridgeMapper::None
+                || self.save_ram_size != 0
+            {
+                return Err(BuildError::Invalid(
+                    "revision 0 cartridges have no header, so they load and start at 0 with no title, save RAM or mapper"
+                        .to_string(),
+                ));
+            }
+            return Ok(first_segment.map_or_else(Vec::new, |segment| segment.data.clone()));
+        }
+        let segment_count = u16::try_from(self.segments.len()).map_err(|_| {
+            BuildError::Invalid(format!(
+                "{} segments is more than a cartridge can have",
+                self.segments.len()
+            ))
+        })?;
+
+        let payload = if self.revision == 1 {
+            first_segment.map_or_else(Vec::new, |segment| segment.data.clone())
+        } else {
+            let mut table = Vec::new();
+            let mut blobs = Vec::new();
+            let table_size = self.segments.len() * SEGMENT_ENTRY_SIZE;
+            for segment in &self.segments {
+                let offset = (table_size + blobs.len()) as u32;
+                table.extend_from_slice(&segment.to_table_entry(offset));
+                blobs.extend_from_slice(&segment.data);
+            }
+            table.extend_from_slice(&blobs);
+            table
+        };
+        // Every segment offset and size is inside the payload, so they fit if it does
+        let payload_length = u32::try_from(payload.len()).map_err(|_| {
+            BuildError::Invalid(format!(
+                "payload of {} bytes is too big for a cartridge",
+                payload.len()
+            ))
+        })?;
+
+        let header = CartridgeHeader {
+            revision: self.revision,
+            load_address: first_segment.map_or(0, |segment| segment.load_address),
+            entry_point: self.entry_point,
+            stack_pointer: self.stack_pointer,
+            payload_length,
+            checksum: crc32(&payload),
+            title: self.title.clone(),
+            segment_count: if self.revision >= 2 { segment_count } else { 0 },
+            mapper: self.mapper,
+            save_ram_size: self.save_ram_size,
+        };
+        let mut image = header.to_bytes();
+        image.extend_from_slice(&payload);
+        Ok(image)
+    }
+
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = Some(Arc::new(symbols));
+        self
+    }
+
+    /// Sets the host file the cartridge's save RAM is loaded from and flushed to, usually a `.sav` next to the image.
+    /// Without one the save RAM still works, but is lost when the emulator exits.
+    pub fn with_save_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.save_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn get_revision(&self) -> u8 {
         self.revision
     }
 
-    pub(crate) fn get_data(&self) -> &Vec<u8> {
-        &self.data
+    /// How many bytes of data the segments hold, leaving out their zero fill.
+    pub fn get_size(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    pub fn get_segments(&self) -> &[CartridgeSegment] {
+        &self.segments
+    }
+
+    pub fn get_entry_point(&self) -> u64 {
+        self.entry_point
+    }
+
+    pub fn get_stack_pointer(&self) -> u64 {
+        self.stack_pointer
+    }
+
+    pub fn get_title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn get_mapper(&self) -> CartridgeMapper {
+        self.mapper
+    }
+
+    pub fn get_save_ram_size(&self) -> u32 {
+        self.save_ram_size
+    }
+
+    pub fn get_save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    pub fn get_symbols(&self) -> Option<&Arc<SymbolTable>> {
+        self.symbols.as_ref()
+    }
+
+    /// Whether the image carried a signature, trusted or not.
+    pub fn is_signed(&self) -> bool {
+        self.signature != SignatureStatus::Unsigned
+    }
+
+    /// Whether the image was signed by one of the keys trusted when it was loaded.
+    pub fn is_verified(&self) -> bool {
+        matches!(self.signature, SignatureStatus::Verified(_))
+    }
+
+    pub fn get_signature_status(&self) -> SignatureStatus {
+        self.signature
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+    use crate::peripherals::storage::verification::sign_image;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("m64-cartridge-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn headers_round_trip_and_keep_reserved_bytes_zero() {
+        let header = CartridgeHeader {
+            revision: 2,
+            load_address: 0x1000,
+            entry_point: 0x1008,
+            stack_pointer: 0x8000,
+            payload_length: 0x40,
+            checksum: 0x12345678,
+            title: "Round Trip".to_string(),
+            segment_count: 2,
+            mapper: CartridgeMapper::Banked,
+            save_ram_size: 0x2000,
+        };
+        let mut bytes = header.to_bytes();
+        assert_eq!(CartridgeHeader::parse(&bytes), Ok(header));
+
+        bytes[0xC0] = 1;
+        assert_eq!(
+            CartridgeHeader::parse(&bytes),
+            Err(CartridgeError::NonZeroReserved(0xC0))
+        );
+        bytes[0xC0] = 0;
+        bytes[0x60] = 1;
+        assert_eq!(
+            CartridgeHeader::parse(&bytes),
+            Err(CartridgeError::NonZeroReserved(0x60))
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_images() {
+        let mut cartridge = MonadBootCartridge::new(&[0x0D, 0x03, 0, 0, 0, 0, 0, 0]);
+        cartridge.revision = 2;
+        let image = cartridge.to_bytes().unwrap();
+        let corrupt = |offset: usize, bytes: &[u8]| {
+            let mut image = image.clone();
+            image[offset..offset + bytes.len()].copy_from_slice(bytes);
+            MonadBootCartridge::from_bytes(&image).err()
+        };
+
+        assert_eq!(
+            MonadBootCartridge::from_bytes(&image[..0x80]).err(),
+            Some(CartridgeError::Truncated {
+                expected: CARTRIDGE_HEADER_SIZE,
+                actual: 0x80
+            })
+        );
+        assert_eq!(
+            MonadBootCartridge::from_bytes(&image[..image.len() - 1]).err(),
+            Some(CartridgeError::Truncated {
+                expected: image.len(),
+                actual: image.len() - 1
+            })
+        );
+        assert_eq!(
+            corrupt(0x04, &[3]),
+            Some(CartridgeError::UnsupportedRevision(3))
+        );
+        assert_eq!(
+            corrupt(0x06, &[0x80, 0]),
+            Some(CartridgeError::BadHeaderSize(0x80))
+        );
+        assert_eq!(corrupt(0x28, &[0xFF]), Some(CartridgeError::InvalidTitle));
+        assert_eq!(
+            corrupt(0x4A, &[7]),
+            Some(CartridgeError::UnsupportedMapper(7))
+        );
+        assert_eq!(
+            corrupt(0x50, &[9]),
+            Some(CartridgeError::UnsupportedSignature(9))
+        );
+        assert!(matches!(
+            corrupt(CARTRIDGE_HEADER_SIZE + 0x18, &[1]),
+            Some(CartridgeError::ChecksumMismatch { .. })
+        ));
+
+        // A segment reaching past the payload, with the checksum fixed up so that's the only thing wrong
+        let mut image = image.clone();
+        image[CARTRIDGE_HEADER_SIZE + 0x0C] = 9;
+        let checksum = crc32(&image[CARTRIDGE_HEADER_SIZE..]);
+        image[0x24..0x28].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            MonadBootCartridge::from_bytes(&image).err(),
+            Some(CartridgeError::BadSegment(0))
+        );
+    }
+
+    #[test]
+    fn images_that_would_lose_something_are_refused() {
+        let mut cartridge = MonadBootCartridge::new(&[0; 8]);
+        assert_eq!(cartridge.to_bytes().unwrap(), [0; 8]);
+        cartridge.title = "Headerless".to_string();
+        assert!(cartridge.to_bytes().is_err());
+
+        cartridge.revision = 1;
+        assert!(cartridge.to_bytes().is_ok());
+        cartridge.segments[0].zero_fill = 0x10;
+        assert!(cartridge.to_bytes().is_err());
+        cartridge.segments[0].zero_fill = 0;
+        cartridge.segments[0].flags = SEGMENT_READ | SEGMENT_EXECUTE;
+        assert!(cartridge.to_bytes().is_err());
+
+        cartridge.revision = 2;
+        let image = cartridge.to_bytes().unwrap();
+        let loaded = MonadBootCartridge::from_bytes(&image).unwrap();
+        assert_eq!(loaded.get_segments(), cartridge.get_segments());
+        assert_eq!(loaded.get_title(), "Headerless");
+
+        cartridge.segments = vec![cartridge.segments[0].clone(); u16::MAX as usize + 1];
+        assert!(cartridge.to_bytes().is_err());
+    }
+
+    #[test]
+    fn patching_a_signed_cartridge_needs_a_new_signature() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let mut cartridge = MonadBootCartridge::new(&[0x0D, 0x03, 0, 0, 0, 0, 0, 0]);
+        cartridge.revision = 1;
+        let mut image = cartridge.to_bytes().unwrap();
+        sign_image(&mut image, &key);
+
+        let cartridge_path = temp_path("signed.bin");
+        let patch_path = temp_path("patch.ips");
+        fs::write(&cartridge_path, &image).unwrap();
+        // Overwrites the first instruction of the payload, and the payload checksum to go with it
+        let offset = (CARTRIDGE_HEADER_SIZE as u32).to_be_bytes();
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&offset[1..]);
+        patch.extend_from_slice(&[0x00, 0x02, 0x0E, 0x03]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x24, 0x00, 0x04]);
+        patch.extend_from_slice(&crc32(&[0x0E, 0x03, 0, 0, 0, 0, 0, 0]).to_le_bytes());
+        patch.extend_from_slice(b"EOF");
+        fs::write(&patch_path, &patch).unwrap();
+
+        let policy = VerificationPolicy::default().with_trusted_key(key.verifying_key());
+        assert!(MonadBootCartridge::from_file_with_policy(&cartridge_path, &policy).is_ok());
+        let patched = MonadBootCartridge::from_file_patched(
+            &cartridge_path,
+            None,
+            Some(&patch_path),
+            Some(crc32(&image)),
+            &policy,
+        );
+        assert!(matches!(
+            patched,
+            Err(CartridgeLoadError::Invalid {
+                error: CartridgeError::UntrustedSignature,
+                ..
+            })
+        ));
+
+        // Without trusted keys the patch is fine, and the stale signature counts for nothing
+        let patched = MonadBootCartridge::from_file_patched(
+            &cartridge_path,
+            None,
+            Some(&patch_path),
+            Some(crc32(&image)),
+            &VerificationPolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(patched.get_segments()[0].data[..2], [0x0E, 0x03]);
+        assert!(!patched.is_verified());
+
+        fs::remove_file(cartridge_path).unwrap();
+        fs::remove_file(patch_path).unwrap();
     }
 }
@@ -0,0 +1,449 @@
+use std::fmt;
+
+use crate::misc::crc32::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+/// A BPS patch ends with the CRC-32 of the source, the target and the patch itself.
+const BPS_FOOTER_SIZE: usize = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// Neither an IPS nor a BPS patch.
+    UnknownFormat,
+    /// The patch ends in the middle of a record.
+    Truncated,
+    /// The patch was made for a different image.
+    SourceMismatch { expected: u32, actual: u32 },
+    /// The patch applied but didn't produce the image it was made to.
+    TargetMismatch { expected: u32, actual: u32 },
+    /// The patch file itself is damaged.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// A record reads or writes outside the image.
+    OutOfBounds,
+    /// An IPS patch was applied without the checksum of the image it was made for.
+    UnknownSource,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch is truncated"),
+            PatchError::SourceMismatch { expected, actual } => write!(
+                f,
+                "patch is for an image with checksum {:#010X}, this one is {:#010X}",
+                expected, actual
+            ),
+            PatchError::TargetMismatch { expected, actual } => write!(
+                f,
+                "patched image has checksum {:#010X}, the patch says it should be {:#010X}",
+                actual, expected
+            ),
+            PatchError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "patch checksum mismatch, footer says {:#010X} but patch is {:#010X}",
+                expected, actual
+            ),
+            PatchError::OutOfBounds => write!(f, "patch reaches outside the image"),
+            PatchError::UnknownSource => write!(
+                f,
+                "IPS patches don't record the image they are for, its checksum has to be given"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// A patch to turn one cartridge image into another, in either of the formats ROM fixes are usually shipped in.
+///
+/// Every patch is checked against the CRC-32 of the image it was made from before it is applied. BPS patches carry
+/// that checksum, and the one of the image they make, themselves. IPS patches have no checksums, so the source one
+/// has to be given with `with_source_checksum` and is the only thing that stops them being applied to the wrong image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Patch {
+    Ips {
+        data: Vec<u8>,
+        source_checksum: Option<u32>,
+    },
+    Bps(Vec<u8>),
+}
+
+impl Patch {
+    pub fn parse(data: &[u8]) -> Result<Self, PatchError> {
+        if data.starts_with(IPS_MAGIC) {
+            Ok(Patch::Ips {
+                data: data.to_vec(),
+                source_checksum: None,
+            })
+        } else if data.starts_with(BPS_MAGIC) {
+            if data.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+                return Err(PatchError::Truncated);
+            }
+            let body_end = data.len() - 4;
+            let expected = read_u32(&data[body_end..]);
+            let actual = crc32(&data[..body_end]);
+            if expected != actual {
+                return Err(PatchError::ChecksumMismatch { expected, actual });
+            }
+            Ok(Patch::Bps(data.to_vec()))
+        } else {
+            Err(PatchError::UnknownFormat)
+        }
+    }
+
+    /// Sets the checksum of the image an IPS patch is for. BPS patches record their own, so this does nothing for them.
+    pub fn with_source_checksum(mut self, checksum: u32) -> Self {
+        if let Patch::Ips {
+            source_checksum, ..
+        } = &mut self
+        {
+            *source_checksum = Some(checksum);
+        }
+        self
+    }
+
+    /// The checksum of the image this patch applies to, if it is known.
+    pub fn source_checksum(&self) -> Option<u32> {
+        match self {
+            Patch::Ips {
+                source_checksum, ..
+            } => *source_checksum,
+            Patch::Bps(data) => Some(read_u32(&data[data.len() - BPS_FOOTER_SIZE..])),
+        }
+    }
+
+    /// Applies the patch to `source`, returning the patched image. Fails without touching anything if `source` isn't
+    /// the image the patch was made for.
+    pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, PatchError> {
+        let expected = self.source_checksum().ok_or(PatchError::UnknownSource)?;
+        let actual = crc32(source);
+        if expected != actual {
+            return Err(PatchError::SourceMismatch { expected, actual });
+        }
+        match self {
+            Patch::Ips { data, .. } => apply_ips(data, source),
+            Patch::Bps(patch) => apply_bps(patch, source),
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+/// Runs of `offset: u24, size: u16, data` records, big endian, up to `EOF`. A size of zero is a run of one byte
+/// repeated `u16` times instead. An optional `u24` after `EOF` truncates the image to that length.
+fn apply_ips(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target = source.to_vec();
+    let mut position = IPS_MAGIC.len();
+    let mut take = |length: usize| {
+        let bytes = patch
+            .get(position..position + length)
+            .ok_or(PatchError::Truncated)?;
+        position += length;
+        Ok::<_, PatchError>(bytes)
+    };
+    let be = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .fold(0usize, |value, &byte| value << 8 | byte as usize)
+    };
+
+    loop {
+        let offset = take(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = be(offset);
+        let size = be(take(2)?);
+        let (size, data) = if size == 0 {
+            let count = be(take(2)?);
+            (count, vec![take(1)?[0]; count])
+        } else {
+            (size, take(size)?.to_vec())
+        };
+
+        if target.len() < offset + size {
+            target.resize(offset + size, 0);
+        }
+        target[offset..offset + size].copy_from_slice(&data);
+    }
+
+    if let Ok(length) = take(3) {
+        target.truncate(be(length));
+    }
+    Ok(target)
+}
+
+/// `BPS1`, then the source size, target size and metadata size as variable length numbers, the metadata, and the
+/// actions building the target in order. Each action is a number holding the kind in its low 2 bits and the length
+/// minus one above them:
+///
+/// ```text
+/// 0 source read  copy from the source at the current output position
+/// 1 target read  copy bytes stored in the patch
+/// 2 source copy  copy from a relative position in the source
+/// 3 target copy  copy from earlier in the output, which may overlap what is being written
+/// ```
+fn apply_bps(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let expected_target = read_u32(&patch[patch.len() - BPS_FOOTER_SIZE + 4..]);
+
+    let actions = &patch[..patch.len() - BPS_FOOTER_SIZE];
+    let mut rest = &actions[BPS_MAGIC.len()..];
+    let source_size = read_number(&mut rest)?;
+    let target_size = read_number(&mut rest)?;
+    let metadata_size = read_number(&mut rest)?;
+    if source_size != source.len() {
+        return Err(PatchError::OutOfBounds);
+    }
+    rest = rest.get(metadata_size..).ok_or(PatchError::Truncated)?;
+
+    let mut target = Vec::new();
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while !rest.is_empty() {
+        let action = read_number(&mut rest)?;
+        let length = (action >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err(PatchError::OutOfBounds);
+        }
+        match action & 0b11 {
+            0 => {
+                let start = target.len();
+                let bytes = source
+                    .get(start..start + length)
+                    .ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+            }
+            1 => {
+                let bytes = rest.get(..length).ok_or(PatchError::Truncated)?;
+                target.extend_from_slice(bytes);
+                rest = &rest[length..];
+            }
+            2 => {
+                source_offset = relative(source_offset, read_number(&mut rest)?)?;
+                let bytes = source
+                    .get(source_offset..source_offset.saturating_add(length))
+                    .ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+                source_offset += length;
+            }
+            _ => {
+                target_offset = relative(target_offset, read_number(&mut rest)?)?;
+                for _ in 0..length {
+                    // Byte by byte, so a copy can repeat the bytes it has just written
+                    let byte = *target.get(target_offset).ok_or(PatchError::OutOfBounds)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    let actual_target = crc32(&target);
+    if expected_target != actual_target {
+        return Err(PatchError::TargetMismatch {
+            expected: expected_target,
+            actual: actual_target,
+        });
+    }
+    Ok(target)
+}
+
+/// Reads one of BPS's variable length numbers from the front of `data`: 7 bits per byte, least significant first,
+/// with the top bit marking the last byte.
+fn read_number(data: &mut &[u8]) -> Result<usize, PatchError> {
+    let mut value = 0u64;
+    let mut shift = 1u64;
+    loop {
+        let (&byte, rest) = data.split_first().ok_or(PatchError::Truncated)?;
+        *data = rest;
+        value = ((byte & 0x7F) as u64)
+            .checked_mul(shift)
+            .and_then(|digit| value.checked_add(digit))
+            .ok_or(PatchError::OutOfBounds)?;
+        if byte & 0x80 != 0 {
+            return Ok(value as usize);
+        }
+        shift = shift.checked_mul(0x80).ok_or(PatchError::OutOfBounds)?;
+        value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+    }
+}
+
+/// Moves `offset` by a BPS relative offset, whose low bit is the sign.
+fn relative(offset: usize, delta: usize) -> Result<usize, PatchError> {
+    let distance = delta >> 1;
+    if delta & 1 != 0 {
+        offset.checked_sub(distance)
+    } else {
+        offset.checked_add(distance)
+    }
+    .ok_or(PatchError::OutOfBounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(records: &[&[u8]], truncate_to: Option<u32>) -> Vec<u8> {
+        let mut patch = IPS_MAGIC.to_vec();
+        records
+            .iter()
+            .for_each(|record| patch.extend_from_slice(record));
+        patch.extend_from_slice(IPS_EOF);
+        if let Some(length) = truncate_to {
+            patch.extend_from_slice(&length.to_be_bytes()[1..]);
+        }
+        patch
+    }
+
+    fn number(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(low | 0x80);
+                return bytes;
+            }
+            bytes.push(low);
+            value -= 1;
+        }
+    }
+
+    /// A BPS patch from `source` to a target of `target_size` bytes checksummed as `target_checksum`.
+    fn bps(source: &[u8], target_size: usize, target_checksum: u32, actions: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(number(source.len() as u64));
+        patch.extend(number(target_size as u64));
+        patch.extend(number(0));
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&target_checksum.to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    fn hello_there(source: &[u8]) -> Vec<u8> {
+        let mut actions = number((6 - 1) << 2);
+        actions.extend(number(((5 - 1) << 2) | 1));
+        actions.extend_from_slice(b"there");
+        bps(source, 11, crc32(b"hello there"), &actions)
+    }
+
+    #[test]
+    fn ips_applies_records_and_runs() {
+        let source = [0u8; 6];
+        let patch = ips(
+            &[
+                b"\x00\x00\x01\x00\x02\xAA\xBB",
+                b"\x00\x00\x05\x00\x00\x00\x03\xCC",
+            ],
+            None,
+        );
+        let patched = Patch::parse(&patch)
+            .unwrap()
+            .with_source_checksum(crc32(&source))
+            .apply(&source)
+            .unwrap();
+        assert_eq!(patched, [0x00, 0xAA, 0xBB, 0x00, 0x00, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn ips_truncates_the_image() {
+        let source = [1u8, 2, 3, 4, 5, 6];
+        let patch = Patch::parse(&ips(&[b"\x00\x00\x00\x00\x01\x09"], Some(4)))
+            .unwrap()
+            .with_source_checksum(crc32(&source));
+        assert_eq!(patch.apply(&source).unwrap(), [9, 2, 3, 4]);
+    }
+
+    #[test]
+    fn ips_needs_the_source_checksum() {
+        let source = [0u8; 4];
+        let patch = Patch::parse(&ips(&[b"\x00\x00\x00\x00\x01\x09"], None)).unwrap();
+        assert_eq!(patch.apply(&source), Err(PatchError::UnknownSource));
+        assert_eq!(
+            patch.clone().with_source_checksum(0x1234).apply(&source),
+            Err(PatchError::SourceMismatch {
+                expected: 0x1234,
+                actual: crc32(&source)
+            })
+        );
+    }
+
+    #[test]
+    fn ips_refuses_truncated_records() {
+        let source = [0u8; 4];
+        let mut patch = ips(&[b"\x00\x00\x00\x00\x04\x01\x02"], None);
+        patch.truncate(patch.len() - IPS_EOF.len());
+        let patch = Patch::parse(&patch)
+            .unwrap()
+            .with_source_checksum(crc32(&source));
+        assert_eq!(patch.apply(&source), Err(PatchError::Truncated));
+    }
+
+    #[test]
+    fn bps_applies_actions() {
+        let patch = Patch::parse(&hello_there(b"hello world")).unwrap();
+        assert_eq!(patch.source_checksum(), Some(crc32(b"hello world")));
+        assert_eq!(patch.apply(b"hello world").unwrap(), b"hello there");
+    }
+
+    #[test]
+    fn bps_checks_its_checksums() {
+        let mut damaged = hello_there(b"hello world");
+        damaged[8] ^= 0xFF;
+        assert!(matches!(
+            Patch::parse(&damaged),
+            Err(PatchError::ChecksumMismatch { .. })
+        ));
+
+        let patch = Patch::parse(&hello_there(b"hello world")).unwrap();
+        assert!(matches!(
+            patch.apply(b"hello earth"),
+            Err(PatchError::SourceMismatch { .. })
+        ));
+
+        let mut actions = number((6 - 1) << 2);
+        actions.extend(number(((5 - 1) << 2) | 1));
+        actions.extend_from_slice(b"there");
+        let wrong_target = Patch::parse(&bps(b"hello world", 11, 0xDEAD_BEEF, &actions)).unwrap();
+        assert_eq!(
+            wrong_target.apply(b"hello world"),
+            Err(PatchError::TargetMismatch {
+                expected: 0xDEAD_BEEF,
+                actual: crc32(b"hello there")
+            })
+        );
+    }
+
+    #[test]
+    fn bps_refuses_oversized_numbers() {
+        let source = b"source";
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x7F; 9]);
+        patch.push(0xFF);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&0u32.to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        let patch = Patch::parse(&patch).unwrap();
+        assert_eq!(patch.apply(source), Err(PatchError::OutOfBounds));
+    }
+
+    #[test]
+    fn bps_refuses_copies_out_of_the_source() {
+        let source = b"source";
+        // A source copy starting far past the end of the source
+        let mut actions = number(2);
+        actions.extend(number(u32::MAX as u64 * 2));
+        let patch = Patch::parse(&bps(source, 1, 0, &actions)).unwrap();
+        assert_eq!(patch.apply(source), Err(PatchError::OutOfBounds));
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::misc::{
+    io_bus::{IoDevice, MachineSignal},
+    memory_bus::MemoryBus48,
+    scheduler::Clock,
+};
+
+/// Any write flushes save RAM to the host straight away, reads return its size in bytes.
+pub const SAVE_RAM_CONTROL_PORT: u16 = 0x0132;
+
+const FLUSH_EVENT: u64 = 0;
+
+/// Keeps a cartridge's battery-backed save RAM in sync with a file on the host.
+///
+/// The RAM itself lives on the memory bus. Every `flush_interval` cycles, and whenever the guest asks through
+/// `SAVE_RAM_CONTROL_PORT`, it is read back and written to the file if it changed since the last flush. The
+/// motherboard also flushes on shutdown, so a clean exit never loses a save.
+pub struct SaveRam {
+    path: Option<PathBuf>,
+    base: u64,
+    flushed: Vec<u8>,
+    flush_interval: u64,
+    clock: Clock,
+}
+
+impl SaveRam {
+    /// Loads `size` bytes of save RAM from `path`. A missing file starts out zeroed, and one of the wrong size is
+    /// truncated or padded with zeroes. Without a path the save RAM is never written anywhere.
+    pub fn open(
+        path: Option<&Path>,
+        base: u64,
+        size: usize,
+        flush_interval: u64,
+    ) -> io::Result<Self> {
+        let mut contents = match path.map(fs::read) {
+            Some(Ok(contents)) => contents,
+            Some(Err(error)) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => Vec::new(),
+        };
+        if let Some(path) = path
+            && !contents.is_empty()
+            && contents.len() != size
+        {
+            log::warn!(
+                "Save RAM: {:?} holds {} bytes but the cartridge has {}.",
+                path,
+                contents.len(),
+                size
+            );
+        }
+        contents.resize(size, 0);
+
+        Ok(Self {
+            path: path.map(Path::to_path_buf),
+            base,
+            flushed: contents,
+            flush_interval,
+            clock: Clock::default(),
+        })
+    }
+
+    /// Replaces the pending flush with one `delay` cycles from now.
+    fn schedule_flush(&mut self, delay: u64) {
+        self.clock.cancel(FLUSH_EVENT);
+        self.clock.schedule_in(delay, FLUSH_EVENT);
+    }
+
+    /// What the save RAM held when it was last loaded or flushed.
+    pub fn contents(&self) -> &[u8] {
+        &self.flushed
+    }
+
+    /// Writes the save RAM out to its file if it changed since the last flush.
+    pub fn flush(&mut self, memory_bus: &Mutex<MemoryBus48>) {
+        let contents = memory_bus
+            .lock()
+            .unwrap()
+            .read_bytes(self.base, self.flushed.len())
+            .to_vec();
+        if contents.len() != self.flushed.len() || contents == self.flushed {
+            return;
+        }
+        self.flushed = contents;
+
+        let Some(path) = &self.path else {
+            return;
+        };
+        // Write next to the real file and rename over it, so a crash mid-write can't corrupt the save
+        let temporary = path.with_extension("sav.tmp");
+        match fs::write(&temporary, &self.flushed).and_then(|()| fs::rename(&temporary, path)) {
+            Ok(()) => log::debug!("Save RAM: Flushed to {:?}.", path),
+            Err(error) => log::error!("Save RAM: Failed to flush to {:?}: {}", path, error),
+        }
+    }
+}
+
+impl IoDevice for SaveRam {
+    // Sizes too big for the width read come back as its largest value
+    fn read_u8(&mut self, _port: u16) -> u8 {
+        self.flushed.len().try_into().unwrap_or(u8::MAX)
+    }
+
+    fn read_u16(&mut self, _port: u16) -> u16 {
+        self.flushed.len().try_into().unwrap_or(u16::MAX)
+    }
+
+    fn read_u32(&mut self, _port: u16) -> u32 {
+        self.flushed.len().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn read_u64(&mut self, _port: u16) -> u64 {
+        self.flushed.len() as u64
+    }
+
+    fn write_u8(&mut self, _port: u16, _value: u8) {
+        self.schedule_flush(0);
+    }
+
+    fn write_u16(&mut self, _port: u16, _value: u16) {
+        self.schedule_flush(0);
+    }
+
+    fn write_u32(&mut self, _port: u16, _value: u32) {
+        self.schedule_flush(0);
+    }
+
+    fn write_u64(&mut self, _port: u16, _value: u64) {
+        self.schedule_flush(0);
+    }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.schedule_flush(self.flush_interval);
+    }
+
+    fn handle_event(
+        &mut self,
+        _event: u64,
+        memory_bus: &Mutex<MemoryBus48>,
+        _signals: &mut Vec<MachineSignal>,
+    ) {
+        self.flush(memory_bus);
+        self.schedule_flush(self.flush_interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::misc::io_bus::IoBus;
+
+    #[test]
+    fn every_width_reads_the_size_and_flushes_on_write() {
+        let path = std::env::temp_dir().join(format!("m64-save-ram-{}.sav", std::process::id()));
+        let memory_bus = Mutex::new(MemoryBus48::with_ram_size(0x400));
+        let save_ram = SaveRam::open(Some(&path), 0x100, 0x120, u64::MAX).unwrap();
+        let mut io_bus = IoBus::new();
+        io_bus.attach_device(&[SAVE_RAM_CONTROL_PORT], Arc::new(Mutex::new(save_ram)));
+
+        assert_eq!(io_bus.read_u8(SAVE_RAM_CONTROL_PORT), u8::MAX);
+        assert_eq!(io_bus.read_u16(SAVE_RAM_CONTROL_PORT), 0x120);
+        assert_eq!(io_bus.read_u32(SAVE_RAM_CONTROL_PORT), 0x120);
+        assert_eq!(io_bus.read_u64(SAVE_RAM_CONTROL_PORT), 0x120);
+
+        for (width, value) in [1u8, 2, 3, 4].into_iter().enumerate() {
+            memory_bus.lock().unwrap().write_bytes(0x100, &[value]);
+            match width {
+                0 => io_bus.write_u8(SAVE_RAM_CONTROL_PORT, 0),
+                1 => io_bus.write_u16(SAVE_RAM_CONTROL_PORT, 0),
+                2 => io_bus.write_u32(SAVE_RAM_CONTROL_PORT, 0),
+                _ => io_bus.write_u64(SAVE_RAM_CONTROL_PORT, 0),
+            }
+            io_bus.advance(1, &memory_bus);
+            assert_eq!(fs::read(&path).unwrap()[0], value);
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,381 @@
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::Deserialize;
+
+use crate::peripherals::storage::monad_boot_cartridge::{
+    CARTRIDGE_HEADER_SIZE, CARTRIDGE_MAGIC, CartridgeError, MonadBootCartridge,
+    SIGNATURE_ALGORITHM_ED25519, SIGNATURE_LENGTH, SIGNATURE_OFFSET,
+};
+
+/// What to do with a cartridge that fails verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerificationAction {
+    /// Don't load it at all.
+    #[default]
+    Refuse,
+    /// Load it, but log a warning saying what was wrong.
+    Warn,
+    /// Load it as if nothing was wrong.
+    Boot,
+}
+
+impl FromStr for VerificationAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "refuse" => Ok(VerificationAction::Refuse),
+            "warn" => Ok(VerificationAction::Warn),
+            "boot" => Ok(VerificationAction::Boot),
+            _ => Err(format!(
+                "unknown verification action {:?}, expected refuse, warn or boot",
+                action
+            )),
+        }
+    }
+}
+
+/// What was found out about a cartridge's signature when its image was loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SignatureStatus {
+    #[default]
+    Unsigned,
+    /// Signed, but there were no trusted keys to check the signature against.
+    Unchecked,
+    /// Signed by none of the trusted keys, and only loaded because the policy let it through.
+    Untrusted,
+    /// Signed by this trusted key.
+    Verified(VerifyingKey),
+}
+
+/// How much to trust a cartridge before booting it.
+///
+/// The payload checksum is always checked. Signatures are only checked once there are trusted keys, so with the
+/// default policy a signed cartridge loads the same as an unsigned one. Trusting a key also makes a signature
+/// required, as otherwise clearing the signature algorithm in a tampered image would make it load as unsigned.
+#[derive(Debug, Clone, Default)]
+pub struct VerificationPolicy {
+    pub action: VerificationAction,
+    pub trusted_keys: Vec<VerifyingKey>,
+    /// Treat unsigned cartridges as failing verification.
+    pub require_signature: bool,
+}
+
+impl VerificationPolicy {
+    pub fn with_action(mut self, action: VerificationAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn with_trusted_key(mut self, key: VerifyingKey) -> Self {
+        self.trusted_keys.push(key);
+        self
+    }
+
+    pub fn with_signature_required(mut self) -> Self {
+        self.require_signature = true;
+        self
+    }
+
+    /// Whether unsigned cartridges fail verification, either because it was asked for or because keys are trusted.
+    pub fn signature_required(&self) -> bool {
+        self.require_signature || !self.trusted_keys.is_empty()
+    }
+
+    /// Checks the signature in `image`, or its absence, against this policy, returning what was found.
+    pub(crate) fn check_signature(&self, image: &[u8]) -> Result<SignatureStatus, CartridgeError> {
+        let status = match image_signature(image) {
+            None => SignatureStatus::Unsigned,
+            Some(_) if self.trusted_keys.is_empty() => SignatureStatus::Unchecked,
+            Some(signature) => {
+                let message = signed_message(image);
+                self.trusted_keys
+                    .iter()
+                    .find(|key| key.verify(&message, &signature).is_ok())
+                    .map_or(SignatureStatus::Untrusted, |key| {
+                        SignatureStatus::Verified(*key)
+                    })
+            }
+        };
+        self.check_status(status)?;
+        Ok(status)
+    }
+
+    /// Checks a signature found when an image was loaded against this policy, which may not be the one it was
+    /// loaded with.
+    fn check_status(&self, status: SignatureStatus) -> Result<(), CartridgeError> {
+        let failure = match status {
+            SignatureStatus::Unsigned if self.signature_required() => {
+                Some(CartridgeError::Unsigned)
+            }
+            SignatureStatus::Unchecked if !self.trusted_keys.is_empty() => {
+                Some(CartridgeError::UntrustedSignature)
+            }
+            SignatureStatus::Untrusted if self.signature_required() => {
+                Some(CartridgeError::UntrustedSignature)
+            }
+            SignatureStatus::Verified(key)
+                if !self.trusted_keys.is_empty() && !self.trusted_keys.contains(&key) =>
+            {
+                Some(CartridgeError::UntrustedSignature)
+            }
+            _ => None,
+        };
+        failure.map_or(Ok(()), |error| self.handle_failure(error))
+    }
+
+    /// Checks a cartridge against this policy however it was made, going by what was found when its image was
+    /// loaded. The motherboard does this before it boots a cartridge or swaps one in.
+    pub fn check_cartridge(&self, cartridge: &MonadBootCartridge) -> Result<(), CartridgeError> {
+        if let Some((expected, actual)) = cartridge.checksum_mismatch {
+            self.handle_failure(CartridgeError::ChecksumMismatch { expected, actual })?;
+        }
+        self.check_status(cartridge.signature)
+    }
+
+    /// Applies the policy to a verification failure. Returns the error back if the cartridge has to be refused.
+    pub(crate) fn handle_failure(&self, error: CartridgeError) -> Result<(), CartridgeError> {
+        match self.action {
+            VerificationAction::Refuse => Err(error),
+            VerificationAction::Warn => {
+                log::warn!(
+                    "Cartridge failed verification, loading it anyway: {}",
+                    error
+                );
+                Ok(())
+            }
+            VerificationAction::Boot => {
+                log::debug!(
+                    "Cartridge failed verification, ignored by policy: {}",
+                    error
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What gets signed: the whole image, with the signature itself zeroed.
+fn signed_message(image: &[u8]) -> Vec<u8> {
+    let mut message = image.to_vec();
+    message[SIGNATURE_OFFSET..SIGNATURE_OFFSET + SIGNATURE_LENGTH].fill(0);
+    message
+}
+
+fn image_signature(image: &[u8]) -> Option<Signature> {
+    if image.len() < CARTRIDGE_HEADER_SIZE
+        || !image.starts_with(&CARTRIDGE_MAGIC)
+        || image[0x50] != SIGNATURE_ALGORITHM_ED25519
+    {
+        return None;
+    }
+    let bytes = image[SIGNATURE_OFFSET..SIGNATURE_OFFSET + SIGNATURE_LENGTH]
+        .try_into()
+        .unwrap();
+    Some(Signature::from_bytes(&bytes))
+}
+
+/// Signs a cartridge image with a header in place. The image must not change afterwards.
+pub fn sign_image(image: &mut [u8], key: &SigningKey) {
+    image[0x50] = SIGNATURE_ALGORITHM_ED25519;
+    let signature = key.sign(&signed_message(image));
+    image[SIGNATURE_OFFSET..SIGNATURE_OFFSET + SIGNATURE_LENGTH]
+        .copy_from_slice(&signature.to_bytes());
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(io::Error),
+    /// The file isn't 64 hex digits.
+    Malformed,
+    /// The bytes don't make a valid public key.
+    Invalid,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(error) => write!(f, "failed to read key: {}", error),
+            KeyError::Malformed => write!(f, "key files hold 32 bytes as 64 hex digits"),
+            KeyError::Invalid => write!(f, "not a valid Ed25519 public key"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+fn read_key_bytes(path: &Path) -> Result<[u8; 32], KeyError> {
+    let text = fs::read_to_string(path).map_err(KeyError::Io)?;
+    let text = text.trim();
+    if text.len() != 64 || !text.is_ascii() {
+        return Err(KeyError::Malformed);
+    }
+    let mut bytes = [0; 32];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16)
+            .map_err(|_| KeyError::Malformed)?;
+    }
+    Ok(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Reads a trusted public key, stored as hex text.
+pub fn load_verifying_key(path: impl AsRef<Path>) -> Result<VerifyingKey, KeyError> {
+    VerifyingKey::from_bytes(&read_key_bytes(path.as_ref())?).map_err(|_| KeyError::Invalid)
+}
+
+/// Reads a private signing key, stored as hex text.
+pub fn load_signing_key(path: impl AsRef<Path>) -> Result<SigningKey, KeyError> {
+    Ok(SigningKey::from_bytes(&read_key_bytes(path.as_ref())?))
+}
+
+/// Makes a new signing key from the host's randomness.
+pub fn generate_signing_key() -> io::Result<SigningKey> {
+    let mut seed = [0; 32];
+    getrandom::getrandom(&mut seed)
+        .map_err(|error| io::Error::other(format!("no randomness from the host: {}", error)))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Writes `key` to `path`, on unix hosts readable only by its owner, and its public half to `path` with a `.pub`
+/// extension.
+pub fn save_key_pair(key: &SigningKey, path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, so a key written over an old one has to be locked down as well
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all((to_hex(key.as_bytes()) + "\n").as_bytes())?;
+    fs::write(
+        path.with_extension("pub"),
+        to_hex(key.verifying_key().as_bytes()) + "\n",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_image(key: &SigningKey) -> Vec<u8> {
+        let mut cartridge = MonadBootCartridge::new(&[0x0D, 0x03, 0, 0, 0, 0, 0, 0]);
+        cartridge.revision = 1;
+        let mut image = cartridge.to_bytes().unwrap();
+        sign_image(&mut image, key);
+        image
+    }
+
+    #[test]
+    fn verifies_signatures_from_trusted_keys() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let image = signed_image(&key);
+
+        let trusted = VerificationPolicy::default().with_trusted_key(key.verifying_key());
+        let cartridge = MonadBootCartridge::from_bytes_with_policy(&image, &trusted).unwrap();
+        assert!(cartridge.is_signed() && cartridge.is_verified());
+        assert!(trusted.check_cartridge(&cartridge).is_ok());
+
+        let untrusted = VerificationPolicy::default().with_trusted_key(other.verifying_key());
+        assert!(matches!(
+            MonadBootCartridge::from_bytes_with_policy(&image, &untrusted),
+            Err(CartridgeError::UntrustedSignature)
+        ));
+        assert!(untrusted.check_cartridge(&cartridge).is_err());
+    }
+
+    #[test]
+    fn trusted_keys_require_a_signature() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let mut image = signed_image(&key);
+        // Clearing the algorithm makes a tampered image look unsigned
+        image[0x50] = 0;
+        image[CARTRIDGE_HEADER_SIZE] ^= 0xFF;
+
+        let policy = VerificationPolicy::default().with_trusted_key(key.verifying_key());
+        assert!(matches!(
+            MonadBootCartridge::from_bytes_with_policy(&image, &policy),
+            Err(CartridgeError::Unsigned)
+        ));
+        assert!(
+            policy
+                .check_cartridge(&MonadBootCartridge::new(&[0; 8]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn untrusted_cartridges_let_through_are_not_verified() {
+        let image = signed_image(&SigningKey::from_bytes(&[1; 32]));
+        let policy = VerificationPolicy::default()
+            .with_action(VerificationAction::Warn)
+            .with_trusted_key(SigningKey::from_bytes(&[2; 32]).verifying_key());
+        let cartridge = MonadBootCartridge::from_bytes_with_policy(&image, &policy).unwrap();
+        assert!(cartridge.is_signed());
+        assert!(!cartridge.is_verified());
+        assert_eq!(cartridge.get_signature_status(), SignatureStatus::Untrusted);
+    }
+
+    #[test]
+    fn refuses_checksum_mismatches_later_too() {
+        let mut image = signed_image(&SigningKey::from_bytes(&[1; 32]));
+        image[CARTRIDGE_HEADER_SIZE] ^= 0xFF;
+        assert!(matches!(
+            MonadBootCartridge::from_bytes(&image),
+            Err(CartridgeError::ChecksumMismatch { .. })
+        ));
+
+        let lenient = VerificationPolicy::default().with_action(VerificationAction::Boot);
+        let cartridge = MonadBootCartridge::from_bytes_with_policy(&image, &lenient).unwrap();
+        assert!(
+            VerificationPolicy::default()
+                .check_cartridge(&cartridge)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn generated_keys_differ() {
+        assert_ne!(
+            generate_signing_key().unwrap().to_bytes(),
+            generate_signing_key().unwrap().to_bytes()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_keys_are_only_readable_by_their_owner() {
+        let path = std::env::temp_dir().join(format!("m64-key-{}", std::process::id()));
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let key = SigningKey::from_bytes(&[3; 32]);
+        save_key_pair(&key, &path).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(load_signing_key(&path).unwrap().to_bytes(), key.to_bytes());
+        assert_eq!(
+            load_verifying_key(path.with_extension("pub")).unwrap(),
+            key.verifying_key()
+        );
+        fs::remove_file(path.with_extension("pub")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
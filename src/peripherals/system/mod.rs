@@ -0,0 +1,2 @@
+pub mod rng;
+pub mod watchdog;
\ No newline at end of file
@@ -0,0 +1,103 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use crate::misc::io_bus::IoDevice;
+
+/// The port the random number generator answers on. Each read returns a fresh value of the requested width.
+pub const RNG_PORT: u16 = 0x00EB;
+
+enum RngSource {
+    /// Reads straight from the host's entropy pool.
+    Host,
+    /// A xoshiro256** generator, used when a seed is configured so runs can be replayed exactly.
+    Seeded([u64; 4]),
+}
+
+/// A random number device. Without a seed it draws from host entropy, with one it is fully deterministic.
+pub struct HardwareRng {
+    source: RngSource,
+}
+
+impl Default for HardwareRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HardwareRng {
+    pub fn new() -> Self {
+        match getrandom::getrandom(&mut [0; 8]) {
+            Ok(()) => Self {
+                source: RngSource::Host,
+            },
+            Err(error) => {
+                // Still random per run, just not from a proper entropy source
+                log::warn!(
+                    "Hardware RNG: No host entropy ({}). Falling back to a randomly seeded generator.",
+                    error
+                );
+                Self::with_seed(RandomState::new().build_hasher().finish())
+            }
+        }
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        // Expand the seed with splitmix64, as recommended for xoshiro
+        let mut splitmix = seed;
+        let mut next = || {
+            splitmix = splitmix.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = splitmix;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            z ^ (z >> 31)
+        };
+
+        Self {
+            source: RngSource::Seeded([next(), next(), next(), next()]),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        match &mut self.source {
+            RngSource::Host => {
+                let mut bytes = [0; 8];
+                if let Err(error) = getrandom::getrandom(&mut bytes) {
+                    log::error!("Hardware RNG: Failed to read host entropy: {}", error);
+                }
+                u64::from_le_bytes(bytes)
+            }
+            RngSource::Seeded(state) => {
+                let result = state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+                let t = state[1] << 17;
+                state[2] ^= state[0];
+                state[3] ^= state[1];
+                state[1] ^= state[2];
+                state[0] ^= state[3];
+                state[2] ^= t;
+                state[3] = state[3].rotate_left(45);
+                result
+            }
+        }
+    }
+}
+
+impl IoDevice for HardwareRng {
+    // Narrow reads take the high bits, which are the strongest in xoshiro256**
+    fn read_u8(&mut self, _port: u16) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn read_u16(&mut self, _port: u16) -> u16 {
+        (self.next_u64() >> 48) as u16
+    }
+
+    fn read_u32(&mut self, _port: u16) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn read_u64(&mut self, _port: u16) -> u64 {
+        self.next_u64()
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::Mutex;
+
+use crate::misc::{
+    io_bus::{IoDevice, MachineSignal},
+    memory_bus::MemoryBus48,
+    scheduler::Clock,
+};
+
+/// Control register. Bit 0 enables the watchdog, bit 1 selects a power cycle instead of a reset on timeout.
+pub const WATCHDOG_CONTROL_PORT: u16 = 0x00F0;
+/// Timeout in cycles. Writing it also reloads the counter.
+pub const WATCHDOG_TIMEOUT_PORT: u16 = 0x00F1;
+/// Any write reloads the counter, reads return the cycles left before the watchdog fires.
+pub const WATCHDOG_KICK_PORT: u16 = 0x00F2;
+
+pub const WATCHDOG_PORTS: [u16; 3] = [
+    WATCHDOG_CONTROL_PORT,
+    WATCHDOG_TIMEOUT_PORT,
+    WATCHDOG_KICK_PORT,
+];
+
+const CONTROL_ENABLE: u64 = 0b1;
+const CONTROL_POWER_CYCLE: u64 = 0b10;
+
+const TIMEOUT_EVENT: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    Reset,
+    PowerCycle,
+}
+
+/// Resets the machine if the guest stops kicking it.
+///
+/// Time is counted in machine cycles rather than host time, so a hung guest is caught at exactly the same point on
+/// every run. When it fires it goes back to how it was at power on, so an `armed` watchdog keeps guarding every boot
+/// while one the firmware set up itself has to be set up again after the reset.
+pub struct Watchdog {
+    control: u64,
+    /// What the control register holds at power on, and again after the watchdog fires.
+    power_on_control: u64,
+    timeout: u64,
+    /// The cycle the watchdog fires at while it is enabled.
+    deadline: u64,
+    clock: Clock,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watchdog {
+    /// A disarmed watchdog, left for the guest to set up.
+    pub fn new() -> Self {
+        Self {
+            control: 0,
+            power_on_control: 0,
+            timeout: 0,
+            deadline: 0,
+            clock: Clock::default(),
+        }
+    }
+
+    /// A watchdog that is already running at power on, so even guests that never touch it get caught.
+    pub fn armed(timeout: u64, action: WatchdogAction) -> Self {
+        let mut watchdog = Self::new();
+        watchdog.timeout = timeout;
+        watchdog.control = CONTROL_ENABLE;
+        if action == WatchdogAction::PowerCycle {
+            watchdog.control |= CONTROL_POWER_CYCLE;
+        }
+        watchdog.power_on_control = watchdog.control;
+        watchdog
+    }
+
+    fn read(&self, port: u16) -> u64 {
+        match port {
+            WATCHDOG_CONTROL_PORT => self.control,
+            WATCHDOG_TIMEOUT_PORT => self.timeout,
+            WATCHDOG_KICK_PORT if self.control & CONTROL_ENABLE != 0 => {
+                self.deadline.saturating_sub(self.clock.now())
+            }
+            WATCHDOG_KICK_PORT => self.timeout,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u64) {
+        match port {
+            WATCHDOG_CONTROL_PORT => {
+                let was_enabled = self.control & CONTROL_ENABLE != 0;
+                self.control = value & (CONTROL_ENABLE | CONTROL_POWER_CYCLE);
+                // Enabling starts a fresh countdown rather than resuming an old one
+                if !was_enabled && self.control & CONTROL_ENABLE != 0 {
+                    self.reload();
+                } else if self.control & CONTROL_ENABLE == 0 {
+                    self.clock.cancel(TIMEOUT_EVENT);
+                }
+            }
+            WATCHDOG_TIMEOUT_PORT => {
+                self.timeout = value;
+                self.reload();
+            }
+            WATCHDOG_KICK_PORT => self.reload(),
+            _ => {}
+        }
+    }
+
+    /// Restarts the countdown, if the watchdog is enabled.
+    fn reload(&mut self) {
+        self.clock.cancel(TIMEOUT_EVENT);
+        if self.control & CONTROL_ENABLE != 0 {
+            self.deadline = self.clock.now().saturating_add(self.timeout);
+            self.clock.schedule_at(self.deadline, TIMEOUT_EVENT);
+        }
+    }
+}
+
+impl IoDevice for Watchdog {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        self.read(port) as u8
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        self.read(port) as u16
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        self.read(port) as u32
+    }
+
+    fn read_u64(&mut self, port: u16) -> u64 {
+        self.read(port)
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        self.write(port, value as u64);
+    }
+
+    fn write_u64(&mut self, port: u16, value: u64) {
+        self.write(port, value);
+    }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.reload();
+    }
+
+    fn handle_event(
+        &mut self,
+        _event: u64,
+        _memory_bus: &Mutex<MemoryBus48>,
+        signals: &mut Vec<MachineSignal>,
+    ) {
+        let (signal, action) = if self.control & CONTROL_POWER_CYCLE != 0 {
+            (MachineSignal::PowerCycle, "power cycling")
+        } else {
+            (MachineSignal::Reset, "resetting")
+        };
+        log::error!(
+            "Watchdog: No kick in {} cycles, {} the machine.",
+            self.timeout,
+            action
+        );
+        self.control = self.power_on_control;
+        self.reload();
+        signals.push(signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::misc::io_bus::IoBus;
+
+    #[test]
+    fn an_armed_watchdog_guards_every_boot() {
+        let memory_bus = Mutex::new(MemoryBus48::with_ram_size(0x100));
+        let mut io_bus = IoBus::new();
+        io_bus.attach_device(
+            &WATCHDOG_PORTS,
+            Arc::new(Mutex::new(Watchdog::armed(10, WatchdogAction::PowerCycle))),
+        );
+
+        for _ in 0..3 {
+            assert!(io_bus.advance(9, &memory_bus).is_empty());
+            assert_eq!(
+                io_bus.advance(1, &memory_bus),
+                vec![MachineSignal::PowerCycle]
+            );
+            assert_eq!(io_bus.read_u64(WATCHDOG_CONTROL_PORT), 0b11);
+        }
+    }
+
+    #[test]
+    fn a_guest_armed_watchdog_disarms_when_it_fires() {
+        let memory_bus = Mutex::new(MemoryBus48::with_ram_size(0x100));
+        let mut io_bus = IoBus::new();
+        io_bus.attach_device(&WATCHDOG_PORTS, Arc::new(Mutex::new(Watchdog::new())));
+        io_bus.write_u64(WATCHDOG_TIMEOUT_PORT, 10);
+        io_bus.write_u64(WATCHDOG_CONTROL_PORT, CONTROL_ENABLE);
+
+        assert_eq!(io_bus.advance(10, &memory_bus), vec![MachineSignal::Reset]);
+        assert_eq!(io_bus.read_u64(WATCHDOG_CONTROL_PORT), 0);
+        assert!(io_bus.advance(100, &memory_bus).is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{
+    config::{ConfigError, MachineConfig},
+    cpus::{Monarch64CPU, monad::MonadCPU},
+    motherboards::{Monarch64Motherboard, monad::MonadMotherboard},
+};
+
+/// A kind of CPU a machine can be built with.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuModel {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub create: fn() -> Box<dyn Monarch64CPU>,
+}
+
+/// A kind of motherboard a machine can be built around.
+#[derive(Debug, Clone, Copy)]
+pub struct MotherboardModel {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// The CPU the board is fitted with when the config doesn't name one.
+    pub default_cpu: &'static str,
+    /// The machine the board makes when nothing else is configured, with this board's name in it.
+    pub default_config: fn() -> MachineConfig,
+    /// Builds the board around `cpu`, with the memory, devices and cartridge `config` describes.
+    pub build: BuildMotherboard,
+}
+
+pub type BuildMotherboard =
+    fn(&MachineConfig, Box<dyn Monarch64CPU>) -> Result<Box<dyn Monarch64Motherboard>, ConfigError>;
+
+pub const MONAD_CPU_MODEL: CpuModel = CpuModel {
+    name: "monad",
+    description: "The original Monad CPU, with 8 byte instructions",
+    create: || Box::new(MonadCPU::new()),
+};
+
+pub const MONAD_MOTHERBOARD_MODEL: MotherboardModel = MotherboardModel {
+    name: "monad",
+    description: "The first Monarch64 board, with a 1 MHz clock and a boot cartridge slot",
+    default_cpu: "monad",
+    default_config: MachineConfig::default,
+    build: |config, cpu| Ok(Box::new(MonadMotherboard::from_config(config, cpu)?)),
+};
+
+/// The motherboard and CPU models machines can be built from, looked up by name.
+///
+/// The default registry holds the built-in models. Embedders can register their own next to them, or start from
+/// `new` to only offer theirs. Registering a model under a name that is already taken replaces the old one.
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    motherboards: Vec<MotherboardModel>,
+    cpus: Vec<CpuModel>,
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        Self::new()
+            .with_motherboard(MONAD_MOTHERBOARD_MODEL)
+            .with_cpu(MONAD_CPU_MODEL)
+    }
+}
+
+impl ModelRegistry {
+    /// An empty registry, without even the built-in models.
+    pub fn new() -> Self {
+        Self {
+            motherboards: Vec::new(),
+            cpus: Vec::new(),
+        }
+    }
+
+    pub fn with_motherboard(mut self, model: MotherboardModel) -> Self {
+        self.register_motherboard(model);
+        self
+    }
+
+    pub fn with_cpu(mut self, model: CpuModel) -> Self {
+        self.register_cpu(model);
+        self
+    }
+
+    pub fn register_motherboard(&mut self, model: MotherboardModel) {
+        self.motherboards
+            .retain(|existing| existing.name != model.name);
+        self.motherboards.push(model);
+    }
+
+    pub fn register_cpu(&mut self, model: CpuModel) {
+        self.cpus.retain(|existing| existing.name != model.name);
+        self.cpus.push(model);
+    }
+
+    pub fn motherboard(&self, name: &str) -> Option<&MotherboardModel> {
+        self.motherboards.iter().find(|model| model.name == name)
+    }
+
+    pub fn cpu(&self, name: &str) -> Option<&CpuModel> {
+        self.cpus.iter().find(|model| model.name == name)
+    }
+
+    /// Every motherboard model, in the order they were registered.
+    pub fn motherboards(&self) -> &[MotherboardModel] {
+        &self.motherboards
+    }
+
+    /// Every CPU model, in the order they were registered.
+    pub fn cpus(&self) -> &[CpuModel] {
+        &self.cpus
+    }
+}
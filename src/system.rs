@@ -1,5 +1,17 @@
 use std::sync::Mutex;
 
+/// A whole machine: a motherboard and the memory it runs against. This is the entry point for embedding the
+/// emulator, e.g.
+///
+/// ```no_run
+/// use monarch_64_emulator::{Monarch64System, MonadBootCartridge, MonadCPU, MonadMotherboard};
+///
+/// let cartridge = MonadBootCartridge::from_file("game.bin").unwrap();
+/// let motherboard = MonadMotherboard::new(Box::new(MonadCPU::new())).with_boot_cartridge(cartridge);
+/// let mut system = Monarch64System::new(Box::new(motherboard));
+/// system.init();
+/// system.run();
+/// ```
 pub struct Monarch64System {
     pub motherboard: Box<dyn crate::motherboards::Monarch64Motherboard>,
     pub memory_bus: Mutex<crate::misc::memory_bus::MemoryBus48>,
@@ -12,4 +24,20 @@ impl Monarch64System {
             memory_bus: Mutex::new(crate::misc::memory_bus::MemoryBus48::new()),
         }
     }
+
+    /// Replaces the memory with `size` bytes of RAM. Has to happen before `init`.
+    pub fn with_ram_size(mut self, size: usize) -> Self {
+        self.memory_bus = Mutex::new(crate::misc::memory_bus::MemoryBus48::with_ram_size(size));
+        self
+    }
+
+    /// Powers the machine on, loading the boot cartridge.
+    pub fn init(&mut self) {
+        self.motherboard.init(&self.memory_bus);
+    }
+
+    /// Runs the machine until its CPU halts.
+    pub fn run(&mut self) {
+        self.motherboard.run_cpu(&self.memory_bus);
+    }
 }